@@ -4,6 +4,7 @@ const wrtc = require('wrtc');
 global.RTCPeerConnection = wrtc.RTCPeerConnection;
 global.RTCIceCandidate = wrtc.RTCIceCandidate;
 global.fs = require('fs');
+global.net = require('net');
 require("../static/wasm.js");
 function wait10s(){
   console.log(new Date());
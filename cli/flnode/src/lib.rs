@@ -1,12 +1,14 @@
 use common::node::{
     ext_interface::{DataStorage, Logger},
+    logging::set_filter_str,
     logic::Stat,
+    network::NetworkError,
 };
 
-use common::node::Node;
+use common::node::{Node, NodeError};
 
+use futures::future::{select, Either};
 use js_sys::Date;
-use wasm_bindgen::JsValue;
 
 use wasm_lib::{web_rtc_setup::WebRTCConnectionSetupWasm, web_socket::WebSocketWasm};
 
@@ -14,47 +16,66 @@ use wasm_bindgen::prelude::*;
 
 use wasm_lib::storage_logs::ConsoleLogger;
 
-#[cfg(not(feature = "local"))]
-const URL: &str = "wss://signal.fledg.re";
-
-#[cfg(feature = "local")]
-const URL: &str = "ws://localhost:8765";
+mod storage;
+use storage::file_storage;
+mod tcp;
+use tcp::NodeTcpTransport;
 
 #[wasm_bindgen(
-    inline_js = "module.exports.fswrite = function(name, str) { fs.writeFileSync(name, str); }
-    module.exports.fsread = function(name) { return fs.readFileSync(name, {encoding: 'utf-8'}); }"
+    inline_js = "module.exports.log_filter = function() { return process.env.FLEDGER_LOG || ''; }
+    module.exports.passphrase = function() { return process.env.FLEDGER_PASSPHRASE || ''; }
+    module.exports.signal_urls = function() { return process.env.FLEDGER_SIGNAL || ''; }
+    module.exports.tcp_listen_address = function() { return process.env.FLEDGER_TCP_LISTEN || ''; }
+    module.exports.tcp_public_address = function() { return process.env.FLEDGER_TCP_ADDRESS || ''; }"
 )]
 extern "C" {
-    pub fn fswrite(name: &str, str: &str);
-    #[wasm_bindgen(catch)]
-    pub fn fsread(name: &str) -> Result<String, JsValue>;
+    pub fn log_filter() -> String;
+    pub fn passphrase() -> String;
+    pub fn signal_urls() -> String;
+    pub fn tcp_listen_address() -> String;
+    pub fn tcp_public_address() -> String;
 }
 
-const STORAGE_NAME: &str = "fledger.toml";
-
-struct DummyDS {}
-
-impl DataStorage for DummyDS {
-    fn load(&self, _key: &str) -> Result<String, String> {
-        fsread(STORAGE_NAME).map_err(|e| format!("While reading file: {:?}", e))
+async fn start(log: Box<dyn Logger>) -> Result<Node, NodeError> {
+    let rtc_spawner = Box::new(WebRTCConnectionSetupWasm::new);
+    let my_storage = Box::new(file_storage()?);
+    let ledger = Node::ledger(my_storage.box_clone())?;
+    // FLEDGER_SIGNAL replaces the signal servers of the network.
+    let urls = WebSocketWasm::urls(&signal_urls(), ledger.signal_urls);
+    log.info(&format!("Joining network {} through {:?}", ledger.name, urls));
+    let ws = WebSocketWasm::with_urls(urls).map_err(NetworkError::from)?;
+    // Without a passphrase, the secret key is stored in plain text.
+    let passphrase = passphrase();
+    let passphrase = Some(passphrase.as_str()).filter(|p| !p.is_empty());
+    let mut node = Node::new(
+        my_storage,
+        passphrase,
+        log.clone(),
+        Box::new(ws),
+        rtc_spawner,
+    )?;
+    // With FLEDGER_TCP_LISTEN, other server nodes connect directly to this
+    // one, using FLEDGER_TCP_ADDRESS if the node is behind a NAT.
+    let listen = tcp_listen_address();
+    if !listen.is_empty() {
+        let address = tcp_public_address();
+        let address = Some(address.as_str()).filter(|a| !a.is_empty());
+        let network = node.info.network.clone();
+        let tcp = NodeTcpTransport::new(
+            log,
+            node.secret(),
+            network,
+            &listen,
+            address,
+            &node.wakeup(),
+        );
+        node.add_transport(Box::new(tcp))?;
     }
 
-    fn save(&self, _key: &str, value: &str) -> Result<(), String> {
-        fswrite(STORAGE_NAME, value);
-        Ok(())
-    }
-}
-
-async fn start(log: Box<dyn Logger>, url: &str) -> Result<Node, JsValue> {
-    let rtc_spawner = Box::new(|cs| WebRTCConnectionSetupWasm::new(cs));
-    let my_storage = Box::new(DummyDS {});
-    let ws = WebSocketWasm::new(url)?;
-    let node = Node::new(my_storage, log, Box::new(ws), rtc_spawner)?;
-
     Ok(node)
 }
 
-async fn list_ping(log: Box<dyn Logger>, n: &mut Node) -> Result<(), String> {
+async fn list_ping(log: Box<dyn Logger>, n: &mut Node) -> Result<(), NodeError> {
     n.list()?;
     n.ping("something").await?;
     let mut nodes: Vec<Stat> = n.logic.stats.iter().map(|(_k, v)| v.clone()).collect();
@@ -63,11 +84,13 @@ async fn list_ping(log: Box<dyn Logger>, n: &mut Node) -> Result<(), String> {
         if let Some(info) = node.node_info.as_ref() {
             if n.info.public != info.public {
                 log.info(&format!(
-                    "Node: name:{} age:{} ping:({}/{}) conn:({:?}/{:?})",
-                    info.info,
+                    "Node: name:{} age:{} ping:({}/{}) rtt:{:?} loss:{:.2} conn:({:?}/{:?})",
+                    info.label(),
                     ((Date::now() - node.last_contact) / 1000.).floor(),
                     node.ping_rx,
                     node.ping_tx,
+                    node.latency.rtt_avg_ms,
+                    node.latency.loss(),
                     node.incoming,
                     node.outgoing,
                 ));
@@ -89,28 +112,36 @@ pub async fn run_app() {
     console_error_panic_hook::set_once();
 
     let logger = Box::new(ConsoleLogger {});
+    if let Err(e) = set_filter_str(&log_filter()) {
+        logger.error(&format!("Invalid FLEDGER_LOG: {}", e));
+    }
     logger.info("starting app for now!");
 
-    let mut node = match start(logger.clone(), URL).await {
+    let mut node = match start(logger.clone()).await {
         Ok(node) => node,
         Err(e) => {
-            logger.error(&format!("Error while creating node: {:?}", e));
+            logger.error(&format!("Error while creating node: {}", e));
             return;
         }
     };
     logger.info("Started successfully");
-    let mut i = 0;
+    let wakeup = node.wakeup();
+    let mut timer = Box::pin(wait_ms(10000));
     loop {
-        i = i + 1;
+        // Process messages as soon as they arrive, and list and ping the other
+        // nodes every 10 seconds.
+        match select(Box::pin(wakeup.wait()), timer).await {
+            Either::Left((_, t)) => timer = t,
+            Either::Right(_) => {
+                timer = Box::pin(wait_ms(10000));
+                logger.info("Waiting");
+                if let Err(e) = list_ping(logger.clone(), &mut node).await {
+                    logger.error(&format!("Couldn't list or ping nodes: {}", e));
+                }
+            }
+        }
         if let Err(e) = node.process().await {
             logger.error(&format!("Error while processing messages: {}", e));
         }
-        if i % 10 == 0 {
-            logger.info("Waiting");
-            if let Err(e) = list_ping(logger.clone(), &mut node).await {
-                logger.error(&format!("Couldn't list or ping nodes: {}", e));
-            }
-        }
-        wait_ms(1000).await;
     }
 }
@@ -0,0 +1,88 @@
+use common::node::{
+    ext_interface::{DataStorage, StorageError},
+    storage::{FileStorage, FileSystem},
+    types::now,
+    CONFIG_BACKUP_NAME, CONFIG_NAME,
+};
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+
+#[wasm_bindgen(
+    inline_js = "module.exports.fswrite = function(name, str) { fs.writeFileSync(name, str); }
+    module.exports.fsread = function(name) {
+        try { return fs.readFileSync(name, {encoding: 'utf-8'}); }
+        catch (e) { if (e.code === 'ENOENT') { return undefined; } throw e; }
+    }
+    module.exports.fsremove = function(name) { fs.rmSync(name, {force: true}); }
+    module.exports.fsrename = function(from, to) { fs.renameSync(from, to); }
+    module.exports.fslist = function(dir) {
+        fs.mkdirSync(dir, {recursive: true});
+        return fs.readdirSync(dir);
+    }"
+)]
+extern "C" {
+    #[wasm_bindgen(catch)]
+    fn fswrite(name: &str, str: &str) -> Result<(), JsValue>;
+    #[wasm_bindgen(catch)]
+    fn fsread(name: &str) -> Result<Option<String>, JsValue>;
+    #[wasm_bindgen(catch)]
+    fn fsremove(name: &str) -> Result<(), JsValue>;
+    #[wasm_bindgen(catch)]
+    fn fsrename(from: &str, to: &str) -> Result<(), JsValue>;
+    #[wasm_bindgen(catch)]
+    fn fslist(dir: &str) -> Result<Box<[JsValue]>, JsValue>;
+}
+
+const STORAGE_NAME: &str = "fledger.toml";
+/// Nodes from before kept a single backup of the config here.
+const OLD_BACKUP_NAME: &str = "fledger.toml.bak";
+const DATA_DIR: &str = "data";
+
+/// Gives `FileStorage` access to the `fs` module of node.js.
+struct NodeFileSystem {}
+
+impl FileSystem for NodeFileSystem {
+    fn read(&self, path: &str) -> Result<Option<String>, String> {
+        fsread(path).map_err(|e| format!("While reading file: {:?}", e))
+    }
+
+    fn write(&self, path: &str, value: &str) -> Result<(), String> {
+        fswrite(path, value).map_err(|e| format!("{:?}", e))
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), String> {
+        fsrename(from, to).map_err(|e| format!("{:?}", e))
+    }
+
+    fn remove(&self, path: &str) -> Result<(), String> {
+        fsremove(path).map_err(|e| format!("{:?}", e))
+    }
+
+    fn list(&self, dir: &str) -> Result<Vec<String>, String> {
+        Ok(fslist(dir)
+            .map_err(|e| format!("{:?}", e))?
+            .iter()
+            .filter_map(|name| name.as_string())
+            .collect())
+    }
+}
+
+/// Stores the config in `fledger.toml`, so it can be edited, and all other
+/// keys in their own file in the `data` directory.
+pub fn file_storage() -> Result<FileStorage, StorageError> {
+    let fs = Rc::new(NodeFileSystem {});
+    let storage = FileStorage::with_fs(fs.clone(), DATA_DIR)?.with_file(CONFIG_NAME, STORAGE_NAME);
+    move_old_backup(fs.as_ref(), &storage)?;
+    Ok(storage)
+}
+
+/// Moves the backup of older nodes to a key of its own, like every other
+/// backup, so it shows up with them and is never overwritten.
+fn move_old_backup(fs: &dyn FileSystem, storage: &FileStorage) -> Result<(), StorageError> {
+    if let Some(old) = fs.read(OLD_BACKUP_NAME).map_err(StorageError::Load)? {
+        let key = format!("{}-{}", CONFIG_BACKUP_NAME, now() as u64);
+        storage.save(&key, &old)?;
+        fs.remove(OLD_BACKUP_NAME).map_err(StorageError::Remove)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,379 @@
+//! The TCP transport for the node running in node.js, which has no access to
+//! the sockets of the standard library. It uses the `net` module of node.js
+//! and speaks the same protocol as the TcpTransport of the native nodes, so
+//! the connections are authenticated and encrypted with Noise.
+use async_trait::async_trait;
+use common::{
+    node::{
+        config::NodeInfo,
+        events::{channel, Receiver, Sender, Wakeup},
+        ext_interface::Logger,
+        network::{
+            connection_state::CSEnum,
+            noise::{frame, Frames, Handshake, NoiseError, NoiseReader, NoiseWriter},
+            transport::{Transport, TransportError, TransportKind, TransportOutput},
+        },
+        types::U256,
+    },
+    signal::web_rtc::WebRTCConnectionState,
+};
+use ed25519_dalek::SigningKey;
+use js_sys::Uint8Array;
+use std::{cell::Cell, collections::HashMap, rc::Rc};
+use wasm_bindgen::prelude::*;
+
+#[wasm_bindgen(
+    inline_js = "module.exports.tcp_listen = function(host, port, on_socket) {
+        const server = net.createServer(on_socket);
+        server.on('error', (e) => console.error('TCP server failed: ' + e.message));
+        server.listen(port, host);
+        return server;
+    }
+    module.exports.tcp_connect = function(host, port) { return net.connect(port, host); }
+    module.exports.tcp_watch = function(socket, on_data, on_close) {
+        let error = 'closed';
+        socket.on('data', (data) => on_data(new Uint8Array(data)));
+        socket.on('error', (e) => { error = e.message; });
+        socket.on('close', () => on_close(error));
+    }
+    module.exports.tcp_write = function(socket, data) { socket.write(Buffer.from(data)); }
+    module.exports.tcp_destroy = function(socket) { socket.destroy(); }"
+)]
+extern "C" {
+    fn tcp_listen(host: &str, port: u16, on_socket: &JsValue) -> JsValue;
+    fn tcp_connect(host: &str, port: u16) -> JsValue;
+    fn tcp_watch(socket: &JsValue, on_data: &JsValue, on_close: &JsValue);
+    fn tcp_write(socket: &JsValue, data: &[u8]);
+    fn tcp_destroy(socket: &JsValue);
+}
+
+type DataCB = Closure<dyn FnMut(Uint8Array)>;
+type CloseCB = Closure<dyn FnMut(String)>;
+type SocketCB = Closure<dyn FnMut(JsValue)>;
+
+/// Events from the callbacks of the sockets, which are identified by a
+/// number, as the remote node is only known after the handshake.
+enum TcpEvent {
+    Incoming(usize, JsValue, (DataCB, CloseCB)),
+    Data(usize, Vec<u8>),
+    Closed(usize, String),
+}
+
+enum Session {
+    Handshake(Box<Handshake>),
+    Open(NoiseWriter, NoiseReader),
+    // after an error, until the socket is closed
+    Failed,
+}
+
+struct Socket {
+    socket: JsValue,
+    dir: WebRTCConnectionState,
+    // the node dialed, or the authenticated node once the handshake is done
+    id: Option<U256>,
+    frames: Frames,
+    session: Session,
+    // The callbacks are kept here, so they are freed with the socket.
+    _callbacks: (DataCB, CloseCB),
+}
+
+pub struct NodeTcpTransport {
+    secret: SigningKey,
+    network: U256,
+    // the address the listener binds to
+    listen: String,
+    // the address advertised to the other nodes
+    address: Option<String>,
+    sockets: HashMap<usize, Socket>,
+    // the socket of every connected node
+    links: HashMap<U256, usize>,
+    // messages waiting for the connection to be set up
+    queues: HashMap<U256, Vec<String>>,
+    addresses: HashMap<U256, String>,
+    next_socket: Rc<Cell<usize>>,
+    events_tx: Sender<TcpEvent>,
+    events_rx: Receiver<TcpEvent>,
+    listener: Option<(JsValue, SocketCB)>,
+    logger: Box<dyn Logger>,
+}
+
+impl NodeTcpTransport {
+    /// Creates a transport listening on `listen`, e.g., "0.0.0.0:7070". The
+    /// other nodes connect to `address`, or to `listen` if it is None.
+    /// Only nodes of the network `network` are accepted.
+    pub fn new(
+        logger: Box<dyn Logger>,
+        secret: SigningKey,
+        network: U256,
+        listen: &str,
+        address: Option<&str>,
+        wakeup: &Wakeup,
+    ) -> NodeTcpTransport {
+        let (events_tx, events_rx) = channel(wakeup);
+        NodeTcpTransport {
+            secret,
+            network,
+            listen: listen.to_string(),
+            address: address.map(|a| a.to_string()),
+            sockets: HashMap::new(),
+            links: HashMap::new(),
+            queues: HashMap::new(),
+            addresses: HashMap::new(),
+            next_socket: Rc::new(Cell::new(0)),
+            events_tx,
+            events_rx,
+            listener: None,
+            logger,
+        }
+    }
+
+    /// Connects to the node and starts the handshake. Until it is done, the
+    /// messages are queued.
+    fn dial(&mut self, id: U256, addr: &str) -> Result<(), TransportError> {
+        let (host, port) = split_address(addr).map_err(TransportError::Connect)?;
+        let mut handshake = Handshake::initiate(&self.secret, &self.network, &id)
+            .map_err(|e| TransportError::Connect(e.to_string()))?;
+        let index = next(&self.next_socket);
+        let socket = tcp_connect(&host, port);
+        let callbacks = watch(&socket, index, &self.events_tx);
+        if let Err(e) = answer(&socket, &mut handshake) {
+            tcp_destroy(&socket);
+            return Err(TransportError::Connect(e.to_string()));
+        }
+        self.queues.entry(id.clone()).or_default();
+        self.sockets.insert(
+            index,
+            Socket {
+                socket,
+                dir: WebRTCConnectionState::Initializer,
+                id: Some(id),
+                frames: Frames::default(),
+                session: Session::Handshake(Box::new(handshake)),
+                _callbacks: callbacks,
+            },
+        );
+        Ok(())
+    }
+
+    /// Passes the received bytes to the handshake or decrypts the messages.
+    /// Returns the authenticated node once the handshake is done.
+    fn data(
+        &mut self,
+        index: usize,
+        data: &[u8],
+        outputs: &mut Vec<TransportOutput>,
+    ) -> Result<Option<U256>, NoiseError> {
+        let socket = match self.sockets.get_mut(&index) {
+            Some(socket) => socket,
+            None => return Ok(None),
+        };
+        socket.frames.push(data);
+        let mut connected = None;
+        while let Some(msg) = socket.frames.next_frame()? {
+            socket.session = match std::mem::replace(&mut socket.session, Session::Failed) {
+                Session::Handshake(mut hs) => {
+                    hs.read(&msg)?;
+                    answer(&socket.socket, &mut hs)?;
+                    if hs.is_finished() {
+                        let (id, writer, reader) = hs.finish()?;
+                        socket.id = Some(id.clone());
+                        connected = Some(id);
+                        Session::Open(writer, reader)
+                    } else {
+                        Session::Handshake(hs)
+                    }
+                }
+                Session::Open(writer, mut reader) => {
+                    let id = socket.id.clone().unwrap();
+                    outputs.push(TransportOutput::Message(id, reader.decrypt(&msg)?));
+                    Session::Open(writer, reader)
+                }
+                Session::Failed => break,
+            };
+        }
+        Ok(connected)
+    }
+
+    /// Sends all queued messages of the node.
+    fn flush(&mut self, node: &U256) {
+        for msg in self.queues.remove(node).unwrap_or_default() {
+            if let Err(e) = self.send(node, msg) {
+                self.logger
+                    .warn(&format!("Couldn't send queued message: {}", e));
+            }
+        }
+    }
+
+    fn close(&mut self, index: usize, reason: &str) {
+        if let Some(socket) = self.sockets.get(&index) {
+            self.logger
+                .warn(&format!("Closing connection {:?}: {}", socket.id, reason));
+            tcp_destroy(&socket.socket);
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Transport for NodeTcpTransport {
+    fn kind(&self) -> TransportKind {
+        TransportKind::TCP
+    }
+
+    fn accept(&mut self) -> Result<Option<String>, TransportError> {
+        let (host, port) = split_address(&self.listen).map_err(TransportError::Accept)?;
+        let events = self.events_tx.clone();
+        let next_socket = Rc::clone(&self.next_socket);
+        let on_socket = Closure::wrap(Box::new(move |socket: JsValue| {
+            let index = next(&next_socket);
+            let callbacks = watch(&socket, index, &events);
+            let _ = events.send(TcpEvent::Incoming(index, socket, callbacks));
+        }) as Box<dyn FnMut(JsValue)>);
+        let server = tcp_listen(&host, port, on_socket.as_ref());
+        self.listener = Some((server, on_socket));
+        Ok(Some(
+            self.address.clone().unwrap_or_else(|| self.listen.clone()),
+        ))
+    }
+
+    /// Connects to the address the node advertises in its NodeInfo.
+    fn connect(&mut self, node: &NodeInfo) -> Result<(), TransportError> {
+        if self.links.contains_key(&node.public) || self.queues.contains_key(&node.public) {
+            return Ok(());
+        }
+        let address = node
+            .address(TransportKind::TCP)
+            .ok_or_else(|| TransportError::Connect(format!("{} has no address", node.public)))?
+            .to_string();
+        self.addresses.insert(node.public.clone(), address.clone());
+        self.dial(node.public.clone(), &address)
+    }
+
+    /// Sends the message if the node is connected, else queues it and
+    /// connects again if the address of the node is known.
+    fn send(&mut self, node: &U256, msg: String) -> Result<(), TransportError> {
+        if let Some(index) = self.links.get(node) {
+            if let Some(socket) = self.sockets.get_mut(index) {
+                if let Session::Open(writer, _) = &mut socket.session {
+                    let data = writer
+                        .encrypt(&msg)
+                        .map_err(|e| TransportError::Send(e.to_string()))?;
+                    tcp_write(&socket.socket, &frame(&data));
+                    return Ok(());
+                }
+            }
+        }
+        if !self.queues.contains_key(node) {
+            let addr = self
+                .addresses
+                .get(node)
+                .cloned()
+                .ok_or_else(|| TransportError::Send(format!("{} is not connected", node)))?;
+            self.dial(node.clone(), &addr)?;
+        }
+        self.queues.get_mut(node).unwrap().push(msg);
+        Ok(())
+    }
+
+    async fn receive(&mut self) -> Result<Vec<TransportOutput>, TransportError> {
+        let mut outputs = vec![];
+        let events: Vec<TcpEvent> = self.events_rx.try_iter().collect();
+        for event in events {
+            match event {
+                TcpEvent::Incoming(index, socket, callbacks) => {
+                    match Handshake::respond(&self.secret, &self.network) {
+                        Ok(handshake) => {
+                            self.sockets.insert(
+                                index,
+                                Socket {
+                                    socket,
+                                    dir: WebRTCConnectionState::Follower,
+                                    id: None,
+                                    frames: Frames::default(),
+                                    session: Session::Handshake(Box::new(handshake)),
+                                    _callbacks: callbacks,
+                                },
+                            );
+                        }
+                        Err(e) => {
+                            self.logger.warn(&format!("Couldn't accept: {}", e));
+                            tcp_destroy(&socket);
+                        }
+                    }
+                }
+                TcpEvent::Data(index, data) => match self.data(index, &data, &mut outputs) {
+                    Ok(Some(id)) => {
+                        let dir = self.sockets[&index].dir;
+                        self.logger.info(&format!("Connected {:?} to {}", dir, id));
+                        self.links.insert(id.clone(), index);
+                        self.flush(&id);
+                        outputs.push(TransportOutput::State(id, dir, CSEnum::Connected, None));
+                    }
+                    Ok(None) => {}
+                    Err(e) => self.close(index, &e.to_string()),
+                },
+                TcpEvent::Closed(index, reason) => {
+                    let socket = match self.sockets.remove(&index) {
+                        Some(socket) => socket,
+                        None => continue,
+                    };
+                    let id = match socket.id {
+                        Some(id) => id,
+                        None => continue,
+                    };
+                    self.logger
+                        .warn(&format!("Connection to {} closed: {}", id, reason));
+                    if self.links.get(&id) == Some(&index) {
+                        self.links.remove(&id);
+                    }
+                    for msg in self.queues.remove(&id).unwrap_or_default() {
+                        outputs.push(TransportOutput::Dropped(id.clone(), msg));
+                    }
+                    outputs.push(TransportOutput::State(id, socket.dir, CSEnum::Idle, None));
+                }
+            }
+        }
+        Ok(outputs)
+    }
+}
+
+/// Sends the messages of the handshake as long as it's the turn of this node.
+fn answer(socket: &JsValue, hs: &mut Handshake) -> Result<(), NoiseError> {
+    while hs.is_my_turn() && !hs.is_finished() {
+        tcp_write(socket, &frame(&hs.write()?));
+    }
+    Ok(())
+}
+
+fn next(counter: &Rc<Cell<usize>>) -> usize {
+    let index = counter.get();
+    counter.set(index + 1);
+    index
+}
+
+/// Passes the data and the closing of the socket to the transport.
+fn watch(socket: &JsValue, index: usize, events: &Sender<TcpEvent>) -> (DataCB, CloseCB) {
+    let events_data = events.clone();
+    let on_data = Closure::wrap(Box::new(move |data: Uint8Array| {
+        let _ = events_data.send(TcpEvent::Data(index, data.to_vec()));
+    }) as Box<dyn FnMut(Uint8Array)>);
+    let events = events.clone();
+    let on_close = Closure::wrap(Box::new(move |reason: String| {
+        let _ = events.send(TcpEvent::Closed(index, reason));
+    }) as Box<dyn FnMut(String)>);
+    tcp_watch(socket, on_data.as_ref(), on_close.as_ref());
+    (on_data, on_close)
+}
+
+/// Splits "host:port" for node.js, removing the brackets of IPv6 addresses.
+fn split_address(addr: &str) -> Result<(String, u16), String> {
+    let (host, port) = addr
+        .rsplit_once(':')
+        .ok_or_else(|| format!("{} has no port", addr))?;
+    let port = port
+        .parse()
+        .map_err(|_| format!("{} has an invalid port", addr))?;
+    Ok((
+        host.trim_matches(|c| c == '[' || c == ']').to_string(),
+        port,
+    ))
+}
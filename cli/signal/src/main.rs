@@ -4,11 +4,12 @@
 /// TODO: use the `newID` endpoint to authentify the nodes' public key
 // mod node_list;
 mod state;
+mod turn;
 
 use async_trait::async_trait;
 
 use std::{
-    net::{TcpListener, TcpStream},
+    net::{IpAddr, SocketAddr, TcpListener, TcpStream},
     sync::{Arc, Mutex},
     thread,
     time::Duration,
@@ -16,28 +17,27 @@ use std::{
 use tungstenite::{accept, protocol::Role, Message, WebSocket};
 
 use common::{
-    node::ext_interface::Logger,
-    signal::websocket::{
-        MessageCallbackSend, NewConnectionCallback, WSMessage, WebSocketConnectionSend,
-        WebSocketServer,
+    node::{
+        ext_interface::Logger,
+        logging::{set_filter_str, Record},
+    },
+    signal::{
+        web_rtc::IceServer,
+        websocket::{
+            MessageCallbackSend, NewConnectionCallback, WSError, WSMessage, WebSocketConnectionSend,
+            WebSocketServer,
+        },
     },
 };
 
 use state::ServerState;
+use turn::TurnRelay;
 
 pub struct StdOutLogger {}
 
 impl Logger for StdOutLogger {
-    fn info(&self, s: &str) {
-        println!("{}", s);
-    }
-
-    fn warn(&self, s: &str) {
-        println!("{}", s);
-    }
-
-    fn error(&self, s: &str) {
-        println!("{}", s);
+    fn write(&self, record: &Record) {
+        println!("{:5}: {}", record.level, record);
     }
 
     fn clone(&self) -> Box<dyn Logger> {
@@ -125,16 +125,65 @@ impl WebSocketConnectionSend for UnixWSConnection {
         cb_lock.replace(cb);
     }
 
-    async fn send(&mut self, msg: String) -> Result<(), String> {
-        self.websocket.write_message(Message::Text(msg)).map_err(|e| e.to_string())?;
+    async fn send(&mut self, msg: String) -> Result<(), WSError> {
+        self.websocket
+            .write_message(Message::Text(msg))
+            .map_err(|e| WSError::Send(e.to_string()))?;
         Ok(())
     }
 }
 
+/// Reads the STUN and TURN servers to be sent to the nodes from the
+/// `FLEDGER_ICE_SERVERS` environment variable, which holds a JSON list like
+/// `[{"urls": ["turn:example.org"], "username": "user", "credential": "pass"}]`.
+fn ice_servers(logger: &dyn Logger) -> Vec<IceServer> {
+    match std::env::var("FLEDGER_ICE_SERVERS") {
+        Ok(servers) => serde_json::from_str(&servers).unwrap_or_else(|e| {
+            logger.error(&format!("Invalid FLEDGER_ICE_SERVERS: {}", e));
+            vec![]
+        }),
+        Err(_) => vec![],
+    }
+}
+
+/// Starts the embedded TURN server if `FLEDGER_TURN` is set to the public IP
+/// of this server, optionally followed by the UDP port, e.g. `1.2.3.4:3478`.
+fn turn_relay(logger: &dyn Logger) -> Option<TurnRelay> {
+    let turn = std::env::var("FLEDGER_TURN").ok()?;
+    let addr = match turn.parse::<SocketAddr>() {
+        Ok(addr) => addr,
+        Err(_) => match turn.parse::<IpAddr>() {
+            Ok(ip) => SocketAddr::new(ip, 3478),
+            Err(e) => {
+                logger.error(&format!("Invalid FLEDGER_TURN: {}", e));
+                return None;
+            }
+        },
+    };
+    let port = addr.port();
+    match TurnRelay::start(addr.ip(), port) {
+        Ok(relay) => {
+            logger.info(&format!("TURN server listening on UDP port {}", port));
+            Some(relay)
+        }
+        Err(e) => {
+            logger.error(&format!("Couldn't start TURN server: {}", e));
+            None
+        }
+    }
+}
+
 fn main() {
     let logger = Box::new(StdOutLogger {});
+    if let Ok(filter) = std::env::var("FLEDGER_LOG") {
+        if let Err(e) = set_filter_str(&filter) {
+            logger.error(&format!("Invalid FLEDGER_LOG: {}", e));
+        }
+    }
+    let ice_servers = ice_servers(logger.as_ref());
+    let turn = turn_relay(logger.as_ref());
     let ws = Box::new(UnixWebSocket::new());
-    let state = ServerState::new(logger, ws);
+    let state = ServerState::new(logger, ws, ice_servers, turn);
     println!("Server started and listening on port 8765");
     state.wait_done(Duration::from_secs(30));
 }
@@ -4,9 +4,14 @@ use std::thread;
 
 use common::{
     node::{ext_interface::Logger, types::U256},
-    signal::websocket::{WebSocketConnectionSend, WebSocketServer},
+    signal::{
+        web_rtc::IceServer,
+        websocket::{WebSocketConnectionSend, WebSocketServer},
+    },
 };
 
+use crate::turn::TurnRelay;
+
 mod internal;
 mod node_entry;
 use internal::Internal;
@@ -21,9 +26,16 @@ pub struct ServerState {
 /// - listen for incoming websocket requests
 /// - handle webrtc signalling setup
 impl ServerState {
-    pub fn new(logger: Box<dyn Logger>, mut ws: Box<dyn WebSocketServer>) -> ServerState {
+    /// The ice_servers are sent to every node after the challenge. If a TURN
+    /// relay is given, every announced node gets its own credentials for it.
+    pub fn new(
+        logger: Box<dyn Logger>,
+        mut ws: Box<dyn WebSocketServer>,
+        ice_servers: Vec<IceServer>,
+        turn: Option<TurnRelay>,
+    ) -> ServerState {
         let ss = ServerState {
-            int: Internal::new(logger),
+            int: Internal::new(logger, ice_servers, turn),
         };
         let int_cl = Arc::clone(&ss.int);
         ws.set_cb_connection(Box::new(move |conn| {
@@ -43,9 +55,8 @@ impl ServerState {
 
         let mut int_lock = int.lock().unwrap();
         let logger = int_lock.logger.clone();
-        int_lock
-            .nodes
-            .insert(challenge.clone(), NodeEntry::new(logger, challenge, conn));
+        let ne = NodeEntry::new(logger, challenge.clone(), conn, &int_lock.ice_servers);
+        int_lock.nodes.insert(challenge, ne);
     }
 
     /// Waits for everything done while calling cleanup from time to time.
@@ -10,35 +10,48 @@ use std::{
 };
 
 use common::{
-    node::{config::NodeInfo, ext_interface::Logger, types::U256},
+    node::{
+        config::{NodeInfo, NodeType},
+        ext_interface::Logger,
+        types::{now, U256},
+    },
     signal::{
-        web_rtc::{WSSignalMessage, WebSocketMessage},
+        web_rtc::{IceServer, WSSignalMessage, WebSocketMessage},
         websocket::WSMessage,
     },
 };
 
 use super::node_entry::NodeEntry;
+use crate::turn::{TurnRelay, CREDENTIALS_TTL};
 
 pub struct Internal {
     pub logger: Box<dyn Logger>,
     pub nodes: HashMap<U256, NodeEntry>,
     // Left: public - Right: challenge
     pub_chal: BiMap<U256, U256>,
+    // sent to all nodes after the challenge
+    pub ice_servers: Vec<IceServer>,
+    turn: Option<TurnRelay>,
 }
 
 impl Internal {
-    pub fn new(logger: Box<dyn Logger>) -> Arc<Mutex<Internal>> {
+    pub fn new(
+        logger: Box<dyn Logger>,
+        ice_servers: Vec<IceServer>,
+        turn: Option<TurnRelay>,
+    ) -> Arc<Mutex<Internal>> {
         let int = Arc::new(Mutex::new(Internal {
             logger,
             nodes: HashMap::new(),
             pub_chal: BiMap::new(),
+            ice_servers,
+            turn,
         }));
         int
     }
 
     /// Treats incoming messages from nodes.
     pub fn cb_msg(&mut self, chal: &U256, msg: WSMessage) {
-        self.logger.info(&format!("Got message: {:?}", msg));
         match msg {
             WSMessage::MessageString(s) => self.receive_msg(chal, s),
             WSMessage::Closed(_) => self.close_ws(),
@@ -65,6 +78,21 @@ impl Internal {
         }
     }
 
+    /// Returns the network the node announced.
+    fn network(&self, public: &U256) -> Option<U256> {
+        let chal = self.pub_to_chal(public)?;
+        let info = self.nodes.get(&chal)?.info.as_ref()?;
+        Some(info.network.clone())
+    }
+
+    /// Nodes can only set up connections to nodes of the same network.
+    fn same_network(&self, a: &U256, b: &U256) -> bool {
+        match (self.network(a), self.network(b)) {
+            (Some(na), Some(nb)) => na == nb,
+            _ => false,
+        }
+    }
+
     /// Receives a message from the websocket. Src is the challenge-ID, which is
     /// random and only tied to the public ID through self.pub_chal.
     fn receive_msg(&mut self, chal: &U256, msg: String) {
@@ -78,6 +106,9 @@ impl Internal {
                 return;
             }
         };
+        // Only the type is logged, as some messages hold credentials.
+        self.logger
+            .info(&format!("Got {} from {}", msg_ws.msg, chal));
 
         if let Some(node) = self.nodes.get_mut(chal) {
             node.last_seen = Instant::now();
@@ -86,10 +117,22 @@ impl Internal {
         match msg_ws.msg {
             // Node sends his information to the server
             WSSignalMessage::Announce(msg_ann) => {
+                if let Err(e) = msg_ann.node_info.verify_fresh() {
+                    self.logger.warn(&format!(
+                        "Ignoring announce of {}: {}",
+                        msg_ann.node_info.public, e
+                    ));
+                    return;
+                }
                 self.logger
                     .info(&format!("Storing node {:?}", msg_ann.node_info));
                 let public = msg_ann.node_info.public.clone();
-                self.nodes.retain(|_, ni| {
+                // A node announces itself again on the same connection when
+                // its information changes.
+                self.nodes.retain(|c, ni| {
+                    if c == chal {
+                        return true;
+                    }
                     if let Some(info) = ni.info.clone() {
                         return info.public != public;
                     }
@@ -100,6 +143,7 @@ impl Internal {
                 self.nodes
                     .entry(chal.clone())
                     .and_modify(|ne| ne.info = Some(msg_ann.node_info));
+                self.send_turn_credentials(chal);
             }
 
             // Node requests deleting of the list of all nodes
@@ -109,18 +153,26 @@ impl Internal {
                 self.nodes.clear();
             }
 
-            // Node requests a list of all currently connected nodes,
-            // including itself.
+            // Node requests a list of all currently connected nodes of its
+            // network, including itself. Servers come first, as they stay
+            // online longer.
             WSSignalMessage::ListIDsRequest => {
-                let ids: Vec<NodeInfo> = self
+                let network = self
+                    .nodes
+                    .get(chal)
+                    .and_then(|ne| ne.info.as_ref())
+                    .map(|ni| ni.network.clone());
+                let mut ids: Vec<NodeInfo> = self
                     .nodes
                     .iter()
-                    .filter(|ne| ne.1.info.is_some())
-                    .map(|ne| ne.1.info.clone().unwrap())
+                    .filter_map(|ne| ne.1.info.clone())
+                    .filter(|ni| Some(&ni.network) == network.as_ref())
                     .collect();
+                ids.sort_by_key(|ni| ni.node_type != NodeType::Server);
                 if let Some(src) = self.chal_to_pub(chal) {
                     self.send_message_errlog(&src, WSSignalMessage::ListIDsReply(ids));
                 }
+                self.renew_turn_credentials(chal);
             }
 
             // Node sends a PeerRequest with some of the data set to 'Some'.
@@ -136,18 +188,74 @@ impl Internal {
                         .error("Node sent a PeerSetup without including itself");
                     return;
                 };
+                if !self.same_network(&src, dst) {
+                    self.logger.warn(&format!(
+                        "Dropping PeerSetup from {} to another network",
+                        src
+                    ));
+                    return;
+                }
                 self.send_message_errlog(&dst, WSSignalMessage::PeerSetup(pr.clone()));
             }
+
+            // Node sends a message to another node it cannot reach over WebRTC.
+            WSSignalMessage::Relay(mut rm) => {
+                rm.from = match self.chal_to_pub(chal) {
+                    Some(public) => public,
+                    None => return,
+                };
+                let allowed = match self.nodes.get_mut(chal) {
+                    Some(ne) => ne.relay_limit.allow(now(), rm.msg.len()),
+                    None => false,
+                };
+                if !allowed || !self.same_network(&rm.from, &rm.to) {
+                    self.logger
+                        .warn(&format!("Dropping relay message from {}", rm.from));
+                    return;
+                }
+                let dst = rm.to.clone();
+                self.send_message_errlog(&dst, WSSignalMessage::Relay(rm));
+            }
             _ => {}
         }
     }
 
+    /// Sends new TURN credentials to the node, together with the other ICE
+    /// servers, as the node replaces its list of servers from the signal server.
+    fn send_turn_credentials(&mut self, chal: &U256) {
+        let public = match self.chal_to_pub(chal) {
+            Some(public) => public,
+            None => return,
+        };
+        let turn_server = match self.turn.as_ref() {
+            Some(turn) => turn.ice_server(&public),
+            None => return,
+        };
+        if let Some(ne) = self.nodes.get_mut(chal) {
+            ne.turn_expiry = Some(Instant::now() + CREDENTIALS_TTL);
+        }
+        let mut servers = self.ice_servers.clone();
+        servers.push(turn_server);
+        self.send_message_errlog(&public, WSSignalMessage::IceServers(servers));
+    }
+
+    /// Sends new credentials if the current ones expire in less than half
+    /// of their lifetime.
+    fn renew_turn_credentials(&mut self, chal: &U256) {
+        let renew = match self.nodes.get(chal).and_then(|ne| ne.turn_expiry) {
+            Some(expiry) => expiry < Instant::now() + CREDENTIALS_TTL / 2,
+            None => false,
+        };
+        if renew {
+            self.send_turn_credentials(chal);
+        }
+    }
+
     fn send_message_errlog(&mut self, public: &U256, msg: WSSignalMessage) {
-        self.logger
-            .info(&format!("Sending to {}: {:?}", public, msg));
+        self.logger.info(&format!("Sending {} to {}", msg, public));
         if let Err(e) = self.send_message(public, msg.clone()) {
             self.logger
-                .error(&format!("Error {} while sending {:?}", e, msg));
+                .error(&format!("Error {} while sending {}", e, msg));
         }
     }
 
@@ -2,9 +2,14 @@ use futures::executor;
 use std::{fmt, time::Instant};
 
 use common::{
-    node::{config::NodeInfo, ext_interface::Logger, types::U256},
+    node::{
+        config::NodeInfo,
+        ext_interface::Logger,
+        types::{now, U256},
+    },
     signal::{
-        web_rtc::{WSSignalMessage, WebSocketMessage},
+        relay::RateLimit,
+        web_rtc::{IceServer, WSSignalMessage, WebSocketMessage},
         websocket::WebSocketConnectionSend,
     },
 };
@@ -13,6 +18,10 @@ pub struct NodeEntry {
     pub conn: Box<dyn WebSocketConnectionSend>,
     pub info: Option<NodeInfo>,
     pub last_seen: Instant,
+    /// When the TURN credentials given to this node expire.
+    pub turn_expiry: Option<Instant>,
+    /// Limits the messages this node can relay to other nodes.
+    pub relay_limit: RateLimit,
     entry: U256,
     logger: Box<dyn Logger>,
 }
@@ -29,6 +38,7 @@ impl NodeEntry {
         logger: Box<dyn Logger>,
         entry: U256,
         conn: Box<dyn WebSocketConnectionSend>,
+        ice_servers: &[IceServer],
     ) -> NodeEntry {
         let mut ne = NodeEntry {
             info: None,
@@ -36,14 +46,21 @@ impl NodeEntry {
             entry,
             conn,
             last_seen: Instant::now(),
+            turn_expiry: None,
+            relay_limit: RateLimit::new(now()),
         };
-        let msg = serde_json::to_string(&WebSocketMessage {
-            msg: WSSignalMessage::Challenge(ne.entry.clone()),
-        })
-        .unwrap();
-        if let Err(e) = executor::block_on(ne.conn.send(msg)) {
-            ne.logger.error(&format!("while sending challenge: {}", e));
+        ne.send(WSSignalMessage::Challenge(ne.entry.clone()));
+        if !ice_servers.is_empty() {
+            ne.send(WSSignalMessage::IceServers(ice_servers.to_vec()));
         }
         ne
     }
+
+    fn send(&mut self, msg: WSSignalMessage) {
+        let name = msg.to_string();
+        let msg = serde_json::to_string(&WebSocketMessage { msg }).unwrap();
+        if let Err(e) = executor::block_on(self.conn.send(msg)) {
+            self.logger.error(&format!("while sending {}: {}", name, e));
+        }
+    }
 }
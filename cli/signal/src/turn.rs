@@ -0,0 +1,197 @@
+/// An embedded TURN and STUN server, so that nodes behind symmetric NATs can
+/// connect without running coturn separately.
+/// Every announced node gets its own credentials that are only valid for
+/// CREDENTIALS_TTL.
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use thiserror::Error;
+use tokio::{net::UdpSocket, runtime::Runtime};
+use turn::{
+    auth::{generate_auth_key, AuthHandler},
+    relay::relay_static::RelayAddressGeneratorStatic,
+    server::{
+        config::{ConnConfig, ServerConfig},
+        Server,
+    },
+};
+use webrtc_util::vnet::net::Net;
+
+use common::{node::types::U256, signal::web_rtc::IceServer};
+
+/// How long the credentials given to a node are valid.
+pub const CREDENTIALS_TTL: Duration = Duration::from_secs(3600);
+
+const REALM: &str = "fledger";
+
+#[derive(Error, Debug)]
+pub enum TurnError {
+    #[error("couldn't open socket: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("couldn't start TURN server: {0}")]
+    Turn(#[from] turn::Error),
+}
+
+/// The credentials that are currently valid, indexed by the username.
+#[derive(Default)]
+struct Credentials {
+    users: Mutex<HashMap<String, (String, SystemTime)>>,
+}
+
+impl Credentials {
+    /// Creates new credentials for the node and removes the expired ones.
+    fn issue(&self, node: &U256) -> (String, String) {
+        let now = SystemTime::now();
+        let expiry = now + CREDENTIALS_TTL;
+        let secs = expiry
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let username = format!("{}:{}", secs, node);
+        let password = U256::rnd().to_string();
+        let mut users = self.users.lock().unwrap();
+        users.retain(|_, (_, exp)| *exp > now);
+        users.insert(username.clone(), (password.clone(), expiry));
+        (username, password)
+    }
+}
+
+impl AuthHandler for Credentials {
+    fn auth_handle(
+        &self,
+        username: &str,
+        realm: &str,
+        _src_addr: SocketAddr,
+    ) -> Result<Vec<u8>, turn::Error> {
+        match self.users.lock().unwrap().get(username) {
+            Some((password, expiry)) if *expiry > SystemTime::now() => {
+                Ok(generate_auth_key(username, realm, password))
+            }
+            Some(_) => Err(turn::Error::Other(format!("expired user {}", username))),
+            None => Err(turn::Error::Other(format!("unknown user {}", username))),
+        }
+    }
+}
+
+pub struct TurnRelay {
+    urls: Vec<String>,
+    credentials: Arc<Credentials>,
+    server: Server,
+    runtime: Runtime,
+}
+
+impl TurnRelay {
+    /// Starts the server on the given UDP port. The public_ip is given to the
+    /// nodes, so it must be reachable by them. For local tests, 127.0.0.1 can
+    /// be used.
+    pub fn start(public_ip: IpAddr, port: u16) -> Result<TurnRelay, TurnError> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?;
+        let credentials = Arc::new(Credentials::default());
+        let auth = Arc::clone(&credentials);
+        let server = runtime.block_on(async move {
+            let conn = Arc::new(UdpSocket::bind(("0.0.0.0", port)).await?);
+            Ok::<_, TurnError>(
+                Server::new(ServerConfig {
+                    conn_configs: vec![ConnConfig {
+                        conn,
+                        relay_addr_generator: Box::new(RelayAddressGeneratorStatic {
+                            relay_address: public_ip,
+                            address: "0.0.0.0".to_string(),
+                            net: Arc::new(Net::new(None)),
+                        }),
+                    }],
+                    realm: REALM.to_string(),
+                    auth_handler: auth,
+                    channel_bind_timeout: Duration::from_secs(0),
+                    alloc_close_notify: None,
+                })
+                .await?,
+            )
+        })?;
+        Ok(TurnRelay {
+            urls: vec![
+                format!("stun:{}:{}", public_ip, port),
+                format!("turn:{}:{}", public_ip, port),
+            ],
+            credentials,
+            server,
+            runtime,
+        })
+    }
+
+    /// Returns a new IceServer with credentials for this node.
+    pub fn ice_server(&self, node: &U256) -> IceServer {
+        let (username, credential) = self.credentials.issue(node);
+        IceServer {
+            urls: self.urls.clone(),
+            username: Some(username),
+            credential: Some(credential),
+        }
+    }
+}
+
+impl Drop for TurnRelay {
+    fn drop(&mut self) {
+        let _ = self.runtime.block_on(self.server.close());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use turn::client::{Client, ClientConfig};
+    use webrtc_util::Conn;
+
+    #[test]
+    fn credentials() {
+        let creds = Credentials::default();
+        let addr: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+        let (user, pass) = creds.issue(&U256::rnd());
+        assert_eq!(
+            creds.auth_handle(&user, REALM, addr).unwrap(),
+            generate_auth_key(&user, REALM, &pass)
+        );
+        assert!(creds.auth_handle("unknown", REALM, addr).is_err());
+
+        creds
+            .users
+            .lock()
+            .unwrap()
+            .insert("old".to_string(), ("pass".to_string(), UNIX_EPOCH));
+        assert!(creds.auth_handle("old", REALM, addr).is_err());
+        creds.issue(&U256::rnd());
+        assert!(!creds.users.lock().unwrap().contains_key("old"));
+    }
+
+    #[test]
+    fn allocate_on_loopback() -> Result<(), TurnError> {
+        let relay = TurnRelay::start("127.0.0.1".parse().unwrap(), 34780)?;
+        let server = relay.ice_server(&U256::rnd());
+        relay.runtime.block_on(async {
+            let conn = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+            let client = Client::new(ClientConfig {
+                stun_serv_addr: "127.0.0.1:34780".to_string(),
+                turn_serv_addr: "127.0.0.1:34780".to_string(),
+                username: server.username.unwrap(),
+                password: server.credential.unwrap(),
+                realm: REALM.to_string(),
+                software: String::new(),
+                rto_in_ms: 0,
+                conn,
+                vnet: None,
+            })
+            .await?;
+            client.listen().await?;
+            let allocation = client.allocate().await?;
+            assert_eq!("127.0.0.1", allocation.local_addr().unwrap().ip().to_string());
+            client.close().await?;
+            Ok(())
+        })
+    }
+}
@@ -1,2 +1,4 @@
 pub mod node;
 pub mod signal;
+#[cfg(any(test, feature = "simul"))]
+pub mod simul;
@@ -1,129 +1,299 @@
 pub mod config;
+pub mod events;
 pub mod ext_interface;
+pub mod logging;
 pub mod logic;
 pub mod network;
+pub mod peer_cache;
+pub mod storage;
 pub mod types;
 
 use crate::node::{
-    config::{NodeConfig, NodeInfo},
-    ext_interface::{DataStorage, Logger},
-    logic::Logic,
-    network::{NOutput, Network},
-    types::U256,
+    config::{ConfigError, Ledger, NodeConfig, NodeInfo},
+    events::{ChannelError, Wakeup},
+    ext_interface::{DataStorage, Logger, StorageError},
+    logging::Level,
+    logic::{latency::LatencyMatrix, Logic},
+    network::{
+        timeline::ConnectionTimeline, transport::Transport, web_rtc_transport::WebRTCTransport,
+        NOutput, Network, NetworkError,
+    },
+    peer_cache::{PeerCache, PEER_CACHE_NAME},
+    types::{now, system_clock, Clock, U256},
 };
 use crate::signal::{web_rtc::WebRTCSpawner, websocket::WebSocketConnection};
+use ed25519_dalek::SigningKey;
+use std::rc::Rc;
+use thiserror::Error;
 
 use self::{
     logic::{LInput, LOutput},
     network::NInput,
 };
 
+/// All errors that can be returned by the node. The errors of the
+/// submodules are kept, so that the caller can react on specific failures.
+#[derive(Error, Debug)]
+pub enum NodeError {
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+    #[error(transparent)]
+    Network(#[from] NetworkError),
+    #[error(transparent)]
+    Channel(#[from] ChannelError),
+}
+
 /// The node structure holds it all together. It is the main structure of the project.
 pub struct Node {
     pub network: Network,
     pub info: NodeInfo,
     pub logic: Logic,
-    _storage: Box<dyn DataStorage>,
-    _logger: Box<dyn Logger>,
+    config: NodeConfig,
+    peer_cache: PeerCache,
+    storage: Box<dyn DataStorage>,
+    logger: Box<dyn Logger>,
+    clock: Clock,
+    wakeup: Wakeup,
 }
 
 pub const CONFIG_NAME: &str = "nodeConfig";
+/// The prefix of the keys holding the previous configs, when they had to be
+/// migrated to a new version or were replaced. Every backup gets its own key
+/// ending with the time it was made, so no backup is overwritten.
+pub const CONFIG_BACKUP_NAME: &str = "nodeConfigBackup";
+
+/// How many times `process` passes messages between the modules before
+/// returning. This avoids looping forever if the modules keep sending
+/// messages to each other.
+const PROCESS_ROUNDS: usize = 10;
+
+/// How many peers of the previous run the node reconnects to at startup.
+const RECONNECT_PEERS: usize = 8;
+
+/// Stores a backup of the config under a new key with the given time, or a
+/// later one if the key is taken.
+fn save_backup(storage: &dyn DataStorage, config: &str, time: f64) -> Result<(), NodeError> {
+    let mut time = time as u64;
+    let key = loop {
+        let key = format!("{}-{}", CONFIG_BACKUP_NAME, time);
+        if storage.get(&key)?.is_none() {
+            break key;
+        }
+        time += 1;
+    };
+    Ok(storage.save(&key, config)?)
+}
 
 impl Node {
     /// Create new node by loading the config from the storage.
     /// This also initializes the network and starts listening for
     /// new messages from the signalling server and from other nodes.
     /// The actual logic is handled in Logic.
+    /// If the secret key of the node is encrypted, the passphrase is needed
+    /// to unlock it, else `ConfigError::Locked` is returned.
+    /// With a passphrase, a plain secret key gets encrypted.
     pub fn new(
-        _storage: Box<dyn DataStorage>,
+        storage: Box<dyn DataStorage>,
+        passphrase: Option<&str>,
+        logger: Box<dyn Logger>,
+        ws: Box<dyn WebSocketConnection>,
+        web_rtc: WebRTCSpawner,
+    ) -> Result<Node, NodeError> {
+        Node::with_clock(storage, passphrase, logger, ws, web_rtc, system_clock())
+    }
+
+    /// Like `new`, but all times of the node are read from the given clock.
+    pub fn with_clock(
+        storage: Box<dyn DataStorage>,
+        passphrase: Option<&str>,
         logger: Box<dyn Logger>,
         ws: Box<dyn WebSocketConnection>,
         web_rtc: WebRTCSpawner,
-    ) -> Result<Node, String> {
-        let config_str = match _storage.load(CONFIG_NAME) {
+        clock: Clock,
+    ) -> Result<Node, NodeError> {
+        let config_str = match storage.load(CONFIG_NAME) {
             Ok(s) => s,
             Err(_) => {
                 logger.info(&format!("Couldn't load configuration - start with empty"));
                 "".to_string()
             }
         };
-        let config = NodeConfig::new(config_str)?;
-        _storage.save(CONFIG_NAME, &config.to_string()?)?;
+        let config = NodeConfig::unlock(config_str.clone(), passphrase)?;
+        if let Some(version) = config.migrated_from() {
+            logger.info(&format!("Migrated configuration from version {}", version));
+            save_backup(storage.as_ref(), &config_str, clock())?;
+        }
+        storage.save(CONFIG_NAME, &config.to_string()?)?;
+        let logger = logger.with_context("node", &[("node", config.our_node.public.to_string())]);
         logger.info(&format!(
-            "Starting node: {} = {}",
-            config.our_node.info, config.our_node.public
+            "Starting node: {} = {} in network {}",
+            config.our_node.label(),
+            config.our_node.public,
+            config.ledger.name
         ));
-        let network = Network::new(logger.clone(), config.our_node.clone(), ws, web_rtc);
-        let logic = Logic::new(config.our_node.clone(), logger.clone());
+        let wakeup = Wakeup::new();
+        let mut network = Network::new(
+            logger.with_context("network", &[]),
+            config.our_node.clone(),
+            config.secret.clone(),
+            ws,
+            wakeup.clone(),
+        );
+        network.add_transport(Box::new(WebRTCTransport::new(
+            logger.with_context("network::web_rtc", &[]),
+            config.our_node.public.clone(),
+            config.ice_servers.clone(),
+            config.relay_fallback,
+            web_rtc,
+            Rc::clone(&clock),
+            wakeup.clone(),
+        )))?;
+        let logic = Logic::new(
+            config.our_node.clone(),
+            logger.with_context("logic", &[]),
+            Rc::clone(&clock),
+            &wakeup,
+        );
+        let peer_cache = PeerCache::load(storage.namespace(PEER_CACHE_NAME), &config.our_node)?;
+        let mut peers: Vec<NodeInfo> = peer_cache
+            .best(RECONNECT_PEERS, clock())
+            .into_iter()
+            .map(|p| p.node_info)
+            .collect();
+        for ni in config.ledger.bootstrap.iter() {
+            match ni.verify() {
+                Ok(_) if config.our_node.compatible(ni) => peers.push(ni.clone()),
+                Ok(_) => logger.warn(&format!("Bootstrap node {} is not compatible", ni.public)),
+                Err(e) => logger.warn(&format!("Bootstrap node {}: {}", ni.public, e)),
+            }
+        }
+        logger.info(&format!("Reconnecting to {} known peers", peers.len()));
+        network.reconnect(peers);
 
         Ok(Node {
-            info: config.our_node,
-            _storage,
+            info: network.node_info(),
+            peer_cache,
+            storage,
             network,
-            _logger: logger,
+            logger,
             logic,
+            config,
+            clock,
+            wakeup,
         })
     }
 
-    pub async fn process(&mut self) -> Result<(), String> {
-        self.process_logic()?;
-        self.process_network()?;
-        self.logic.process().await?;
-        self.network.process().await?;
+    /// Processes all waiting messages. As long as the modules create new messages
+    /// for each other, they are passed on, so that a message from the websocket
+    /// or a WebRTC connection is treated completely by one call to process.
+    pub async fn process(&mut self) -> Result<(), NodeError> {
+        for _ in 0..PROCESS_ROUNDS {
+            self.wakeup.clear();
+            self.process_logic()?;
+            self.process_network()?;
+            self.logic.process().await?;
+            self.network.process().await?;
+            if !self.wakeup.is_pending() {
+                break;
+            }
+        }
+        if let Err(e) = self.peer_cache.update(&self.logic.stats, (self.clock)()) {
+            self.logger.warn(&format!("Couldn't store peers: {}", e));
+        }
         Ok(())
     }
 
-    fn process_network(&mut self) -> Result<(), String> {
+    /// Processes new messages as soon as they arrive. This method only returns
+    /// if the future is dropped.
+    /// If the node needs to be accessed while running, use `wakeup` instead.
+    pub async fn run(&mut self) {
+        loop {
+            self.wakeup.wait().await;
+            if let Err(e) = self.process().await {
+                self.logger
+                    .error(&format!("Couldn't process messages: {}", e));
+            }
+        }
+    }
+
+    /// Returns the Wakeup of this node. Its `wait` method resolves as soon as
+    /// new messages are available, and `process` should be called.
+    pub fn wakeup(&self) -> Wakeup {
+        self.wakeup.clone()
+    }
+
+    fn process_network(&mut self) -> Result<(), NodeError> {
         let msgs: Vec<NOutput> = self.network.output_rx.try_iter().collect();
         for msg in msgs {
             match msg {
                 NOutput::WebRTC(id, msg) => {
-                    // self.logger.info(&format!(
-                    //     "dbg: Node::process webrtc message {} from {}",
-                    //     msg, id
-                    // ));
-                    self.logic
-                        .input_tx
-                        .send(LInput::WebRTC(id, msg))
-                        .map_err(|e| e.to_string())?;
+                    self.logger.log(
+                        Level::Debug,
+                        "Got WebRTC message",
+                        &[("peer", id.to_string()), ("msg", msg.clone())],
+                    );
+                    self.logic.input_tx.send(LInput::WebRTC(id, msg))?;
                 }
-                NOutput::UpdateList(list) => self
-                    .logic
-                    .input_tx
-                    .send(LInput::SetNodes(list))
-                    .map_err(|e| e.to_string())?,
-                NOutput::State(id, dir, c, s) => self
-                    .logic
-                    .input_tx
-                    .send(LInput::ConnStat(id, dir, c, s))
-                    .map_err(|e| e.to_string())?,
+                NOutput::UpdateList(list) => self.logic.input_tx.send(LInput::SetNodes(list))?,
+                NOutput::State(id, dir, c, s) => {
+                    self.logic.input_tx.send(LInput::ConnStat(id, dir, c, s))?
+                }
+                NOutput::Dropped(id, msg) => self.logic.input_tx.send(LInput::Dropped(id, msg))?,
+                NOutput::Relayed(id, relayed) => {
+                    self.logic.input_tx.send(LInput::Relayed(id, relayed))?
+                }
+                NOutput::Reconnect(list) => self.logic.input_tx.send(LInput::Reconnect(list))?,
             }
         }
         Ok(())
     }
 
-    fn process_logic(&mut self) -> Result<(), String> {
+    fn process_logic(&mut self) -> Result<(), NodeError> {
         let msgs: Vec<LOutput> = self.logic.output_rx.try_iter().collect();
         for msg in msgs {
             match msg {
-                logic::LOutput::WebRTC(id, msg) => self
-                    .network
-                    .input_tx
-                    .send(NInput::WebRTC(id, msg))
-                    .map_err(|e| e.to_string())?,
+                logic::LOutput::WebRTC(id, msg) => {
+                    self.network.input_tx.send(NInput::WebRTC(id, msg))?
+                }
             }
         }
         Ok(())
     }
 
+    /// Returns the secret key of this node, e.g., to authenticate the
+    /// connections of a TcpTransport.
+    pub fn secret(&self) -> SigningKey {
+        self.config.secret.clone()
+    }
+
+    /// Adds another transport to reach the other nodes. It must be added before
+    /// the first call to `process`, so that it is announced to the other nodes.
+    pub fn add_transport(&mut self, transport: Box<dyn Transport>) -> Result<(), NodeError> {
+        self.network.add_transport(transport)?;
+        self.info = self.network.node_info();
+        Ok(())
+    }
+
+    /// Sets the alias shown next to the name of the node, or removes it,
+    /// stores it in the configuration, and announces it to the signal server.
+    pub fn set_alias(&mut self, alias: Option<&str>) -> Result<(), NodeError> {
+        self.config.set_alias(alias)?;
+        self.storage.save(CONFIG_NAME, &self.config.to_string()?)?;
+        self.network.set_alias(self.config.our_node.alias.clone())?;
+        self.info = self.network.node_info();
+        Ok(())
+    }
+
     /// TODO: this is only for development
-    pub fn clear(&mut self) -> Result<(), String> {
-        self.network.clear_nodes()
+    pub fn clear(&mut self) -> Result<(), NodeError> {
+        Ok(self.network.clear_nodes()?)
     }
 
     /// Requests a list of all connected nodes
-    pub fn list(&mut self) -> Result<(), String> {
-        self.network.update_node_list()
+    pub fn list(&mut self) -> Result<(), NodeError> {
+        Ok(self.network.update_node_list()?)
     }
 
     /// Gets the current list
@@ -132,24 +302,136 @@ impl Node {
     }
 
     /// Pings all known nodes
-    pub async fn ping(&mut self, msg: &str) -> Result<(), String> {
-        self.logic
-            .input_tx
-            .send(LInput::PingAll(msg.to_string()))
-            .map_err(|e| e.to_string())
+    pub async fn ping(&mut self, msg: &str) -> Result<(), NodeError> {
+        Ok(self.logic.input_tx.send(LInput::PingAll(msg.to_string()))?)
+    }
+
+    /// Returns the round-trip times between the known nodes, as measured by the
+    /// pings of this node and reported by the pongs of the peers.
+    pub fn latency_matrix(&self) -> LatencyMatrix {
+        self.logic.latency_matrix()
     }
 
     /// Sends a message over webrtc to a node. The node must already be connected
     /// through websocket to the signalling server. If the connection is not set up
     /// yet, the network stack will set up a connection with the remote node.
-    pub fn send(&mut self, dst: &U256, msg: String) -> Result<(), String> {
-        self.network
+    pub fn send(&mut self, dst: &U256, msg: String) -> Result<(), NodeError> {
+        Ok(self
+            .network
             .input_tx
-            .send(NInput::WebRTC(dst.clone(), msg))
-            .map_err(|e| e.to_string())
+            .send(NInput::WebRTC(dst.clone(), msg))?)
+    }
+
+    /// Returns the events recorded while setting up the connections to the
+    /// given node. Use `ConnectionTimeline::to_json` to export it.
+    pub fn timeline(&self, id: &U256) -> Option<ConnectionTimeline> {
+        self.network.timeline(id)
+    }
+
+    /// Returns the network of the stored config, to connect to one of its
+    /// signal servers before creating the node.
+    pub fn ledger(storage: Box<dyn DataStorage>) -> Result<Ledger, NodeError> {
+        Ok(NodeConfig::ledger_of(&storage.load(CONFIG_NAME)?)?)
+    }
+
+    /// Replaces the stored config, keeping the current one as a backup.
+    pub fn set_config(storage: Box<dyn DataStorage>, config: &str) -> Result<(), NodeError> {
+        Node::backup_config(storage.as_ref(), config)?;
+        Ok(storage.save(CONFIG_NAME, config)?)
+    }
+
+    /// Returns the recovery words of the secret key of this node.
+    pub fn mnemonic(&self) -> String {
+        self.config.mnemonic()
     }
 
-    pub fn set_config(storage: Box<dyn DataStorage>, config: &str) -> Result<(), String> {
-        storage.save(CONFIG_NAME, config)
+    /// Returns the config of this node with the secret key encrypted by the
+    /// passphrase, to be downloaded as a backup.
+    pub fn export(&self, passphrase: &str) -> Result<String, NodeError> {
+        Ok(self.config.export(passphrase)?)
+    }
+
+    /// Stores a config exported by `export`, after checking that the
+    /// passphrase unlocks it. The current config is kept as a backup.
+    /// The node needs to be restarted to use it.
+    pub fn import(
+        storage: Box<dyn DataStorage>,
+        config: &str,
+        passphrase: &str,
+    ) -> Result<(), NodeError> {
+        NodeConfig::unlock(config.to_string(), Some(passphrase))?;
+        Node::set_config(storage, config)
+    }
+
+    /// Stores a new config with the identity of the recovery words, replacing
+    /// the current one, which is kept as a backup. The other settings of the
+    /// current config are kept. If a passphrase is given, the secret key is
+    /// encrypted.
+    /// The node needs to be restarted to use it.
+    pub fn import_mnemonic(
+        storage: Box<dyn DataStorage>,
+        words: &str,
+        passphrase: Option<&str>,
+    ) -> Result<(), NodeError> {
+        let current = storage.get(CONFIG_NAME)?.unwrap_or_default();
+        let config = NodeConfig::from_mnemonic(&current, words, passphrase)?;
+        Node::set_config(storage, &config.to_string()?)
+    }
+
+    /// Keeps the stored config, if any, as a new backup before it is replaced
+    /// by a different one.
+    fn backup_config(storage: &dyn DataStorage, new: &str) -> Result<(), NodeError> {
+        match storage.get(CONFIG_NAME)? {
+            Some(old) if old != new => save_backup(storage, &old, now()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Encrypts the secret key in the stored config with a new passphrase.
+    /// If `new` is None, the secret key is stored in plain text.
+    pub fn change_passphrase(
+        storage: Box<dyn DataStorage>,
+        old: Option<&str>,
+        new: Option<&str>,
+    ) -> Result<(), NodeError> {
+        let mut config = NodeConfig::unlock(storage.load(CONFIG_NAME)?, old)?;
+        config.set_passphrase(new)?;
+        Ok(storage.save(CONFIG_NAME, &config.to_string()?)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::storage::MemoryStorage;
+
+    #[test]
+    fn backups_are_kept() -> Result<(), NodeError> {
+        let storage = MemoryStorage::default();
+        let backups = || storage.keys(CONFIG_BACKUP_NAME).map(|keys| keys.len());
+        let first = NodeConfig::new("".to_string())?.to_string()?;
+        Node::set_config(storage.box_clone(), &first)?;
+        Node::set_config(storage.box_clone(), &first)?;
+        assert_eq!(0, backups()?);
+
+        let second = NodeConfig::new("".to_string())?.to_string()?;
+        Node::set_config(storage.box_clone(), &second)?;
+        assert_eq!(1, backups()?);
+        Node::import_mnemonic(
+            storage.box_clone(),
+            &NodeConfig::new("".to_string())?.mnemonic(),
+            None,
+        )?;
+        let keys = storage.keys(CONFIG_BACKUP_NAME)?;
+        assert_eq!(2, keys.len());
+        let mut saved: Vec<String> = keys
+            .iter()
+            .map(|key| storage.get(key).unwrap().unwrap())
+            .collect();
+        saved.sort();
+        let mut expected = vec![first, second];
+        expected.sort();
+        assert_eq!(expected, saved);
+        Ok(())
     }
 }
@@ -1,68 +1,818 @@
-use super::types::U256;
+use self::{
+    migrate::{migrate, CONFIG_VERSION},
+    secret::{EncryptedSecret, SecretError},
+};
+use super::{
+    network::transport::TransportKind,
+    types::{now, U256},
+};
+use crate::signal::web_rtc::IceServer;
+use bip39::Mnemonic;
+use ed25519_dalek::{Signature, SignatureError, Signer, SigningKey, Verifier, VerifyingKey};
+use names::{ADJECTIVES, NOUNS};
+use rand::random;
 use serde_derive::{Deserialize, Serialize};
+use std::convert::TryInto;
+use thiserror::Error;
+use toml::Value;
+use zeroize::Zeroizing;
 
-// TODO: add public key and an optional private key
+pub mod migrate;
+pub mod secret;
+
+/// The version of the software running the node.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The version of the messages between the nodes. Two nodes can only talk to
+/// each other if they share at least one protocol version.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The version of the encoding of the NodeInfo signed by this node, see
+/// `NodeInfo::signed_bytes`.
+pub const SIGN_VERSION: u32 = 1;
+
+/// How far in the future the timestamp of a NodeInfo may be, to allow for
+/// clocks that are not in sync.
+pub const MAX_CLOCK_SKEW_MS: u64 = 5 * 60 * 1000;
+
+/// How old the NodeInfo of an announcement may be. Nodes sign it again for
+/// every announcement, so an older one is replayed.
+pub const MAX_ANNOUNCE_AGE_MS: u64 = 5 * 60 * 1000;
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("couldn't parse config: {0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("couldn't serialize config: {0}")]
+    Serialize(#[from] toml::ser::Error),
+    #[error("invalid signature: {0}")]
+    Signature(#[from] SignatureError),
+    #[error("the secret key is encrypted and needs a passphrase")]
+    Locked,
+    #[error(transparent)]
+    Secret(#[from] SecretError),
+    #[error("invalid recovery words: {0}")]
+    Mnemonic(#[from] bip39::Error),
+    #[error("need 24 recovery words, got {0}")]
+    MnemonicLength(usize),
+    #[error("unsupported config version {0}, this node writes version {max}", max = CONFIG_VERSION)]
+    Version(String),
+    #[error("unknown network {0}, use a [ledger] table to describe it")]
+    Network(String),
+    #[error("an alias needs 1 to {max} printable chars, got {0:?}", max = MAX_ALIAS_LEN)]
+    Alias(String),
+    #[error("unknown signature version {0}, this node signs version {max}", max = SIGN_VERSION)]
+    SignVersion(u32),
+    #[error("timestamp {0} is too far in the future or too old")]
+    Timestamp(u64),
+}
+
+/// The longest alias a node can choose, in chars.
+pub const MAX_ALIAS_LEN: usize = 32;
+
+/// Where the node is running.
+#[derive(Debug, Default, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum NodeType {
+    /// Nodes from before the type was advertised.
+    #[default]
+    Unknown,
+    Browser,
+    /// Nodes running outside of a browser, which are usually online for a
+    /// longer time.
+    Server,
+}
+
+impl NodeType {
+    /// Returns the type of the running node. Wasm nodes without a window
+    /// run in node.js, so they're servers, too.
+    pub fn current() -> NodeType {
+        #[cfg(target_arch = "wasm32")]
+        return match web_sys::window() {
+            Some(_) => NodeType::Browser,
+            None => NodeType::Server,
+        };
+
+        #[cfg(not(target_arch = "wasm32"))]
+        NodeType::Server
+    }
+}
+
+/// The information a node announces to the signal server and the other nodes.
+/// All fields added after the first version have a default, so that old
+/// configurations and nodes can still be read.
+/// New fields must also be added to the signed encoding with a new
+/// SIGN_VERSION, else the signatures of older nodes don't verify anymore.
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub struct NodeInfo {
+    /// The ed25519 public key of the node, which verifies the signature.
     pub public: U256,
+    /// The name of the node, as returned by `name`. Older nodes used a random
+    /// name, so it is only kept for them. Migrated configs don't have it, as
+    /// it is derived again when they are loaded.
+    #[serde(default)]
     pub info: String,
-    pub ip: String,
-    pub webrtc_address: String,
+    /// A name chosen by the user. It is signed like the rest of the
+    /// information, but other nodes can choose the same alias.
+    #[serde(default)]
+    pub alias: Option<String>,
+    #[serde(default)]
+    pub version: String,
+    #[serde(default)]
+    pub node_type: NodeType,
+    /// The ID of the network of the node, see `Ledger`.
+    #[serde(default = "default_network")]
+    pub network: U256,
+    /// The protocol versions this node understands.
+    #[serde(default = "default_protocols")]
+    pub protocols: Vec<u32>,
+    /// The transports this node can use, in order of preference. Nodes that
+    /// don't advertise any only know WebRTC.
+    #[serde(default = "default_transports")]
+    pub transports: Vec<TransportKind>,
+    /// The addresses other nodes can connect to directly, prefixed with the
+    /// scheme of the transport, e.g. `tcp://192.0.2.1:7070`.
+    #[serde(default)]
+    pub addresses: Vec<String>,
+    /// When the node signed this information, in milliseconds since the
+    /// UNIX epoch.
+    #[serde(default)]
+    pub timestamp: u64,
+    /// The version of the encoding that has been signed. Information
+    /// without a version has not been signed.
+    #[serde(default)]
+    pub sign_version: u32,
+    #[serde(default)]
+    pub signature: Vec<u8>,
 }
 
 impl NodeInfo {
-    /// Creates a new NodeInfo with a random public key.
-    /// TODO: actually implement something useful.
-    pub fn new() -> NodeInfo {
+    /// Creates a new, unsigned NodeInfo for the running software.
+    pub fn new(public: U256) -> NodeInfo {
         NodeInfo {
-            public: U256::rnd(),
-            info: names::Generator::default().next().unwrap().to_string(),
-            ip: "127".to_string(),
-            webrtc_address: "something".to_string(),
+            info: name_of(&public),
+            alias: None,
+            public,
+            version: VERSION.to_string(),
+            node_type: NodeType::current(),
+            network: default_network(),
+            protocols: default_protocols(),
+            transports: default_transports(),
+            addresses: vec![],
+            timestamp: 0,
+            sign_version: 0,
+            signature: vec![],
+        }
+    }
+
+    /// Returns the name derived from the public key, like `rusty-nail-3fa1`:
+    /// an adjective and a noun chosen by the hash of the key, followed by
+    /// the first hex chars of the key. Other nodes cannot use this name,
+    /// as it depends on the key that signs the information.
+    pub fn name(&self) -> String {
+        name_of(&self.public)
+    }
+
+    /// Returns the alias followed by the name in parentheses, or only the
+    /// name if the node has no alias.
+    pub fn label(&self) -> String {
+        match &self.alias {
+            Some(alias) => format!("{} ({})", alias, self.name()),
+            None => self.name(),
+        }
+    }
+
+    /// Returns the address for the given transport, without the scheme.
+    pub fn address(&self, kind: TransportKind) -> Option<&str> {
+        let prefix = format!("{}://", kind.scheme());
+        self.addresses
+            .iter()
+            .find_map(|addr| addr.strip_prefix(prefix.as_str()))
+    }
+
+    /// Returns true if both nodes are in the same network and share a
+    /// protocol version.
+    pub fn compatible(&self, other: &NodeInfo) -> bool {
+        self.network == other.network && self.protocols.iter().any(|p| other.protocols.contains(p))
+    }
+
+    /// Updates the timestamp and signs the information with the key of the node.
+    pub fn sign(&mut self, key: &SigningKey) {
+        self.timestamp = now() as u64;
+        self.sign_version = SIGN_VERSION;
+        self.signature = key.sign(&self.canonical_bytes()).to_bytes().to_vec();
+    }
+
+    /// Checks that the information has been signed by the key in `public`,
+    /// that it hasn't been signed in the future, and that the alias is valid.
+    pub fn verify(&self) -> Result<(), ConfigError> {
+        if let Some(alias) = &self.alias {
+            check_alias(alias)?;
+        }
+        if self.timestamp > now() as u64 + MAX_CLOCK_SKEW_MS {
+            return Err(ConfigError::Timestamp(self.timestamp));
+        }
+        let bytes = self.signed_bytes()?;
+        let key = VerifyingKey::from_bytes(&self.public.to_bytes())?;
+        let signature = Signature::from_slice(&self.signature)?;
+        Ok(key.verify(&bytes, &signature)?)
+    }
+
+    /// Like `verify`, but also checks that the information has been signed in
+    /// the last MAX_ANNOUNCE_AGE_MS, as it is done for every announcement.
+    pub fn verify_fresh(&self) -> Result<(), ConfigError> {
+        self.verify()?;
+        if self.timestamp + MAX_ANNOUNCE_AGE_MS < now() as u64 {
+            return Err(ConfigError::Timestamp(self.timestamp));
+        }
+        Ok(())
+    }
+
+    // The bytes covered by the signature, in the encoding given by
+    // `sign_version`.
+    fn signed_bytes(&self) -> Result<Vec<u8>, ConfigError> {
+        match self.sign_version {
+            SIGN_VERSION => Ok(self.canonical_bytes()),
+            v => Err(ConfigError::SignVersion(v)),
+        }
+    }
+
+    // Version 1: a tag, then every field in a fixed order, with the length of
+    // strings, bytes and lists as a 4-byte big-endian number.
+    fn canonical_bytes(&self) -> Vec<u8> {
+        let mut enc = Encoder::default();
+        enc.bytes(b"fledger-node-info");
+        enc.u32(1);
+        enc.bytes(&self.public.to_bytes());
+        enc.bytes(self.info.as_bytes());
+        match &self.alias {
+            Some(alias) => {
+                enc.u32(1);
+                enc.bytes(alias.as_bytes());
+            }
+            None => enc.u32(0),
         }
+        enc.bytes(self.version.as_bytes());
+        enc.u32(match self.node_type {
+            NodeType::Unknown => 0,
+            NodeType::Browser => 1,
+            NodeType::Server => 2,
+        });
+        enc.bytes(&self.network.to_bytes());
+        enc.u32(self.protocols.len() as u32);
+        for p in &self.protocols {
+            enc.u32(*p);
+        }
+        enc.u32(self.transports.len() as u32);
+        for t in &self.transports {
+            enc.bytes(t.scheme().as_bytes());
+        }
+        enc.u32(self.addresses.len() as u32);
+        for a in &self.addresses {
+            enc.bytes(a.as_bytes());
+        }
+        enc.0.extend_from_slice(&self.timestamp.to_be_bytes());
+        enc.0
+    }
+}
+
+#[derive(Default)]
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    fn u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.0.extend_from_slice(bytes);
     }
 }
 
-// TODO: handle discovering the ledger and remove the root NodeInfo
-#[derive(Debug, Deserialize)]
+fn name_of(public: &U256) -> String {
+    let hash = U256::from_blake3(&public.to_bytes()).to_bytes();
+    let index = |bytes: &[u8], len: usize| {
+        let value = bytes.iter().fold(0u64, |v, b| v << 8 | *b as u64);
+        (value % len as u64) as usize
+    };
+    format!(
+        "{}-{}-{}",
+        ADJECTIVES[index(&hash[0..8], ADJECTIVES.len())],
+        NOUNS[index(&hash[8..16], NOUNS.len())],
+        &public.to_string()[0..4]
+    )
+}
+
+fn check_alias(alias: &str) -> Result<(), ConfigError> {
+    let len = alias.chars().count();
+    if len == 0 || len > MAX_ALIAS_LEN || alias.chars().any(char::is_control) {
+        return Err(ConfigError::Alias(alias.to_string()));
+    }
+    Ok(())
+}
+
+fn default_protocols() -> Vec<u32> {
+    vec![PROTOCOL_VERSION]
+}
+
+fn default_transports() -> Vec<TransportKind> {
+    vec![TransportKind::WebRTC]
+}
+
+fn default_network() -> U256 {
+    Ledger::default().genesis
+}
+
+/// The network the node takes part in. Nodes only talk to nodes of the same
+/// network, which is identified by the hash of its genesis.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub struct Ledger {
-    name: String,
-    root: NodeInfo,
+    pub name: String,
+    pub genesis: U256,
+    /// The signal servers of the network, in order of preference.
+    pub signal_urls: Vec<String>,
+    /// Nodes contacted at startup, together with the peers of previous runs.
+    #[serde(default)]
+    pub bootstrap: Vec<NodeInfo>,
+}
+
+impl Ledger {
+    /// Returns one of the predefined networks: `mainnet`, `testnet`, or
+    /// `local` for development with a signal server on this machine.
+    /// The networks can share a signal server, as it only lists the nodes of
+    /// the same network.
+    pub fn named(name: &str) -> Option<Ledger> {
+        let url = match name {
+            "mainnet" | "testnet" => "wss://signal.fledg.re",
+            "local" => "ws://localhost:8765",
+            _ => return None,
+        };
+        Some(Ledger {
+            name: name.to_string(),
+            // As long as there is no genesis block, the predefined networks
+            // use the hash of their name.
+            genesis: U256::from_sha256(name.as_bytes()),
+            signal_urls: vec![url.to_string()],
+            bootstrap: vec![],
+        })
+    }
+
+    /// The ID of the network, which all its nodes announce.
+    pub fn id(&self) -> &U256 {
+        &self.genesis
+    }
+
+    /// Chooses the network of a config: a `[ledger]` table describes the
+    /// network completely, while `network` only names a predefined one.
+    fn from_toml(ledger: Option<Ledger>, network: Option<String>) -> Result<Ledger, ConfigError> {
+        match (ledger, network) {
+            (Some(ledger), _) => Ok(ledger),
+            (None, Some(name)) => Ledger::named(&name).ok_or(ConfigError::Network(name)),
+            (None, None) => Ok(Ledger::default()),
+        }
+    }
+}
+
+impl Default for Ledger {
+    fn default() -> Ledger {
+        Ledger::named("mainnet").expect("mainnet is predefined")
+    }
 }
 
 #[derive(Debug)]
 pub struct NodeConfig {
     pub our_node: NodeInfo,
-    // pub ledger: Ledger,
+    /// The key signing the NodeInfo of this node.
+    pub secret: SigningKey,
+    /// STUN and TURN servers used for all connections. The signal server can
+    /// add more servers.
+    pub ice_servers: Vec<IceServer>,
+    /// If WebRTC cannot connect to a node, send the messages through the
+    /// signal server.
+    pub relay_fallback: bool,
+    // The secret as it is stored, if it is protected by a passphrase.
+    encrypted: Option<EncryptedSecret>,
+    // The version of the parsed config, if it had to be migrated.
+    migrated_from: Option<u32>,
+    /// The network of the node.
+    pub ledger: Ledger,
 }
 
 impl NodeConfig {
-    /// Parses the string as a config for the node. If the ledger is not available, it returns an error.
+    /// Parses the string as a config for the node. Without a ledger, the
+    /// node joins the mainnet.
     /// If the our_node is missing, it is created.
-    pub fn new(str: String) -> Result<NodeConfig, String> {
-        let t: Toml = if str.len() > 0 {
-            toml::from_str(str.as_str()).map_err(|e| e.to_string())?
+    /// Configs of older versions are migrated to the current version.
+    /// Configs from before the nodes had keys get a new identity, as their
+    /// random public ID cannot sign anything.
+    pub fn new(str: String) -> Result<NodeConfig, ConfigError> {
+        NodeConfig::unlock(str, None)
+    }
+
+    /// Parses the config like `new`, and decrypts the secret key with the
+    /// passphrase. If the secret key is encrypted and no passphrase is given,
+    /// `ConfigError::Locked` is returned.
+    /// A config with a plain secret key gets encrypted with the passphrase.
+    pub fn unlock(str: String, passphrase: Option<&str>) -> Result<NodeConfig, ConfigError> {
+        let (t, version) = NodeConfig::parse(&str)?;
+        NodeConfig::from_parsed(t, version, passphrase)
+    }
+
+    // Migrates the config and parses it, or returns the defaults if it is
+    // empty.
+    fn parse(str: &str) -> Result<(Toml, u32), ConfigError> {
+        Ok(if str.len() > 0 {
+            let (config, version) = migrate(str)?;
+            (Value::Table(config).try_into()?, version)
         } else {
-            Toml { our_node: None }
+            let t = Toml {
+                version: CONFIG_VERSION,
+                our_node: None,
+                secret: None,
+                encrypted_secret: None,
+                ice_servers: default_ice_servers(),
+                relay_fallback: true,
+                network: None,
+                ledger: None,
+            };
+            (t, CONFIG_VERSION)
+        })
+    }
+
+    fn from_parsed(
+        t: Toml,
+        version: u32,
+        passphrase: Option<&str>,
+    ) -> Result<NodeConfig, ConfigError> {
+        let stored = match (&t.encrypted_secret, passphrase) {
+            (Some(enc), Some(passphrase)) => Some(Zeroizing::new(enc.decrypt(passphrase)?)),
+            (Some(_), None) => return Err(ConfigError::Locked),
+            (None, _) => t.secret.map(|s| Zeroizing::new(s.to_bytes())),
+        };
+        // a new identity is created if either part is missing
+        let fresh = t.our_node.is_none() || stored.is_none();
+        let (mut our_node, secret) = match (t.our_node, stored) {
+            (Some(node), Some(secret)) => (node, SigningKey::from_bytes(&secret)),
+            (node, _) => {
+                let secret = SigningKey::from_bytes(&random());
+                let mut info = NodeInfo::new(secret.verifying_key().to_bytes().into());
+                if let Some(old) = node {
+                    info.alias = old.alias;
+                }
+                (info, secret)
+            }
         };
+        let ledger = Ledger::from_toml(t.ledger, t.network)?;
+        if let Some(alias) = &our_node.alias {
+            check_alias(alias)?;
+        }
+        // The software might have been updated since the config was written.
+        our_node.info = our_node.name();
+        our_node.version = VERSION.to_string();
+        our_node.node_type = NodeType::current();
+        our_node.network = ledger.id().clone();
+        our_node.protocols = default_protocols();
 
-        Ok(NodeConfig {
-            our_node: t.our_node.unwrap_or(NodeInfo::new()),
-            // ledger: t.ledger,
-        })
+        let mut config = NodeConfig {
+            our_node,
+            secret,
+            ice_servers: t.ice_servers,
+            relay_fallback: t.relay_fallback,
+            encrypted: t.encrypted_secret,
+            migrated_from: Some(version).filter(|v| *v < CONFIG_VERSION),
+            ledger,
+        };
+        if passphrase.is_some() && (fresh || config.encrypted.is_none()) {
+            config.set_passphrase(passphrase)?;
+        }
+        Ok(config)
+    }
+
+    /// Sets the alias of the node, or removes it.
+    pub fn set_alias(&mut self, alias: Option<&str>) -> Result<(), ConfigError> {
+        if let Some(alias) = alias {
+            check_alias(alias)?;
+        }
+        self.our_node.alias = alias.map(|a| a.to_string());
+        Ok(())
+    }
+
+    /// Returns the network of the config without unlocking the secret key,
+    /// so the node can connect to its signal server before it is created.
+    pub fn ledger_of(str: &str) -> Result<Ledger, ConfigError> {
+        if str.is_empty() {
+            return Ok(Ledger::default());
+        }
+        let (config, _) = migrate(str)?;
+        let t: Toml = Value::Table(config).try_into()?;
+        Ledger::from_toml(t.ledger, t.network)
+    }
+
+    /// Encrypts the secret key with the new passphrase, or stores it in plain
+    /// text if the passphrase is None.
+    pub fn set_passphrase(&mut self, passphrase: Option<&str>) -> Result<(), ConfigError> {
+        self.encrypted = match passphrase {
+            Some(p) => Some(EncryptedSecret::encrypt(&self.secret.to_bytes(), p)?),
+            None => None,
+        };
+        Ok(())
+    }
+
+    /// Returns the version of the parsed config if it was older than the
+    /// current version. Its original should be kept as a backup before
+    /// writing the migrated config.
+    pub fn migrated_from(&self) -> Option<u32> {
+        self.migrated_from
+    }
+
+    /// Returns true if the secret key is protected by a passphrase.
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypted.is_some()
+    }
+
+    /// Replaces the identity of the current config with the one stored in
+    /// the recovery words. The network, the ICE servers, the relay setting
+    /// and the alias of the current config are kept. The current config
+    /// doesn't need to be unlocked, as its secret key is replaced.
+    pub fn from_mnemonic(
+        current: &str,
+        words: &str,
+        passphrase: Option<&str>,
+    ) -> Result<NodeConfig, ConfigError> {
+        let mnemonic = Mnemonic::parse(words)?;
+        let entropy = Zeroizing::new(mnemonic.to_entropy());
+        let secret: [u8; 32] = entropy
+            .as_slice()
+            .try_into()
+            .map_err(|_| ConfigError::MnemonicLength(mnemonic.word_count()))?;
+        let (mut t, version) = NodeConfig::parse(current)?;
+        let public = SigningKey::from_bytes(&secret).verifying_key().to_bytes();
+        let mut our_node = NodeInfo::new(public.into());
+        our_node.alias = t.our_node.and_then(|node| node.alias);
+        t.our_node = Some(our_node);
+        t.secret = Some(secret.into());
+        t.encrypted_secret = None;
+        NodeConfig::from_parsed(t, version, passphrase)
+    }
+
+    /// Returns the 24 recovery words of the secret key. Together with
+    /// `from_mnemonic`, it allows to move the node to another device.
+    pub fn mnemonic(&self) -> String {
+        Mnemonic::from_entropy(&self.secret.to_bytes())
+            .map(|m| m.to_string())
+            .unwrap_or_default()
+    }
+
+    /// Returns the config with the secret key encrypted with the passphrase,
+    /// to be stored as a backup. It can be restored with `unlock`.
+    pub fn export(&self, passphrase: &str) -> Result<String, ConfigError> {
+        self.to_toml(Some(EncryptedSecret::encrypt(
+            &self.secret.to_bytes(),
+            passphrase,
+        )?))
     }
 
-    pub fn to_string(&self) -> Result<String, String> {
-        toml::to_string(&Toml {
+    /// Only the encrypted secret key is written if a passphrase is set.
+    pub fn to_string(&self) -> Result<String, ConfigError> {
+        self.to_toml(self.encrypted.clone())
+    }
+
+    fn to_toml(&self, encrypted: Option<EncryptedSecret>) -> Result<String, ConfigError> {
+        Ok(toml::to_string(&Toml {
+            version: CONFIG_VERSION,
             our_node: Some(self.our_node.clone()),
-        })
-        .map_err(|e| e.to_string())
+            secret: match encrypted {
+                Some(_) => None,
+                None => Some(self.secret.to_bytes().into()),
+            },
+            encrypted_secret: encrypted,
+            ice_servers: self.ice_servers.clone(),
+            relay_fallback: self.relay_fallback,
+            network: Some(self.ledger.name.clone()),
+            // predefined networks are only written by name
+            ledger: Some(self.ledger.clone())
+                .filter(|ledger| Ledger::named(&ledger.name).as_ref() != Some(ledger)),
+        })?)
     }
 }
 
-// TODO: find good name, add private key
+/// Without a STUN server, nodes behind a NAT can only connect to nodes in
+/// the same network.
+fn default_ice_servers() -> Vec<IceServer> {
+    vec![]
+}
+
+fn default_relay_fallback() -> bool {
+    true
+}
+
+// TODO: find good name
 #[derive(Debug, Deserialize, Serialize)]
 struct Toml {
+    // plain values must come before the tables in TOML.
+    version: u32,
+    #[serde(default = "default_relay_fallback")]
+    relay_fallback: bool,
+    /// The name of a predefined network, if there is no ledger.
+    network: Option<String>,
+    secret: Option<U256>,
     our_node: Option<NodeInfo>,
-    // ledger: Ledger,
+    encrypted_secret: Option<EncryptedSecret>,
+    // an empty list would be a plain value after the tables
+    #[serde(default = "default_ice_servers", skip_serializing_if = "Vec::is_empty")]
+    ice_servers: Vec<IceServer>,
+    ledger: Option<Ledger>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn old_config() -> Result<(), ConfigError> {
+        let old = r#"
+            [our_node]
+            public = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16,
+                      17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32]
+            info = "old-node"
+            ip = "127"
+            webrtc_address = "something"
+        "#;
+        let config = NodeConfig::new(old.to_string())?;
+        assert_eq!(None, config.our_node.alias);
+        assert_eq!(config.our_node.name(), config.our_node.info);
+        assert_eq!(
+            U256::from(config.secret.verifying_key().to_bytes()),
+            config.our_node.public
+        );
+        assert_eq!(vec![PROTOCOL_VERSION], config.our_node.protocols);
+        assert_eq!(Some(0), config.migrated_from());
+
+        let again = NodeConfig::new(config.to_string()?)?;
+        assert_eq!(None, again.migrated_from());
+        assert_eq!(config.our_node, again.our_node);
+        assert_eq!(config.secret, again.secret);
+        Ok(())
+    }
+
+    #[test]
+    fn passphrase() -> Result<(), ConfigError> {
+        let config = NodeConfig::unlock("".to_string(), Some("first"))?;
+        assert!(config.is_encrypted());
+        let stored = config.to_string()?;
+        assert!(matches!(
+            NodeConfig::new(stored.clone()),
+            Err(ConfigError::Locked)
+        ));
+        assert!(matches!(
+            NodeConfig::unlock(stored.clone(), Some("second")),
+            Err(ConfigError::Secret(SecretError::Decrypt))
+        ));
+
+        let mut config = NodeConfig::unlock(stored, Some("first"))?;
+        config.set_passphrase(Some("second"))?;
+        let again = NodeConfig::unlock(config.to_string()?, Some("second"))?;
+        assert_eq!(config.secret, again.secret);
+        assert_eq!(config.our_node.public, again.our_node.public);
+        Ok(())
+    }
+
+    #[test]
+    fn export_import() -> Result<(), ConfigError> {
+        let config = NodeConfig::new("".to_string())?;
+        let words = config.mnemonic();
+        assert_eq!(24, words.split_whitespace().count());
+        let restored = NodeConfig::from_mnemonic("", &words, None)?;
+        assert_eq!(config.our_node.public, restored.our_node.public);
+        assert!(NodeConfig::from_mnemonic("", "fledger fledger fledger", None).is_err());
+
+        let file = config.export("backup")?;
+        assert!(!config.is_encrypted());
+        let restored = NodeConfig::unlock(file, Some("backup"))?;
+        assert_eq!(config.our_node, restored.our_node);
+        Ok(())
+    }
+
+    #[test]
+    fn networks() -> Result<(), ConfigError> {
+        let mainnet = NodeConfig::new("".to_string())?;
+        assert_eq!(Ledger::default(), mainnet.ledger);
+        assert_eq!(mainnet.ledger.id(), &mainnet.our_node.network);
+
+        let stored = NodeConfig::new(mainnet.to_string()?)?.to_string()?;
+        let testnet = NodeConfig::new(stored.replace("mainnet", "testnet"))?;
+        assert_eq!("testnet", testnet.ledger.name);
+        assert!(!testnet.to_string()?.contains("[ledger]"));
+        assert!(!mainnet.our_node.compatible(&testnet.our_node));
+        assert_eq!(
+            testnet.ledger,
+            NodeConfig::ledger_of(&testnet.to_string()?)?
+        );
+
+        let mut custom = testnet;
+        custom.ledger.name = "private".to_string();
+        custom.ledger.signal_urls = vec!["ws://192.0.2.1:8765".to_string()];
+        custom.ledger.bootstrap.push(mainnet.our_node.clone());
+        let again = NodeConfig::new(custom.to_string()?)?;
+        assert_eq!(custom.ledger, again.ledger);
+        assert_eq!(again.ledger.id(), &again.our_node.network);
+
+        assert!(matches!(
+            NodeConfig::new(stored.replace("mainnet", "nowhere")),
+            Err(ConfigError::Network(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn names() -> Result<(), ConfigError> {
+        let mut config = NodeConfig::new("".to_string())?;
+        let info = &config.our_node;
+        assert_eq!(info.name(), NodeInfo::new(info.public.clone()).name());
+        assert!(info.name().ends_with(&info.public.to_string()[0..4]));
+        assert!(ADJECTIVES
+            .iter()
+            .any(|a| info.name().starts_with(&format!("{}-", a))));
+        assert_ne!(info.name(), NodeInfo::new(U256::rnd()).name());
+        assert_eq!(info.name(), info.label());
+
+        assert!(config.set_alias(Some("")).is_err());
+        assert!(config
+            .set_alias(Some(&"x".repeat(MAX_ALIAS_LEN + 1)))
+            .is_err());
+        config.set_alias(Some("kitchen"))?;
+        let mut info = NodeConfig::new(config.to_string()?)?.our_node;
+        assert_eq!(format!("kitchen ({})", info.name()), info.label());
+        info.sign(&config.secret);
+        info.verify()?;
+        info.alias = Some("bathroom".to_string());
+        assert!(info.verify().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn sign_verify() -> Result<(), ConfigError> {
+        let config = NodeConfig::new("".to_string())?;
+        let mut info = config.our_node.clone();
+        assert!(info.verify().is_err());
+        info.sign(&config.secret);
+        info.verify()?;
+        info.addresses.push("tcp://192.0.2.1:7070".to_string());
+        assert!(info.verify().is_err());
+
+        // Signed in the future, or long ago.
+        info.sign(&config.secret);
+        info.verify_fresh()?;
+        let sign_at = |info: &mut NodeInfo, timestamp: u64| {
+            info.timestamp = timestamp;
+            info.signature = config
+                .secret
+                .sign(&info.canonical_bytes())
+                .to_bytes()
+                .to_vec();
+        };
+        let signed = info.timestamp;
+        sign_at(&mut info, signed + 2 * MAX_CLOCK_SKEW_MS);
+        assert!(matches!(info.verify(), Err(ConfigError::Timestamp(_))));
+        sign_at(&mut info, signed - 2 * MAX_ANNOUNCE_AGE_MS);
+        info.verify()?;
+        assert!(matches!(
+            info.verify_fresh(),
+            Err(ConfigError::Timestamp(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn mnemonic_keeps_settings() -> Result<(), ConfigError> {
+        let mut current = NodeConfig::new("network = \"testnet\"".to_string())?;
+        current.set_alias(Some("kitchen"))?;
+        current.relay_fallback = false;
+        current.set_passphrase(Some("old"))?;
+        let words = NodeConfig::new("".to_string())?.mnemonic();
+
+        let imported = NodeConfig::from_mnemonic(&current.to_string()?, &words, None)?;
+        assert_eq!(current.ledger, imported.ledger);
+        assert_eq!(current.our_node.network, imported.our_node.network);
+        assert_eq!(current.ice_servers, imported.ice_servers);
+        assert!(!imported.relay_fallback);
+        assert_eq!(Some("kitchen".to_string()), imported.our_node.alias);
+        assert!(!imported.is_encrypted());
+        assert_ne!(current.our_node.public, imported.our_node.public);
+        Ok(())
+    }
+
+    #[test]
+    fn sign_versions() -> Result<(), ConfigError> {
+        let config = NodeConfig::new("".to_string())?;
+        let mut info = NodeInfo::new(config.our_node.public);
+        // Information without a version has not been signed.
+        assert!(matches!(info.verify(), Err(ConfigError::SignVersion(0))));
+        // A signature version this node doesn't know.
+        info.sign(&config.secret);
+        info.verify()?;
+        info.sign_version = SIGN_VERSION + 1;
+        assert!(matches!(info.verify(), Err(ConfigError::SignVersion(_))));
+        Ok(())
+    }
 }
@@ -0,0 +1,81 @@
+//! Upgrades configs written by older versions of the node. Every migration
+//! takes a config of one version and changes it to the format of the next one,
+//! so an old config passes through all following migrations in order.
+use toml::{value::Table, Value};
+
+use super::ConfigError;
+
+/// The version of the config format written by this node.
+pub const CONFIG_VERSION: u32 = 1;
+
+type Migration = fn(&mut Table) -> Result<(), ConfigError>;
+
+// MIGRATIONS[v] upgrades a config from version v to v + 1.
+const MIGRATIONS: [Migration; CONFIG_VERSION as usize] = [v0_first];
+
+/// Parses the config and migrates it to the current version. Returns the
+/// migrated config and the version it had before.
+/// Configs of a newer version are rejected, as writing them back would lose
+/// the fields this node doesn't know about.
+pub fn migrate(str: &str) -> Result<(Table, u32), ConfigError> {
+    let mut config: Table = toml::from_str(str)?;
+    let version = match config.get("version") {
+        Some(Value::Integer(v)) if *v >= 0 => *v as u32,
+        Some(v) => return Err(ConfigError::Version(v.to_string())),
+        None => 0,
+    };
+    if version > CONFIG_VERSION {
+        return Err(ConfigError::Version(version.to_string()));
+    }
+    for migration in MIGRATIONS[version as usize..].iter() {
+        migration(&mut config)?;
+    }
+    config.insert("version".to_string(), Value::Integer(CONFIG_VERSION.into()));
+    Ok((config, version))
+}
+
+/// The first configs had no version. Their node only had a random `info` as
+/// name, and `ip` and `webrtc_address` held placeholders, so these are
+/// dropped: the name is now derived from the public key, and an alias is
+/// chosen by the user. All these nodes were connected to the signal server of
+/// the mainnet, which is now written down unless the config names a network,
+/// so a changed default doesn't move the node to another network.
+fn v0_first(config: &mut Table) -> Result<(), ConfigError> {
+    if let Some(Value::Table(node)) = config.get_mut("our_node") {
+        node.remove("info");
+        node.remove("ip");
+        node.remove("webrtc_address");
+    }
+    if !config.contains_key("network") && !config.contains_key("ledger") {
+        config.insert("network".to_string(), Value::String("mainnet".to_string()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions() -> Result<(), ConfigError> {
+        let (config, version) = migrate("[our_node]\nip = \"127\"\ninfo = \"old\"")?;
+        assert_eq!(0, version);
+        assert_eq!(Some(&Value::Integer(CONFIG_VERSION.into())), config.get("version"));
+        let node = config.get("our_node").and_then(|n| n.as_table()).unwrap();
+        assert_eq!(None, node.get("ip"));
+        assert_eq!(None, node.get("info"));
+        assert_eq!(None, node.get("alias"));
+        assert_eq!(
+            Some(&Value::String("mainnet".to_string())),
+            config.get("network")
+        );
+
+        let (_, version) = migrate(&format!("version = {}", CONFIG_VERSION))?;
+        assert_eq!(CONFIG_VERSION, version);
+        assert!(matches!(
+            migrate(&format!("version = {}", CONFIG_VERSION + 1)),
+            Err(ConfigError::Version(_))
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,101 @@
+//! Encrypts the secret key of the node with a passphrase, so it is not stored
+//! in plain text. The passphrase is stretched with Argon2id, and the result
+//! is used as the key for ChaCha20-Poly1305.
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use rand::random;
+use serde_derive::{Deserialize, Serialize};
+use thiserror::Error;
+use std::convert::TryInto;
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::node::types::U256;
+
+#[derive(Error, Debug)]
+pub enum SecretError {
+    #[error("couldn't derive key from passphrase: {0}")]
+    Derive(String),
+    #[error("wrong passphrase or corrupted secret")]
+    Decrypt,
+    #[error("couldn't encrypt secret")]
+    Encrypt,
+}
+
+/// A secret key encrypted with a passphrase, as it is stored in the config.
+/// The Argon2id parameters are stored, too, so they can be raised later
+/// without breaking existing configs.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct EncryptedSecret {
+    /// Memory used by Argon2id, in KiB.
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+    pub salt: U256,
+    pub nonce: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+}
+
+impl EncryptedSecret {
+    /// Encrypts the secret with a new salt and nonce.
+    pub fn encrypt(secret: &[u8; 32], passphrase: &str) -> Result<EncryptedSecret, SecretError> {
+        let nonce: [u8; 12] = random();
+        let mut enc = EncryptedSecret {
+            m_cost: Params::DEFAULT_M_COST,
+            t_cost: Params::DEFAULT_T_COST,
+            p_cost: Params::DEFAULT_P_COST,
+            salt: U256::rnd(),
+            nonce: nonce.to_vec(),
+            ciphertext: vec![],
+        };
+        enc.ciphertext = enc
+            .cipher(passphrase)?
+            .encrypt(Nonce::from_slice(&nonce), &secret[..])
+            .map_err(|_| SecretError::Encrypt)?;
+        Ok(enc)
+    }
+
+    /// Returns the secret, or an error if the passphrase is wrong.
+    pub fn decrypt(&self, passphrase: &str) -> Result<[u8; 32], SecretError> {
+        if self.nonce.len() != 12 {
+            return Err(SecretError::Decrypt);
+        }
+        let plain = Zeroizing::new(
+            self.cipher(passphrase)?
+                .decrypt(Nonce::from_slice(&self.nonce), self.ciphertext.as_slice())
+                .map_err(|_| SecretError::Decrypt)?,
+        );
+        plain
+            .as_slice()
+            .try_into()
+            .map_err(|_| SecretError::Decrypt)
+    }
+
+    fn cipher(&self, passphrase: &str) -> Result<ChaCha20Poly1305, SecretError> {
+        let params = Params::new(self.m_cost, self.t_cost, self.p_cost, Some(32))
+            .map_err(|e| SecretError::Derive(e.to_string()))?;
+        let mut key = [0u8; 32];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), &self.salt.to_bytes(), &mut key)
+            .map_err(|e| SecretError::Derive(e.to_string()))?;
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&key));
+        key.zeroize();
+        Ok(cipher)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encrypt_decrypt() -> Result<(), SecretError> {
+        let secret: [u8; 32] = random();
+        let enc = EncryptedSecret::encrypt(&secret, "correct horse")?;
+        assert_eq!(secret, enc.decrypt("correct horse")?);
+        assert!(matches!(enc.decrypt("battery staple"), Err(SecretError::Decrypt)));
+        Ok(())
+    }
+}
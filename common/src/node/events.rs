@@ -0,0 +1,146 @@
+//! Channels that wake up the node whenever a message is sent.
+//! All modules of a node share the same `Wakeup`, so that a single future can
+//! wait for any new event, be it from the websocket, a WebRTC connection, or
+//! one of the internal modules.
+//! This works with `wasm_bindgen_futures::spawn_local` as well as with any
+//! native executor.
+use futures::{
+    channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+    task::{Context, Poll, Waker},
+    Future,
+};
+use std::{
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+use thiserror::Error;
+
+/// The receiving half of a channel has been dropped.
+#[derive(Error, Debug)]
+#[error("channel is closed")]
+pub struct ChannelError;
+
+#[derive(Default)]
+struct WakeupState {
+    pending: bool,
+    waker: Option<Waker>,
+}
+
+/// Signals new events to whoever is waiting for them.
+#[derive(Clone, Default)]
+pub struct Wakeup(Arc<Mutex<WakeupState>>);
+
+impl Wakeup {
+    pub fn new() -> Wakeup {
+        Wakeup::default()
+    }
+
+    /// Signals that a new event is available.
+    pub fn notify(&self) {
+        let mut state = self.0.lock().unwrap();
+        state.pending = true;
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// Returns true if an event happened since the last call to `clear`.
+    pub fn is_pending(&self) -> bool {
+        self.0.lock().unwrap().pending
+    }
+
+    /// Forgets about all events that happened up to now.
+    pub fn clear(&self) {
+        self.0.lock().unwrap().pending = false;
+    }
+
+    /// Returns a future that resolves once a new event is available.
+    /// If an event happened since the last `clear`, it resolves immediately.
+    pub fn wait(&self) -> WakeupFuture {
+        WakeupFuture(self.clone())
+    }
+}
+
+pub struct WakeupFuture(Wakeup);
+
+impl Future for WakeupFuture {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = (self.0).0.lock().unwrap();
+        if state.pending {
+            state.pending = false;
+            return Poll::Ready(());
+        }
+        state.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+/// The sending half of a channel. Every message sent wakes up the node.
+pub struct Sender<T> {
+    tx: UnboundedSender<T>,
+    wakeup: Wakeup,
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        Sender {
+            tx: self.tx.clone(),
+            wakeup: self.wakeup.clone(),
+        }
+    }
+}
+
+impl<T> Sender<T> {
+    /// Queues the message and wakes up the node.
+    pub fn send(&self, msg: T) -> Result<(), ChannelError> {
+        self.tx.unbounded_send(msg).map_err(|_| ChannelError)?;
+        self.wakeup.notify();
+        Ok(())
+    }
+}
+
+/// The receiving half of a channel.
+pub struct Receiver<T> {
+    rx: UnboundedReceiver<T>,
+}
+
+impl<T> Receiver<T> {
+    /// Returns all messages that are waiting, without blocking.
+    pub fn try_iter(&mut self) -> impl Iterator<Item = T> + '_ {
+        std::iter::from_fn(move || self.rx.try_recv().ok())
+    }
+}
+
+/// Creates a new channel whose messages wake up the given Wakeup.
+pub fn channel<T>(wakeup: &Wakeup) -> (Sender<T>, Receiver<T>) {
+    let (tx, rx) = unbounded();
+    (
+        Sender {
+            tx,
+            wakeup: wakeup.clone(),
+        },
+        Receiver { rx },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use std::thread;
+
+    #[test]
+    fn wait_for_message() {
+        let wakeup = Wakeup::new();
+        let (tx, mut rx) = channel::<u32>(&wakeup);
+        assert!(!wakeup.is_pending());
+
+        let handle = thread::spawn(move || tx.send(42).unwrap());
+        block_on(wakeup.wait());
+        handle.join().unwrap();
+        assert_eq!(vec![42], rx.try_iter().collect::<Vec<u32>>());
+        assert!(!wakeup.is_pending());
+    }
+}
@@ -1,12 +1,145 @@
+use thiserror::Error;
+
+use super::{
+    logging::{enabled, ContextLogger, Level, Record},
+    storage::{from_hex, to_hex, Namespace, StorageOp},
+};
+
+#[derive(Error, Debug)]
+pub enum StorageError {
+    #[error("storage is not available: {0}")]
+    Unavailable(String),
+    #[error("couldn't load: {0}")]
+    Load(String),
+    #[error("couldn't save: {0}")]
+    Save(String),
+    #[error("couldn't remove: {0}")]
+    Remove(String),
+    #[error("couldn't list keys: {0}")]
+    List(String),
+}
+
+/// A key/value store for strings. Implementations only need the first five
+/// methods, everything else is built on top of them, but can be overwritten
+/// if the storage has a better way to do it.
 pub trait DataStorage {
-    fn load(&self, key: &str) -> Result<String, String>;
+    /// Returns the value of the key, or None if it doesn't exist.
+    fn get(&self, key: &str) -> Result<Option<String>, StorageError>;
+
+    fn save(&self, key: &str, value: &str) -> Result<(), StorageError>;
+
+    /// Removes the key. Removing a missing key is not an error.
+    fn remove(&self, key: &str) -> Result<(), StorageError>;
+
+    /// Returns all keys starting with the prefix, sorted.
+    fn keys(&self, prefix: &str) -> Result<Vec<String>, StorageError>;
+
+    /// Returns a storage using the same data.
+    fn box_clone(&self) -> Box<dyn DataStorage>;
+
+    /// Returns the value of the key, or an empty string if it doesn't exist.
+    fn load(&self, key: &str) -> Result<String, StorageError> {
+        Ok(self.get(key)?.unwrap_or_default())
+    }
+
+    /// Binary values are stored as hex, so every storage can hold them.
+    fn load_bytes(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        self.get(key)?.map(|value| from_hex(&value)).transpose()
+    }
+
+    fn save_bytes(&self, key: &str, value: &[u8]) -> Result<(), StorageError> {
+        self.save(key, &to_hex(value))
+    }
 
-    fn save(&self, key: &str, value: &str) -> Result<(), String>;
+    /// Returns the size of the stored value, or None if the key doesn't exist.
+    fn size(&self, key: &str) -> Result<Option<usize>, StorageError> {
+        Ok(self.get(key)?.map(|value| value.len()))
+    }
+
+    /// Returns the size of all values with keys starting with the prefix.
+    fn used(&self, prefix: &str) -> Result<usize, StorageError> {
+        let mut used = 0;
+        for key in self.keys(prefix)? {
+            used += self.size(&key)?.unwrap_or_default();
+        }
+        Ok(used)
+    }
+
+    /// Applies all operations, or none of them if one fails. Here the
+    /// previous values are restored if an operation fails.
+    fn apply(&self, ops: Vec<StorageOp>) -> Result<(), StorageError> {
+        let mut undo: Vec<(String, Option<String>)> = vec![];
+        for op in ops {
+            let previous = self.get(op.key())?;
+            let res = match &op {
+                StorageOp::Save(key, value) => self.save(key, value),
+                StorageOp::Remove(key) => self.remove(key),
+            };
+            if let Err(e) = res {
+                for (key, value) in undo.into_iter().rev() {
+                    let _ = match value {
+                        Some(value) => self.save(&key, &value),
+                        None => self.remove(&key),
+                    };
+                }
+                return Err(e);
+            }
+            undo.push((op.key().to_string(), previous));
+        }
+        Ok(())
+    }
+
+    /// Returns a storage whose keys are all prefixed with `name/`, so that
+    /// different modules cannot overwrite each other's data.
+    fn namespace(&self, name: &str) -> Box<dyn DataStorage> {
+        Box::new(Namespace::new(self.box_clone(), name))
+    }
 }
 
+/// Implementations only need to write out the records, filtering by module
+/// and level is done in `log`.
 pub trait Logger: Send {
-    fn info(&self, s: &str);
-    fn warn(&self, s: &str);
-    fn error(&self, s: &str);
+    fn write(&self, record: &Record);
     fn clone(&self) -> Box<dyn Logger>;
+
+    /// The module used for filtering, set by `with_context`.
+    fn module(&self) -> &str {
+        ""
+    }
+
+    /// Returns a logger that adds the module and the fields to every record.
+    fn with_context(&self, module: &str, fields: &[(&str, String)]) -> Box<dyn Logger> {
+        Box::new(ContextLogger::new(Logger::clone(self), module, fields))
+    }
+
+    fn log(&self, level: Level, msg: &str, fields: &[(&str, String)]) {
+        if enabled(self.module(), level) {
+            self.write(&Record {
+                level,
+                module: self.module(),
+                msg,
+                fields,
+            });
+        }
+    }
+
+    fn error(&self, s: &str) {
+        self.log(Level::Error, s, &[]);
+    }
+
+    fn warn(&self, s: &str) {
+        self.log(Level::Warn, s, &[]);
+    }
+
+    fn info(&self, s: &str) {
+        self.log(Level::Info, s, &[]);
+    }
+
+    fn debug(&self, s: &str) {
+        self.log(Level::Debug, s, &[]);
+    }
+
+    fn trace(&self, s: &str) {
+        self.log(Level::Trace, s, &[]);
+    }
 }
@@ -0,0 +1,216 @@
+//! Levels, records and a runtime filter for the `Logger` trait.
+//! Every logger has a module, e.g. "network::connection", and a list of
+//! key-value fields like the node or the peer it is working for.
+//! The filter decides per module which levels are written. It can be changed
+//! at runtime with `set_filter`, using a string like
+//!
+//!   `info,network=debug,network::connection=trace`
+//!
+//! where the first entry without a module is the default level.
+use std::{fmt, str::FromStr, sync::Mutex};
+use thiserror::Error;
+
+use super::ext_interface::Logger;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        })
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum FilterError {
+    #[error("unknown log level: {0}")]
+    Level(String),
+}
+
+impl FromStr for Level {
+    type Err = FilterError;
+
+    fn from_str(s: &str) -> Result<Level, FilterError> {
+        match s.trim().to_lowercase().as_str() {
+            "error" => Ok(Level::Error),
+            "warn" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            "trace" => Ok(Level::Trace),
+            l => Err(FilterError::Level(l.to_string())),
+        }
+    }
+}
+
+/// One log entry as it is passed to `Logger::write`.
+pub struct Record<'a> {
+    pub level: Level,
+    pub module: &'a str,
+    pub msg: &'a str,
+    pub fields: &'a [(&'a str, String)],
+}
+
+impl fmt::Display for Record<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.module.is_empty() {
+            write!(f, "[{}] ", self.module)?;
+        }
+        f.write_str(self.msg)?;
+        for (key, value) in self.fields {
+            write!(f, " {}={}", key, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Decides which records are written, depending on their module and level.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogFilter {
+    default: Level,
+    modules: Vec<(String, Level)>,
+}
+
+impl LogFilter {
+    /// Only writes info, warn and error.
+    pub const fn new() -> LogFilter {
+        LogFilter {
+            default: Level::Info,
+            modules: Vec::new(),
+        }
+    }
+
+    /// Returns true if the level is to be written for this module. The longest
+    /// matching module of the filter is used, so `network::connection=trace`
+    /// overrides `network=info`.
+    pub fn enabled(&self, module: &str, level: Level) -> bool {
+        let max = self
+            .modules
+            .iter()
+            .filter(|(m, _)| {
+                module == m || (module.starts_with(m.as_str()) && module[m.len()..].starts_with("::"))
+            })
+            .max_by_key(|(m, _)| m.len())
+            .map(|(_, l)| *l)
+            .unwrap_or(self.default);
+        level <= max
+    }
+}
+
+impl Default for LogFilter {
+    fn default() -> Self {
+        LogFilter::new()
+    }
+}
+
+impl FromStr for LogFilter {
+    type Err = FilterError;
+
+    fn from_str(s: &str) -> Result<LogFilter, FilterError> {
+        let mut filter = LogFilter::new();
+        for entry in s.split(',').map(|e| e.trim()).filter(|e| !e.is_empty()) {
+            match entry.split_once('=') {
+                Some((module, level)) => filter
+                    .modules
+                    .push((module.trim().to_string(), level.parse()?)),
+                None => filter.default = entry.parse()?,
+            }
+        }
+        Ok(filter)
+    }
+}
+
+static FILTER: Mutex<LogFilter> = Mutex::new(LogFilter::new());
+
+/// Replaces the filter used by all loggers.
+pub fn set_filter(filter: LogFilter) {
+    *FILTER.lock().unwrap() = filter;
+}
+
+/// Parses the string and replaces the filter used by all loggers.
+pub fn set_filter_str(s: &str) -> Result<(), FilterError> {
+    set_filter(s.parse()?);
+    Ok(())
+}
+
+/// Returns true if the current filter lets this record pass.
+pub fn enabled(module: &str, level: Level) -> bool {
+    FILTER.lock().unwrap().enabled(module, level)
+}
+
+/// Adds a module and key-value fields to all records before passing them
+/// to the wrapped logger. Created by `Logger::with_context`.
+pub struct ContextLogger {
+    inner: Box<dyn Logger>,
+    module: String,
+    fields: Vec<(String, String)>,
+}
+
+impl ContextLogger {
+    pub fn new(inner: Box<dyn Logger>, module: &str, fields: &[(&str, String)]) -> ContextLogger {
+        ContextLogger {
+            inner,
+            module: module.to_string(),
+            fields: fields
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.clone()))
+                .collect(),
+        }
+    }
+}
+
+impl Logger for ContextLogger {
+    fn write(&self, record: &Record) {
+        let fields: Vec<(&str, String)> = self
+            .fields
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.clone()))
+            .chain(record.fields.iter().cloned())
+            .collect();
+        self.inner.write(&Record {
+            fields: &fields,
+            ..*record
+        });
+    }
+
+    fn module(&self) -> &str {
+        &self.module
+    }
+
+    fn clone(&self) -> Box<dyn Logger> {
+        Box::new(ContextLogger {
+            inner: self.inner.clone(),
+            module: self.module.clone(),
+            fields: self.fields.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter() -> Result<(), FilterError> {
+        let filter: LogFilter = "warn,network=debug,network::connection=trace".parse()?;
+        assert!(filter.enabled("node", Level::Warn));
+        assert!(!filter.enabled("node", Level::Info));
+        assert!(filter.enabled("network", Level::Debug));
+        assert!(!filter.enabled("network", Level::Trace));
+        assert!(filter.enabled("network::connection", Level::Trace));
+        assert!(!filter.enabled("networking", Level::Debug));
+        assert!("info,network=loud".parse::<LogFilter>().is_err());
+        Ok(())
+    }
+}
@@ -1,12 +1,19 @@
+pub mod latency;
+
 use super::{
-    config::NodeInfo, ext_interface::Logger, network::connection_state::CSEnum, types::U256,
-};
-use crate::signal::web_rtc::{ConnectionStateMap, WebRTCConnectionState};
-use js_sys::Date;
-use std::{
-    collections::HashMap,
-    sync::mpsc::{channel, Receiver, Sender},
+    config::NodeInfo,
+    events::{channel, ChannelError, Receiver, Sender, Wakeup},
+    ext_interface::Logger,
+    logging::Level,
+    network::connection_state::CSEnum,
+    types::{Clock, U256},
 };
+use crate::signal::web_rtc::{ConnType, ConnectionStateMap, WebRTCConnectionState};
+use rand::random;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use self::latency::{Latency, LatencyMatrix, LogicMessage};
 
 #[derive(Debug)]
 pub enum LInput {
@@ -19,6 +26,11 @@ pub enum LInput {
         CSEnum,
         Option<ConnectionStateMap>,
     ),
+    Dropped(U256, String),
+    /// The messages to this node are relayed through the signal server.
+    Relayed(U256, bool),
+    /// Peers from a previous run, which are pinged to set up the connections.
+    Reconnect(Vec<NodeInfo>),
 }
 
 #[derive(Debug)]
@@ -26,7 +38,7 @@ pub enum LOutput {
     WebRTC(U256, String),
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub enum ConnState {
     Idle,
     Setup,
@@ -34,6 +46,8 @@ pub enum ConnState {
     TURN,
     STUN,
     Host,
+    /// No WebRTC connection, the messages go through the signal server.
+    Relay,
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -45,20 +59,41 @@ pub struct Stat {
     pub incoming: ConnState,
     pub outgoing: ConnState,
     pub client_info: String,
+    /// The last statistics received from a connected link.
+    pub link: Option<ConnectionStateMap>,
+    /// Round-trip times measured with ping / pong.
+    pub latency: Latency,
+    /// The average round-trip times this peer reported in its last pong.
+    pub peer_rtts: Vec<(U256, f64)>,
+    /// The messages to this peer go through the signal server.
+    pub relayed: bool,
 }
 
 impl Stat {
-    pub fn new(node_info: Option<NodeInfo>) -> Stat {
+    pub fn new(node_info: Option<NodeInfo>, time: f64) -> Stat {
         Stat {
             node_info,
             ping_rx: 0,
             ping_tx: 0,
-            last_contact: Date::now(),
+            last_contact: time,
             incoming: ConnState::Idle,
             outgoing: ConnState::Idle,
             client_info: "N/A".to_string(),
+            link: None,
+            latency: Latency::default(),
+            peer_rtts: vec![],
+            relayed: false,
         }
     }
+
+    /// Returns the type of the connection if one is up, preferring the
+    /// outgoing one. Relayed messages don't count as a connection.
+    pub fn connection(&self) -> Option<ConnState> {
+        [&self.outgoing, &self.incoming]
+            .iter()
+            .find(|cs| !matches!(cs, ConnState::Idle | ConnState::Setup | ConnState::Relay))
+            .map(|cs| (*cs).clone())
+    }
 }
 
 pub struct Logic {
@@ -69,15 +104,22 @@ pub struct Logic {
     output_tx: Sender<LOutput>,
     node_info: NodeInfo,
     logger: Box<dyn Logger>,
+    clock: Clock,
 }
 
 impl Logic {
-    pub fn new(node_info: NodeInfo, logger: Box<dyn Logger>) -> Logic {
-        let (input_tx, input_rx) = channel::<LInput>();
-        let (output_tx, output_rx) = channel::<LOutput>();
+    pub fn new(
+        node_info: NodeInfo,
+        logger: Box<dyn Logger>,
+        clock: Clock,
+        wakeup: &Wakeup,
+    ) -> Logic {
+        let (input_tx, input_rx) = channel::<LInput>(wakeup);
+        let (output_tx, output_rx) = channel::<LOutput>(wakeup);
         Logic {
             node_info,
             logger,
+            clock,
             stats: HashMap::new(),
             input_tx,
             input_rx,
@@ -86,16 +128,24 @@ impl Logic {
         }
     }
 
-    pub async fn process(&mut self) -> Result<(), String> {
+    pub async fn process(&mut self) -> Result<(), ChannelError> {
         let msgs: Vec<LInput> = self.input_rx.try_iter().collect();
         for msg in msgs {
-            // self.logger
-            //     .info(&format!("dbg: Logic::process got {:?}", msg));
+            self.logger.log(
+                Level::Trace,
+                "Processing input",
+                &[("input", format!("{:?}", msg))],
+            );
             match msg {
-                LInput::WebRTC(id, msg) => self.rcv(id, msg),
+                LInput::WebRTC(id, msg) => self.rcv(id, msg)?,
                 LInput::SetNodes(nodes) => self.store_nodes(nodes),
                 LInput::PingAll(msg) => self.ping_all(msg)?,
                 LInput::ConnStat(id, dir, c, stm) => self.update_connection_state(id, dir, c, stm),
+                LInput::Dropped(id, msg) => self
+                    .logger
+                    .warn(&format!("Dropped message {} to {}", msg, id)),
+                LInput::Relayed(id, relayed) => self.update_relayed(id, relayed),
+                LInput::Reconnect(nodes) => self.reconnect(nodes)?,
             }
         }
         Ok(())
@@ -108,30 +158,31 @@ impl Logic {
         st: CSEnum,
         state: Option<ConnectionStateMap>,
     ) {
+        let time = (self.clock)();
         self.stats
             .entry(id.clone())
-            .or_insert_with(|| Stat::new(None));
+            .or_insert_with(|| Stat::new(None, time));
         let log = self.logger.clone();
         self.stats.entry(id.clone()).and_modify(|s| {
             let cs = match st {
+                CSEnum::Idle | CSEnum::Setup
+                    if s.relayed && dir == WebRTCConnectionState::Initializer =>
+                {
+                    ConnState::Relay
+                }
                 CSEnum::Idle => ConnState::Idle,
                 CSEnum::Setup => ConnState::Setup,
                 CSEnum::Connected => {
                     if let Some(state_value) = state {
                         if let Some(n) = s.node_info.as_ref() {
-                            log.info(&format!("Got CSM from {}: {:?}", n.info, state_value));
-                        }
-                        match state_value.type_local {
-                            crate::signal::web_rtc::ConnType::Unknown => ConnState::Connected,
-                            crate::signal::web_rtc::ConnType::Host => ConnState::Host,
-                            crate::signal::web_rtc::ConnType::STUNPeer => ConnState::STUN,
-                            crate::signal::web_rtc::ConnType::STUNServer => ConnState::STUN,
-                            crate::signal::web_rtc::ConnType::TURN => ConnState::TURN,
+                            log.info(&format!("Got CSM from {}: {:?}", n.label(), state_value));
                         }
+                        s.link = Some(state_value);
+                        Logic::link_type(&state_value)
                     } else {
                         ConnState::Connected
                     }
-                },
+                }
             };
             if dir == WebRTCConnectionState::Initializer {
                 s.outgoing = cs;
@@ -141,36 +192,157 @@ impl Logic {
         });
     }
 
+    /// Marks the outgoing link as relayed, or back to idle once the messages
+    /// go over WebRTC again and no outgoing connection is up.
+    fn update_relayed(&mut self, id: U256, relayed: bool) {
+        let time = (self.clock)();
+        let s = self
+            .stats
+            .entry(id)
+            .or_insert_with(|| Stat::new(None, time));
+        s.relayed = relayed;
+        if relayed {
+            s.outgoing = ConnState::Relay;
+        } else if s.outgoing == ConnState::Relay {
+            s.outgoing = ConnState::Idle;
+        }
+    }
+
+    /// Returns how the data travels between the nodes: if any side uses
+    /// TURN, it is relayed, and if any side uses STUN, it goes through NAT.
+    fn link_type(state: &ConnectionStateMap) -> ConnState {
+        let types = [state.type_local, state.type_remote];
+        if types.contains(&ConnType::TURN) {
+            ConnState::TURN
+        } else if types.contains(&ConnType::STUNPeer) || types.contains(&ConnType::STUNServer) {
+            ConnState::STUN
+        } else if types.iter().all(|t| t == &ConnType::Host) {
+            ConnState::Host
+        } else {
+            ConnState::Connected
+        }
+    }
+
     fn store_nodes(&mut self, nodes: Vec<NodeInfo>) {
+        let time = (self.clock)();
         for ni in nodes {
             self.stats
                 .entry(ni.public.clone())
-                .or_insert_with(|| Stat::new(Some(ni)));
+                .or_insert_with(|| Stat::new(Some(ni), time));
         }
     }
 
-    fn ping_all(&mut self, msg: String) -> Result<(), String> {
-        for stat in self.stats.iter_mut() {
-            if let Some(ni) = stat.1.node_info.as_ref() {
-                if self.node_info.public != ni.public {
-                    self.output_tx
-                        .send(LOutput::WebRTC(ni.public.clone(), msg.clone()))
-                        .map_err(|e| e.to_string())?;
-                    stat.1.ping_tx += 1;
-                }
-            }
+    /// Returns the average round-trip times measured by this node and the ones
+    /// reported by its peers.
+    pub fn latency_matrix(&self) -> LatencyMatrix {
+        let mut peers: Vec<&U256> = self
+            .stats
+            .keys()
+            .filter(|id| *id != &self.node_info.public)
+            .collect();
+        peers.sort_by_key(|id| id.to_string());
+        let mut rows: HashMap<U256, Vec<(U256, f64)>> = self
+            .stats
+            .iter()
+            .map(|(id, s)| (id.clone(), s.peer_rtts.clone()))
+            .collect();
+        rows.insert(self.node_info.public.clone(), self.rtts());
+        let nodes = std::iter::once(&self.node_info.public)
+            .chain(peers)
+            .cloned()
+            .collect();
+        LatencyMatrix::new(nodes, &rows)
+    }
+
+    /// The average round-trip times to all peers with at least one pong.
+    fn rtts(&self) -> Vec<(U256, f64)> {
+        self.stats
+            .iter()
+            .filter_map(|(id, s)| s.latency.rtt_avg_ms.map(|rtt| (id.clone(), rtt)))
+            .collect()
+    }
+
+    fn ping_all(&mut self, msg: String) -> Result<(), ChannelError> {
+        let ids: Vec<U256> = self
+            .stats
+            .values()
+            .filter_map(|s| s.node_info.as_ref())
+            .filter(|ni| ni.public != self.node_info.public)
+            .map(|ni| ni.public.clone())
+            .collect();
+        for id in ids {
+            self.ping(&id, &msg)?;
+        }
+        Ok(())
+    }
+
+    /// Stores the peers of a previous run and pings them, in the given order,
+    /// so the connections are set up before the first message is needed.
+    fn reconnect(&mut self, nodes: Vec<NodeInfo>) -> Result<(), ChannelError> {
+        let ids: Vec<U256> = nodes.iter().map(|ni| ni.public.clone()).collect();
+        self.store_nodes(nodes);
+        for id in ids {
+            self.ping(&id, "reconnect")?;
+        }
+        Ok(())
+    }
+
+    fn ping(&mut self, id: &U256, msg: &str) -> Result<(), ChannelError> {
+        let time = (self.clock)();
+        if let Some(stat) = self.stats.get_mut(id) {
+            stat.latency.expire(time);
+            let nonce = random();
+            let ping = LogicMessage::Ping {
+                nonce,
+                msg: msg.to_string(),
+            };
+            self.output_tx
+                .send(LOutput::WebRTC(id.clone(), Logic::encode(&ping)))?;
+            stat.latency.ping(nonce, time);
+            stat.ping_tx += 1;
         }
         Ok(())
     }
 
-    fn rcv(&mut self, id: U256, msg: String) {
+    /// Treats a message from another node. Messages that are not a LogicMessage
+    /// are counted like a ping, but not answered.
+    fn rcv(&mut self, id: U256, msg: String) -> Result<(), ChannelError> {
         self.logger.info(&format!("Got msg {} from id {}", msg, id));
-        self.stats
+        let rtts = self.rtts();
+        let time = (self.clock)();
+        let s = self
+            .stats
             .entry(id.clone())
-            .or_insert_with(|| Stat::new(None));
-        self.stats.entry(id.clone()).and_modify(|s| {
-            s.last_contact = Date::now();
-            s.ping_rx += 1;
-        });
+            .or_insert_with(|| Stat::new(None, time));
+        s.last_contact = time;
+        match serde_json::from_str::<LogicMessage>(&msg) {
+            Ok(LogicMessage::Ping { nonce, .. }) => {
+                s.ping_rx += 1;
+                let pong = LogicMessage::Pong { nonce, rtts };
+                self.output_tx
+                    .send(LOutput::WebRTC(id, Logic::encode(&pong)))?;
+            }
+            Ok(LogicMessage::Pong { nonce, rtts }) => {
+                match s.latency.pong(nonce, time) {
+                    Some(rtt) => self.logger.log(
+                        Level::Debug,
+                        "Got pong",
+                        &[("peer", id.to_string()), ("rtt_ms", rtt.to_string())],
+                    ),
+                    None => self.logger.log(
+                        Level::Debug,
+                        "Got late or unknown pong",
+                        &[("peer", id.to_string()), ("nonce", nonce.to_string())],
+                    ),
+                }
+                s.peer_rtts = rtts;
+            }
+            Err(_) => s.ping_rx += 1,
+        }
+        Ok(())
+    }
+
+    fn encode(msg: &LogicMessage) -> String {
+        serde_json::to_string(msg).expect("LogicMessage is always serializable")
     }
 }
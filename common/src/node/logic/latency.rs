@@ -0,0 +1,128 @@
+//! Round-trip measurements between the nodes. Every ping carries a nonce that
+//! is sent back in the pong, so the round-trip time can be measured and
+//! missing pongs can be counted as lost.
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::node::types::U256;
+
+/// After this time, a ping without pong is counted as lost.
+pub const PING_TIMEOUT_MS: f64 = 10_000.;
+
+/// Messages sent between the Logic of two nodes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum LogicMessage {
+    Ping { nonce: u64, msg: String },
+    /// Answer to a Ping. It also holds the average round-trip times the
+    /// sender measured to its peers, so that all nodes can build a matrix.
+    Pong { nonce: u64, rtts: Vec<(U256, f64)> },
+}
+
+/// Round-trip time, jitter and loss to one peer.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Latency {
+    /// Pongs received in time.
+    pub received: u64,
+    /// Pings that didn't get a pong within PING_TIMEOUT_MS.
+    pub lost: u64,
+    /// The last measured round-trip time.
+    pub rtt_ms: Option<f64>,
+    /// Smoothed round-trip time, like the SRTT of TCP.
+    pub rtt_avg_ms: Option<f64>,
+    /// Interarrival jitter as in RFC 3550, using the round-trip times.
+    pub jitter_ms: f64,
+    pending: HashMap<u64, f64>,
+}
+
+impl Latency {
+    /// Remembers a ping that has been sent at `time`.
+    pub fn ping(&mut self, nonce: u64, time: f64) {
+        self.pending.insert(nonce, time);
+    }
+
+    /// Updates the statistics with a pong received at `time`. Returns the
+    /// round-trip time, or None if the nonce is unknown or expired.
+    pub fn pong(&mut self, nonce: u64, time: f64) -> Option<f64> {
+        let rtt = time - self.pending.remove(&nonce)?;
+        if let Some(last) = self.rtt_ms {
+            self.jitter_ms += ((rtt - last).abs() - self.jitter_ms) / 16.;
+        }
+        self.rtt_avg_ms = Some(match self.rtt_avg_ms {
+            Some(avg) => avg + (rtt - avg) / 8.,
+            None => rtt,
+        });
+        self.rtt_ms = Some(rtt);
+        self.received += 1;
+        Some(rtt)
+    }
+
+    /// Counts all pings older than PING_TIMEOUT_MS as lost.
+    pub fn expire(&mut self, time: f64) {
+        let before = self.pending.len();
+        self.pending.retain(|_, sent| time - *sent < PING_TIMEOUT_MS);
+        self.lost += (before - self.pending.len()) as u64;
+    }
+
+    /// Returns the ratio of lost pings, between 0 and 1.
+    pub fn loss(&self) -> f64 {
+        match self.received + self.lost {
+            0 => 0.,
+            total => self.lost as f64 / total as f64,
+        }
+    }
+}
+
+/// Average round-trip times between all nodes known to this node.
+/// `rtt_ms[i][j]` is the time measured by `nodes[i]` to `nodes[j]`, if
+/// available. The first row is the one measured by this node, the other rows
+/// are the ones reported by the peers in their pongs.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LatencyMatrix {
+    pub nodes: Vec<U256>,
+    pub rtt_ms: Vec<Vec<Option<f64>>>,
+}
+
+impl LatencyMatrix {
+    /// Creates the matrix out of the rows of every node.
+    pub fn new(nodes: Vec<U256>, rows: &HashMap<U256, Vec<(U256, f64)>>) -> LatencyMatrix {
+        let rtt_ms = nodes
+            .iter()
+            .map(|from| {
+                let row: HashMap<&U256, f64> = rows
+                    .get(from)
+                    .map(|r| r.iter().map(|(id, rtt)| (id, *rtt)).collect())
+                    .unwrap_or_default();
+                nodes.iter().map(|to| row.get(to).cloned()).collect()
+            })
+            .collect();
+        LatencyMatrix { nodes, rtt_ms }
+    }
+
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string_pretty(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rtt_jitter_loss() {
+        let mut lat = Latency::default();
+        lat.ping(1, 0.);
+        lat.ping(2, 100.);
+        lat.ping(3, 200.);
+        assert_eq!(lat.pong(1, 50.), Some(50.));
+        assert_eq!(lat.pong(1, 60.), None);
+        assert_eq!(lat.pong(2, 182.), Some(82.));
+        assert_eq!(lat.rtt_avg_ms, Some(54.));
+        assert_eq!(lat.jitter_ms, 2.);
+
+        lat.expire(200. + PING_TIMEOUT_MS);
+        assert_eq!(lat.lost, 1);
+        assert_eq!(lat.pong(3, 200. + PING_TIMEOUT_MS), None);
+        assert!((lat.loss() - 1. / 3.).abs() < 1e-9);
+    }
+}
@@ -1,26 +1,50 @@
 use crate::signal::{
     web_rtc::{
-        ConnectionStateMap, MessageAnnounce, PeerInfo, WSSignalMessage, WebRTCSpawner,
+        ConnectionStateMap, MessageAnnounce, PeerInfo, RelayMessage, WSSignalMessage,
         WebSocketMessage,
     },
-    websocket::{WSMessage, WebSocketConnection},
+    websocket::{WSError, WSMessage, WebSocketConnection},
 };
 use crate::{
-    node::{config::NodeInfo, ext_interface::Logger, types::U256},
+    node::{
+        config::NodeInfo,
+        events::{channel, ChannelError, Receiver, Sender, Wakeup},
+        ext_interface::Logger,
+        logging::Level,
+        types::U256,
+    },
     signal::web_rtc::WebRTCConnectionState,
 };
 
-use std::sync::{
-    mpsc::{channel, Receiver, Sender},
-    Mutex,
-};
-use std::{collections::HashMap, sync::Arc};
-
-use node_connection::{NCInput, NodeConnection};
+use ed25519_dalek::SigningKey;
+use std::collections::HashMap;
+use thiserror::Error;
 
-use self::{connection_state::CSEnum, node_connection::NCOutput};
+use self::{
+    connection_state::CSEnum,
+    timeline::ConnectionTimeline,
+    transport::{Transport, TransportError, TransportKind, TransportOutput},
+};
 pub mod connection_state;
 pub mod node_connection;
+pub mod noise;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod tcp_transport;
+pub mod timeline;
+pub mod transport;
+pub mod web_rtc_transport;
+
+#[derive(Error, Debug)]
+pub enum NetworkError {
+    #[error(transparent)]
+    Channel(#[from] ChannelError),
+    #[error(transparent)]
+    WebSocket(#[from] WSError),
+    #[error(transparent)]
+    Transport(#[from] TransportError),
+    #[error("no transport to reach {0}")]
+    NoTransport(U256),
+}
 
 pub enum NOutput {
     WebRTC(U256, String),
@@ -31,6 +55,13 @@ pub enum NOutput {
         CSEnum,
         Option<ConnectionStateMap>,
     ),
+    /// A message to this node couldn't be queued and has been dropped.
+    Dropped(U256, String),
+    /// Messages to this node are relayed through the signal server, or not
+    /// anymore.
+    Relayed(U256, bool),
+    /// Peers from a previous run that can be reached now.
+    Reconnect(Vec<NodeInfo>),
 }
 
 pub enum NInput {
@@ -45,33 +76,49 @@ pub struct Network {
     list: Vec<NodeInfo>,
     ws: Box<dyn WebSocketConnection>,
     ws_rx: Receiver<WSMessage>,
-    web_rtc: Arc<Mutex<WebRTCSpawner>>,
-    connections: HashMap<U256, NodeConnection>,
+    // sorted by TransportKind, so the preferred transport comes first
+    transports: Vec<Box<dyn Transport>>,
+    // the index of the transport used for every remote node
+    routes: HashMap<U256, usize>,
+    // peers from a previous run waiting for the signal server or a peer
+    reconnect: Vec<NodeInfo>,
+    // the challenge of the signal server, while it is connected
+    challenge: Option<U256>,
+    // the connections that are up, in the order they came up
+    connected: Vec<(U256, WebRTCConnectionState)>,
+    // the peer through which the setup messages of a node came last
+    signal_peers: HashMap<U256, U256>,
     node_info: NodeInfo,
+    secret: SigningKey,
     logger: Box<dyn Logger>,
 }
 
 /// Network combines a websocket to connect to the signal server with
-/// a WebRTC trait to connect to other nodes.
+/// one or more transports to connect to other nodes.
 /// It supports setting up automatic connetions to other nodes.
 impl Network {
     pub fn new(
         logger: Box<dyn Logger>,
         node_info: NodeInfo,
+        secret: SigningKey,
         mut ws: Box<dyn WebSocketConnection>,
-        web_rtc: WebRTCSpawner,
+        wakeup: Wakeup,
     ) -> Network {
-        let (output_tx, output_rx) = channel::<NOutput>();
-        let (input_tx, input_rx) = channel::<NInput>();
-        let (ws_tx, ws_rx) = channel::<WSMessage>();
+        let (output_tx, output_rx) = channel::<NOutput>(&wakeup);
+        let (input_tx, input_rx) = channel::<NInput>(&wakeup);
+        let (ws_tx, ws_rx) = channel::<WSMessage>(&wakeup);
         let log_clone = logger.clone();
         ws.set_cb_wsmessage(Box::new(move |msg| {
-            // log_clone.info(&format!("dbg: Got message from websocket: {:?}", msg));
+            log_clone.log(
+                Level::Trace,
+                "Got message from websocket",
+                &[("msg", format!("{:?}", msg))],
+            );
             if let Err(e) = ws_tx.send(msg) {
                 log_clone.info(&format!("Couldn't send msg over ws-channel: {}", e));
             }
         }));
-        let net = Network {
+        let mut network = Network {
             list: vec![],
             output_tx,
             output_rx,
@@ -79,23 +126,119 @@ impl Network {
             input_rx,
             ws,
             ws_rx,
-            web_rtc: Arc::new(Mutex::new(web_rtc)),
-            connections: HashMap::new(),
-            node_info,
+            transports: vec![],
+            routes: HashMap::new(),
+            reconnect: vec![],
+            challenge: None,
+            connected: vec![],
+            signal_peers: HashMap::new(),
+            node_info: NodeInfo {
+                transports: vec![],
+                addresses: vec![],
+                ..node_info
+            },
+            secret,
             logger,
         };
-        net
+        network.node_info.sign(&network.secret);
+        network
+    }
+
+    /// Adds a transport and advertises it in the NodeInfo of this node,
+    /// together with its address, if any.
+    /// Transports must be added before the node announces itself to the
+    /// signal server.
+    pub fn add_transport(&mut self, mut transport: Box<dyn Transport>) -> Result<(), NetworkError> {
+        let address = transport.accept()?;
+        self.logger.info(&format!(
+            "Adding transport {:?} at {:?}",
+            transport.kind(),
+            address
+        ));
+        if let Some(address) = address {
+            self.node_info
+                .addresses
+                .push(format!("{}://{}", transport.kind().scheme(), address));
+        }
+        self.node_info.transports.push(transport.kind());
+        self.node_info.transports.sort();
+        self.node_info.sign(&self.secret);
+        self.transports.push(transport);
+        self.transports.sort_by_key(|t| t.kind());
+        self.routes.clear();
+        Ok(())
+    }
+
+    /// Adds peers known from a previous run to the list, so they can be
+    /// reached before the signal server sends its list. Peers with a direct
+    /// transport are returned with `NOutput::Reconnect` by the next `process`,
+    /// the others once the WebRTC connections can be set up, through the
+    /// signal server or through a connected peer.
+    pub fn reconnect(&mut self, peers: Vec<NodeInfo>) {
+        for ni in peers {
+            if !self.list.iter().any(|entry| entry.public == ni.public) {
+                self.list.push(ni.clone());
+                self.reconnect.push(ni);
+            }
+        }
+    }
+
+    /// Returns the waiting peers of a previous run: only the ones reachable
+    /// without the signal server, or all of them if it is connected or a
+    /// peer can pass on the setup of the WebRTC connections.
+    fn reconnect_peers(&mut self, signal: bool) -> Result<(), NetworkError> {
+        let signal = signal || !self.connected.is_empty();
+        let transports = &self.transports;
+        let (peers, waiting): (Vec<NodeInfo>, Vec<NodeInfo>) =
+            self.reconnect.drain(..).partition(|ni| {
+                let best = transports
+                    .iter()
+                    .find(|t| ni.transports.contains(&t.kind()));
+                signal || matches!(best, Some(t) if t.kind() != TransportKind::WebRTC)
+            });
+        self.reconnect = waiting;
+        if !peers.is_empty() {
+            self.output_tx.send(NOutput::Reconnect(peers))?;
+        }
+        Ok(())
+    }
+
+    /// Returns the information of this node as announced to the signal server.
+    pub fn node_info(&self) -> NodeInfo {
+        self.node_info.clone()
+    }
+
+    /// Changes the alias of this node and announces it again to the signal
+    /// server, if it is connected.
+    pub fn set_alias(&mut self, alias: Option<String>) -> Result<(), NetworkError> {
+        self.node_info.alias = alias;
+        self.node_info.sign(&self.secret);
+        match self.challenge.clone() {
+            Some(challenge) => self.announce(challenge),
+            None => Ok(()),
+        }
+    }
+
+    /// Signs the information of this node and sends it to the signal server.
+    fn announce(&mut self, challenge: U256) -> Result<(), NetworkError> {
+        self.node_info.sign(&self.secret);
+        let ma = MessageAnnounce {
+            challenge,
+            node_info: self.node_info.clone(),
+        };
+        self.ws_send(WSSignalMessage::Announce(ma))
     }
 
     /// Process all connections with their waiting messages.
-    pub async fn process(&mut self) -> Result<(), String> {
+    pub async fn process(&mut self) -> Result<(), NetworkError> {
+        self.reconnect_peers(false)?;
         self.process_input().await?;
         self.process_websocket().await?;
-        self.process_connections().await?;
+        self.process_transports().await?;
         Ok(())
     }
 
-    async fn process_input(&mut self) -> Result<(), String> {
+    async fn process_input(&mut self) -> Result<(), NetworkError> {
         let msgs: Vec<NInput> = self.input_rx.try_iter().collect();
         for msg in msgs {
             match msg {
@@ -105,102 +248,198 @@ impl Network {
         Ok(())
     }
 
-    async fn process_websocket(&mut self) -> Result<(), String> {
+    async fn process_websocket(&mut self) -> Result<(), NetworkError> {
         let msgs: Vec<WSMessage> = self.ws_rx.try_iter().collect();
         for msg in msgs {
-            // self.logger.info(&format!("dbg: Network::process_ws({:?})", msg));
             match msg {
                 WSMessage::MessageString(s) => {
                     self.process_msg(WebSocketMessage::from_str(&s)?.msg)
                         .await?;
                 }
-                _ => {}
+                WSMessage::Closed(e) | WSMessage::Error(e) => {
+                    self.logger.warn(&format!("Lost the signal server: {}", e));
+                    self.challenge = None;
+                }
+                WSMessage::Opened(_) => {}
             }
         }
         Ok(())
     }
 
-    async fn process_connections(&mut self) -> Result<(), String> {
+    async fn process_transports(&mut self) -> Result<(), NetworkError> {
         let mut ws_msgs = vec![];
-        let conns: Vec<(&U256, &mut NodeConnection)> = self.connections.iter_mut().collect();
-        for conn in conns {
-            let outputs: Vec<NCOutput> = conn.1.output_rx.try_iter().collect();
-            for output in outputs {
-                // self.logger.info(&format!("dbg: Network::process {:?}", output));
+        let mut peer_msgs = vec![];
+        for index in 0..self.transports.len() {
+            for output in self.transports[index].receive().await? {
                 match output {
-                    NCOutput::WebSocket(message, remote) => {
-                        let (id_init, id_follow) = match remote {
-                            true => (conn.0.clone(), self.node_info.public.clone()),
-                            false => (self.node_info.public.clone(), conn.0.clone()),
-                        };
-                        let peer_info = PeerInfo {
-                            id_init,
-                            id_follow,
-                            message,
-                        };
-                        ws_msgs.push(WSSignalMessage::PeerSetup(peer_info));
+                    TransportOutput::Message(id, msg) => {
+                        self.routes.entry(id.clone()).or_insert(index);
+                        match WebSocketMessage::from_str(&msg) {
+                            Ok(WebSocketMessage {
+                                msg: WSSignalMessage::Relay(rm),
+                            }) => peer_msgs.push((id, rm)),
+                            _ => self.output_tx.send(NOutput::WebRTC(id, msg))?,
+                        }
+                    }
+                    TransportOutput::State(id, dir, c, sta) => {
+                        if c == CSEnum::Idle && self.routes.get(&id) == Some(&index) {
+                            self.routes.remove(&id);
+                        }
+                        let conn = (id.clone(), dir);
+                        match c {
+                            CSEnum::Connected if !self.connected.contains(&conn) => {
+                                self.connected.push(conn)
+                            }
+                            CSEnum::Idle => self.connected.retain(|c| c != &conn),
+                            _ => {}
+                        }
+                        self.output_tx.send(NOutput::State(id, dir, c, sta))?
+                    }
+                    TransportOutput::Dropped(id, msg) => {
+                        self.output_tx.send(NOutput::Dropped(id, msg))?
+                    }
+                    TransportOutput::Relayed(id, relayed) => {
+                        self.output_tx.send(NOutput::Relayed(id, relayed))?
                     }
-                    NCOutput::WebRTCMessage(msg) => self
-                        .output_tx
-                        .send(NOutput::WebRTC(conn.0.clone(), msg))
-                        .map_err(|e| e.to_string())?,
-                    NCOutput::State(dir, c, sta) =>
-                        self.output_tx
-                            .send(NOutput::State(conn.0.clone(), dir, c, sta))
-                            .map_err(|e| e.to_string())?,
+                    TransportOutput::Signal(msg) => ws_msgs.push(*msg),
                 }
             }
-            conn.1.process().await?;
+        }
+        for (id, rm) in peer_msgs {
+            if let Err(e) = self.process_peer_signal(id.clone(), rm).await {
+                self.logger
+                    .warn(&format!("Couldn't treat setup message from {}: {}", id, e));
+            }
         }
         for msg in ws_msgs {
-            self.ws_send(msg)?;
+            self.send_signal(msg).await?;
         }
         Ok(())
     }
 
+    /// Sends a message to the signal server. While it is unreachable, the
+    /// setup of WebRTC connections goes through a connected peer instead.
+    async fn send_signal(&mut self, msg: WSSignalMessage) -> Result<(), NetworkError> {
+        match msg {
+            WSSignalMessage::PeerSetup(pi) => {
+                if self.challenge.is_some() {
+                    match self.ws_send(WSSignalMessage::PeerSetup(pi.clone())) {
+                        Ok(()) => return Ok(()),
+                        Err(e) => {
+                            self.logger.warn(&format!("Lost the signal server: {}", e));
+                            self.challenge = None;
+                        }
+                    }
+                }
+                self.signal_through_peer(pi).await
+            }
+            msg => self.ws_send(msg),
+        }
+    }
+
+    /// Sends the setup message through the peer that passed on the last setup
+    /// message of the remote node, or else through the first connected peer.
+    /// The peer must be connected to the remote node to pass it on.
+    /// Without a connected peer, it is sent to the signal server anyway.
+    async fn signal_through_peer(&mut self, pi: PeerInfo) -> Result<(), NetworkError> {
+        let dst = pi.get_remote(&self.node_info.public).unwrap();
+        let connected = |id: &U256| self.connected.iter().any(|(c, _)| c == id);
+        let peer = match self.signal_peers.get(&dst) {
+            Some(peer) if connected(peer) => Some(peer.clone()),
+            _ => self
+                .connected
+                .iter()
+                .map(|(id, _)| id)
+                .find(|id| *id != &dst)
+                .cloned(),
+        };
+        let msg = WebSocketMessage {
+            msg: WSSignalMessage::PeerSetup(pi),
+        };
+        match peer {
+            Some(peer) => {
+                let rm = RelayMessage {
+                    from: self.node_info.public.clone(),
+                    to: dst,
+                    msg: msg.to_string(),
+                };
+                let msg = WebSocketMessage {
+                    msg: WSSignalMessage::Relay(rm),
+                };
+                self.send(&peer, msg.to_string()).await
+            }
+            None => self.ws_send(msg.msg),
+        }
+    }
+
+    /// Treats a setup message passed on by a peer. If it is for this node, it
+    /// is handled like one from the signal server, else it is passed on to
+    /// the remote node if it is connected. Like the signal server, the peer
+    /// sets the sender to the node it got the message from.
+    async fn process_peer_signal(
+        &mut self,
+        peer: U256,
+        mut rm: RelayMessage,
+    ) -> Result<(), NetworkError> {
+        if rm.to != self.node_info.public {
+            if !self.connected.iter().any(|(id, _)| id == &rm.to) {
+                self.logger.warn(&format!(
+                    "Can't pass on setup message from {} to {}",
+                    peer, rm.to
+                ));
+                return Ok(());
+            }
+            rm.from = peer;
+            let to = rm.to.clone();
+            let msg = WebSocketMessage {
+                msg: WSSignalMessage::Relay(rm),
+            };
+            return self.send(&to, msg.to_string()).await;
+        }
+        match WebSocketMessage::from_str(&rm.msg) {
+            Ok(WebSocketMessage {
+                msg: WSSignalMessage::PeerSetup(pi),
+            }) if pi.get_remote(&rm.to) == Some(rm.from.clone()) => {
+                self.signal_peers.insert(rm.from, peer);
+                self.process_msg(WSSignalMessage::PeerSetup(pi)).await
+            }
+            _ => {
+                self.logger.warn(&format!(
+                    "Ignoring setup message from {} through {}",
+                    rm.from, peer
+                ));
+                Ok(())
+            }
+        }
+    }
+
     /// Processes incoming messages from the signalling server.
     /// This can be either messages requested by this node, or connection
-    /// setup requests from another node.
-    async fn process_msg(&mut self, msg: WSSignalMessage) -> Result<(), String> {
+    /// setup requests from another node, which are passed to the transports.
+    async fn process_msg(&mut self, msg: WSSignalMessage) -> Result<(), NetworkError> {
+        self.logger.log(
+            Level::Debug,
+            "Processing message from signalling server",
+            &[("type", msg.to_string())],
+        );
         match msg {
             WSSignalMessage::Challenge(challenge) => {
                 self.logger.info("Processing Challenge message");
-                let ma = MessageAnnounce {
-                    challenge,
-                    node_info: self.node_info.clone(),
-                };
-                self.ws.send(
-                    WebSocketMessage {
-                        msg: WSSignalMessage::Announce(ma),
-                    }
-                    .to_string(),
-                )?;
+                self.challenge = Some(challenge.clone());
+                self.announce(challenge)?;
                 self.update_node_list()?;
+                self.reconnect_peers(true)?;
             }
             WSSignalMessage::ListIDsReply(list) => {
                 self.logger.info("Processing ListIDsReply message");
                 self.update_list(list)?;
             }
-            WSSignalMessage::PeerSetup(pi) => {
-                // self.logger
-                //     .info(&format!("dbg: Network::Processing PeerSetup message: {}", pi.message));
-                let remote_node = match pi.get_remote(&self.node_info.public) {
-                    Some(id) => id,
-                    None => {
-                        return Err("Got alien PeerSetup".to_string());
-                    }
-                };
-                let remote = remote_node == pi.id_init;
-                let conn = self
-                    .connections
-                    .entry(remote_node)
-                    .or_insert(NodeConnection::new(
-                        self.logger.clone(),
-                        Arc::clone(&self.web_rtc),
-                    )?);
-                conn.input_tx
-                    .send(NCInput::WebSocket(pi.message, remote))
-                    .map_err(|e| e.to_string())?;
+            WSSignalMessage::PeerSetup(_)
+            | WSSignalMessage::IceServers(_)
+            | WSSignalMessage::Relay(_) => {
+                for transport in self.transports.iter_mut() {
+                    transport.signal(&msg)?;
+                }
             }
             WSSignalMessage::Done => {
                 self.logger.info("Processing done message");
@@ -213,52 +452,122 @@ impl Network {
     }
 
     /// Requests a new node list from the server.
-    pub fn update_node_list(&mut self) -> Result<(), String> {
+    pub fn update_node_list(&mut self) -> Result<(), NetworkError> {
         self.ws_send(WSSignalMessage::ListIDsRequest)
     }
 
-    /// Stores a node list sent from the signalling server.
-    fn update_list(&mut self, list: Vec<NodeInfo>) -> Result<(), String> {
+    /// Stores a node list sent from the signalling server, replacing the
+    /// peers known from a previous run.
+    /// Nodes with an invalid signature or without a common protocol version
+    /// are ignored, as well as nodes of other networks. The direct transports
+    /// refuse nodes of other networks in their handshake, and the signal
+    /// server doesn't set up WebRTC connections between networks.
+    fn update_list(&mut self, list: Vec<NodeInfo>) -> Result<(), NetworkError> {
+        let list: Vec<NodeInfo> = list
+            .into_iter()
+            .filter(|entry| match entry.verify() {
+                Ok(_) if entry.network != self.node_info.network => {
+                    self.logger.warn(&format!(
+                        "Ignoring node {} of network {}",
+                        entry.public, entry.network
+                    ));
+                    false
+                }
+                Ok(_) => self.node_info.compatible(entry),
+                Err(e) => {
+                    self.logger
+                        .warn(&format!("Ignoring node {}: {}", entry.public, e));
+                    false
+                }
+            })
+            .collect();
         self.list = list
             .iter()
             .filter(|entry| entry.public != self.node_info.public)
             .cloned()
             .collect();
-        self.output_tx.send(NOutput::UpdateList(list)).map_err(|e| e.to_string())
+        Ok(self.output_tx.send(NOutput::UpdateList(list))?)
     }
 
-    fn ws_send(&mut self, msg: WSSignalMessage) -> Result<(), String> {
-        self.ws.send(WebSocketMessage { msg }.to_string())
+    fn ws_send(&mut self, msg: WSSignalMessage) -> Result<(), NetworkError> {
+        Ok(self.ws.send(WebSocketMessage { msg }.to_string())?)
     }
 
-    /// Sends a message to the node dst.
-    /// If no connection is active yet, a new one will be created.
-    /// NodeConnection will take care of putting the message in a queue while
-    /// the setup is finishing.
-    async fn send(&mut self, dst: &U256, msg: String) -> Result<(), String> {
-        let conn = self
-            .connections
-            .entry(dst.clone())
-            .or_insert(NodeConnection::new(
-                self.logger.clone(),
-                Arc::clone(&self.web_rtc),
-            )?);
-        conn.send(msg.clone())
+    /// Sends a message to the node dst, using the transport chosen for this
+    /// node. If the transport fails, the route is dropped and the next
+    /// transport shared with the node is tried.
+    async fn send(&mut self, dst: &U256, msg: String) -> Result<(), NetworkError> {
+        let mut index = self.route(dst)?;
+        loop {
+            match self.transports[index].send(dst, msg.clone()) {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    self.routes.remove(dst);
+                    self.logger.warn(&format!(
+                        "Couldn't send to {} with {:?}: {}",
+                        dst,
+                        self.transports[index].kind(),
+                        e
+                    ));
+                    index = match self.pick_route(dst, index + 1)? {
+                        Some(next) => next,
+                        None => return Err(e.into()),
+                    };
+                }
+            }
+        }
     }
 
-    /// Prints the states of all connections.
-    pub async fn print_states(&self) -> Result<(), String> {
-        for (_id, conn) in self.connections.iter() {
-            for dir in conn.get_stats().await? {
-                if let Some(stats) = dir {
-                    self.logger.info(&format!("Connection is: {:?}", stats));
-                }
+    /// Returns the transport for the given node. The first time, the best
+    /// transport advertised by the node is chosen and connected. The route is
+    /// chosen again once its connection goes idle or a send fails. Nodes that
+    /// are not in the list yet are reached using WebRTC.
+    fn route(&mut self, dst: &U256) -> Result<usize, NetworkError> {
+        if let Some(index) = self.routes.get(dst) {
+            return Ok(*index);
+        }
+        self.pick_route(dst, 0)?
+            .ok_or_else(|| NetworkError::NoTransport(dst.clone()))
+    }
+
+    /// Connects to the node with the best transport starting at `from`, and
+    /// stores it as the route to the node.
+    fn pick_route(&mut self, dst: &U256, from: usize) -> Result<Option<usize>, NetworkError> {
+        let info = self.list.iter().find(|ni| &ni.public == dst).cloned();
+        let index = self
+            .transports
+            .iter()
+            .enumerate()
+            .skip(from)
+            .find(|(_, t)| match &info {
+                Some(ni) => ni.transports.contains(&t.kind()),
+                None => t.kind() == TransportKind::WebRTC,
+            })
+            .map(|(index, _)| index);
+        if let Some(index) = index {
+            if let Some(ni) = info {
+                self.transports[index].connect(&ni)?;
             }
+            self.routes.insert(dst.clone(), index);
+        }
+        Ok(index)
+    }
+
+    /// Returns the setup events of the connections to the given node, if a
+    /// connection has been started.
+    pub fn timeline(&self, id: &U256) -> Option<ConnectionTimeline> {
+        self.transports.iter().find_map(|t| t.timeline(id))
+    }
+
+    /// Prints the states of all connections.
+    pub async fn print_states(&self) -> Result<(), NetworkError> {
+        for transport in self.transports.iter() {
+            transport.print_states().await?;
         }
         Ok(())
     }
 
-    pub fn clear_nodes(&mut self) -> Result<(), String> {
+    pub fn clear_nodes(&mut self) -> Result<(), NetworkError> {
         self.ws_send(WSSignalMessage::ClearNodes)
     }
 
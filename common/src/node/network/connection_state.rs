@@ -1,20 +1,47 @@
 /// This handles one connection, either an incoming or an outgoing connection.
-use backtrace::Backtrace;
-
 use crate::{
-    node::ext_interface::Logger,
+    node::{
+        events::{channel, ChannelError, Receiver, Sender, Wakeup},
+        ext_interface::Logger,
+        logging::Level,
+        network::timeline::{Timeline, TimelineEntry, TimelineEvent},
+        types::Clock,
+    },
     signal::web_rtc::{
-        ConnectionStateMap, PeerMessage, WebRTCConnection, WebRTCConnectionSetup,
-        WebRTCConnectionState, WebRTCSetupCBMessage, WebRTCSpawner,
+        ConnectionError, ConnectionStateMap, IceServer, PeerMessage, SetupError, WebRTCConnection,
+        WebRTCConnectionEvent, WebRTCConnectionSetup, WebRTCConnectionState, WebRTCSetupCBMessage,
+        WebRTCSpawner,
     },
 };
-use std::sync::{
-    mpsc::{channel, Receiver, Sender},
-    Arc, Mutex,
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
 };
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum CSError {
+    #[error(transparent)]
+    Channel(#[from] ChannelError),
+    #[error(transparent)]
+    Setup(#[from] SetupError),
+    #[error(transparent)]
+    Connection(#[from] ConnectionError),
+    #[error("wrong PeerMessage {message} for {state:?}")]
+    WrongMessage {
+        message: PeerMessage,
+        state: WebRTCConnectionState,
+    },
+}
+
+/// How many messages are kept while the connection is being set up.
+/// If more messages arrive, the oldest ones are dropped and reported
+/// back using `CSOutput::Dropped`.
+pub const SEND_QUEUE_MAX: usize = 100;
 
 /// Represents the state of an incoming or outgoing connection.
-#[derive(PartialEq, Debug, Clone)]
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
 pub enum CSEnum {
     /// No connection yet
     Idle,
@@ -31,6 +58,7 @@ pub enum CSInput {
     ProcessPeerMessage(PeerMessage),
     Send(String),
     WebRTCSetup(WebRTCSetupCBMessage),
+    WebRTCEvent(WebRTCConnectionEvent),
 }
 
 /// Messages from ConnectionState to the parent or other modules.
@@ -39,6 +67,8 @@ pub enum CSOutput {
     State(CSEnum, Option<ConnectionStateMap>),
     WebSocket(PeerMessage),
     WebRTCMessage(String),
+    /// A message had to be dropped because the send queue was full.
+    Dropped(String),
 }
 
 /// Holds all information necessary to setup and hold a connection.
@@ -50,10 +80,13 @@ pub struct ConnectionState {
     input_rx: Receiver<CSInput>,
     logger: Box<dyn Logger>,
     web_rtc: Arc<Mutex<WebRTCSpawner>>,
-    send_queue: Vec<String>,
+    ice_servers: Arc<Mutex<Vec<IceServer>>>,
+    send_queue: VecDeque<String>,
     setup: Option<Box<dyn WebRTCConnectionSetup>>,
     connected: Option<Box<dyn WebRTCConnection>>,
     remote: bool,
+    timeline: Timeline,
+    clock: Clock,
 }
 
 impl ConnectionState {
@@ -62,9 +95,12 @@ impl ConnectionState {
         remote: bool,
         logger: Box<dyn Logger>,
         web_rtc: Arc<Mutex<WebRTCSpawner>>,
-    ) -> Result<ConnectionState, String> {
-        let (output_tx, output_rx) = channel::<CSOutput>();
-        let (input_tx, input_rx) = channel::<CSInput>();
+        ice_servers: Arc<Mutex<Vec<IceServer>>>,
+        clock: Clock,
+        wakeup: &Wakeup,
+    ) -> Result<ConnectionState, CSError> {
+        let (output_tx, output_rx) = channel::<CSOutput>(wakeup);
+        let (input_tx, input_rx) = channel::<CSInput>(wakeup);
         let cs = ConnectionState {
             state: CSEnum::Idle,
             output_rx,
@@ -73,39 +109,84 @@ impl ConnectionState {
             input_tx,
             logger,
             web_rtc,
-            send_queue: vec![],
+            ice_servers,
+            send_queue: VecDeque::new(),
             setup: None,
             connected: None,
             remote,
+            timeline: Timeline::new(),
+            clock,
         };
         if !remote {
             cs.input_tx
-                .send(CSInput::ProcessPeerMessage(PeerMessage::Init))
-                .map_err(|e| e.to_string())?;
+                .send(CSInput::ProcessPeerMessage(PeerMessage::Init))?;
         }
         Ok(cs)
     }
 
-    pub async fn process(&mut self) -> Result<(), String> {
+    pub async fn process(&mut self) -> Result<(), CSError> {
         let inputs: Vec<CSInput> = self.input_rx.try_iter().collect();
         for input in inputs {
-            // self.logger.info(&format!("dbg: ConnectionState::processes {:?}", input));
-            match input {
-                CSInput::GetState => self.get_state().await?,
-                CSInput::ProcessPeerMessage(msg) => self.process_peer_message(msg).await?,
-                CSInput::Send(s) => self.send(s).await?,
-                CSInput::WebRTCSetup(s) => self.web_rtc_setup(s)?,
+            self.logger.log(
+                Level::Trace,
+                "Processing input",
+                &[("input", format!("{:?}", input))],
+            );
+            let res = match input {
+                CSInput::GetState => self.get_state().await,
+                CSInput::ProcessPeerMessage(msg) => {
+                    self.timeline
+                        .push((self.clock)(), TimelineEvent::PeerReceived(msg.to_string()));
+                    self.process_peer_message(msg).await
+                }
+                CSInput::Send(s) => self.send(s).await,
+                CSInput::WebRTCSetup(s) => {
+                    self.timeline
+                        .push((self.clock)(), TimelineEvent::Setup(format!("{:?}", s)));
+                    self.web_rtc_setup(s)
+                }
+                CSInput::WebRTCEvent(ev) => {
+                    self.timeline.push(
+                        (self.clock)(),
+                        TimelineEvent::Connection(format!("{:?}", ev)),
+                    );
+                    self.web_rtc_event(ev)
+                }
             };
+            if let Err(e) = res {
+                self.timeline
+                    .push((self.clock)(), TimelineEvent::Error(e.to_string()));
+                return Err(e);
+            }
         }
         Ok(())
     }
 
-    fn web_rtc_setup(&mut self, s: WebRTCSetupCBMessage) -> Result<(), String> {
+    /// Returns the recorded events of this connection, oldest first.
+    pub fn timeline(&self) -> Vec<TimelineEntry> {
+        self.timeline.entries()
+    }
+
+    /// Changes the state, records it in the timeline, and informs the parent.
+    fn set_state(&mut self, state: CSEnum) -> Result<(), CSError> {
+        self.timeline
+            .push((self.clock)(), TimelineEvent::State(state.clone()));
+        self.state = state;
+        Ok(self
+            .output_tx
+            .send(CSOutput::State(self.state.clone(), None))?)
+    }
+
+    /// Sends a PeerMessage to the remote node through the signalling server.
+    fn send_peer(&mut self, msg: PeerMessage) -> Result<(), CSError> {
+        self.timeline
+            .push((self.clock)(), TimelineEvent::PeerSent(msg.to_string()));
+        Ok(self.output_tx.send(CSOutput::WebSocket(msg))?)
+    }
+
+    fn web_rtc_setup(&mut self, s: WebRTCSetupCBMessage) -> Result<(), CSError> {
         match s {
-            WebRTCSetupCBMessage::Ice(ice) => self
-                .output_tx
-                .send(CSOutput::WebSocket(PeerMessage::IceCandidate(ice)))
-                .map_err(|e| e.to_string()),
+            WebRTCSetupCBMessage::Ice(ice) => self.send_peer(PeerMessage::IceCandidate(ice)),
             WebRTCSetupCBMessage::Connection(conn) => {
                 self.logger.info(&format!(
                     "Connected {}",
@@ -118,29 +199,74 @@ impl ConnectionState {
                         log.error(&format!("Couldn't send WebRTCMessage to node: {}", e));
                     }
                 }));
+                let sender = self.input_tx.clone();
+                let log = self.logger.clone();
+                conn.set_cb_state(Box::new(move |ev| {
+                    if let Err(e) = sender.send(CSInput::WebRTCEvent(ev)) {
+                        log.error(&format!("Couldn't send WebRTCEvent: {}", e));
+                    }
+                }));
                 self.connected = Some(conn);
-                self.state = CSEnum::Connected;
-                self.output_tx
-                    .send(CSOutput::State(self.state.clone(), None))
-                    .map_err(|e| e.to_string())?;
-                Ok(())
+                self.set_state(CSEnum::Connected)?;
+                self.flush_queue()
+            }
+            WebRTCSetupCBMessage::Failed(reason) => {
+                if self.state != CSEnum::Setup {
+                    return Ok(());
+                }
+                self.logger.warn(&format!(
+                    "Setup {} failed: {}",
+                    if self.remote { "incoming" } else { "outgoing" },
+                    reason
+                ));
+                self.reset()
             }
         }
     }
 
+    /// Treats changes of an established connection. If the connection cannot
+    /// be used anymore, it is reset to Idle.
+    fn web_rtc_event(&mut self, ev: WebRTCConnectionEvent) -> Result<(), CSError> {
+        if !ev.is_fatal() {
+            self.logger
+                .warn(&format!("Connection is unstable: {:?}", ev));
+            return Ok(());
+        }
+        if self.state == CSEnum::Idle {
+            return Ok(());
+        }
+        self.logger.warn(&format!(
+            "Connection {} dropped: {:?}",
+            if self.remote { "incoming" } else { "outgoing" },
+            ev
+        ));
+        self.reset()
+    }
+
+    /// Closes the current connection and goes back to Idle.
+    fn reset(&mut self) -> Result<(), CSError> {
+        if let Some(conn) = self.connected.take() {
+            conn.close();
+        }
+        if let Some(mut setup) = self.setup.take() {
+            setup.close();
+        }
+        self.set_state(CSEnum::Idle)
+    }
+
     /// Returns the state of the connection, if available.
-    async fn get_state(&self) -> Result<(), String> {
+    async fn get_state(&self) -> Result<(), CSError> {
         let stat = match &self.state {
             CSEnum::Connected => Some(self.connected.as_ref().unwrap().get_state().await?),
             _ => None,
         };
-        self.output_tx
-            .send(CSOutput::State(self.state.clone(), stat))
-            .map_err(|e| e.to_string())
+        Ok(self
+            .output_tx
+            .send(CSOutput::State(self.state.clone(), stat))?)
     }
 
     /// Process message from websocket connection to setup an 'incoming' webrtc connection.
-    async fn process_peer_message(&mut self, pi_message: PeerMessage) -> Result<(), String> {
+    async fn process_peer_message(&mut self, pi_message: PeerMessage) -> Result<(), CSError> {
         match &self.state {
             CSEnum::Idle => {
                 if (matches!(pi_message, PeerMessage::Offer { .. }) && self.remote)
@@ -149,12 +275,7 @@ impl ConnectionState {
                     self.setup_new_connection().await?;
                     self.setup_peer_message(pi_message).await?;
                 } else {
-                    return Err(format!(
-                        "Wrong PeerMessage {:?} for new connection with remote = {} in {:?}",
-                        pi_message,
-                        self.remote,
-                        Backtrace::new()
-                    ));
+                    return Err(Self::wrong_message(self.remote, pi_message));
                 }
             }
             _ => self.setup_peer_message(pi_message).await?,
@@ -163,40 +284,76 @@ impl ConnectionState {
     }
 
     /// Sends the message to the remote end.
-    async fn send(&mut self, msg: String) -> Result<(), String> {
+    async fn send(&mut self, msg: String) -> Result<(), CSError> {
         match self.state {
             CSEnum::Idle => {
                 self.process_peer_message(PeerMessage::Init).await?;
             }
             CSEnum::Connected => {
-                if let Err(e) = self.connected.as_ref().unwrap().send(msg) {
-                    self.logger.error(&format!(
-                        "Couldn't send over webrtc, resetting connection: {}",
-                        e
-                    ));
-                    self.state = CSEnum::Idle;
-                    self.output_tx
-                        .send(CSOutput::State(self.state.clone(), None))
-                        .map_err(|e| e.to_string())?;
-                } else {
+                if self.send_connected(msg)? {
                     self.get_state().await?;
                 }
                 return Ok(());
             }
             _ => {}
         }
-        self.send_queue.push(msg);
+        self.queue_msg(msg)
+    }
+
+    /// Sends the message over the established connection. If the sending fails, the
+    /// connection is reset and the message is put back in the queue.
+    /// Returns true if the message has been sent.
+    fn send_connected(&mut self, msg: String) -> Result<bool, CSError> {
+        if let Err(e) = self.connected.as_ref().unwrap().send(msg.clone()) {
+            self.logger.error(&format!(
+                "Couldn't send over webrtc, resetting connection: {}",
+                e
+            ));
+            self.send_queue.push_front(msg);
+            self.reset()?;
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    /// Puts a message in the queue, to be sent once the connection is set up.
+    /// If the queue is full, the oldest message is dropped and sent back to the
+    /// parent.
+    fn queue_msg(&mut self, msg: String) -> Result<(), CSError> {
+        self.send_queue.push_back(msg);
+        while self.send_queue.len() > SEND_QUEUE_MAX {
+            if let Some(dropped) = self.send_queue.pop_front() {
+                self.output_tx.send(CSOutput::Dropped(dropped))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes all queued messages and returns them, oldest first.
+    pub fn take_queue(&mut self) -> Vec<String> {
+        self.send_queue.drain(..).collect()
+    }
+
+    /// Sends all queued messages in order. Stops if the connection fails, keeping
+    /// the unsent messages in the queue.
+    fn flush_queue(&mut self) -> Result<(), CSError> {
+        while let Some(msg) = self.send_queue.pop_front() {
+            if !self.send_connected(msg)? {
+                break;
+            }
+        }
         Ok(())
     }
 
     /// Sets up a new connection and sets up a callback for ICE messages and completeion of
     /// connection setup.
-    async fn setup_new_connection(&mut self) -> Result<(), String> {
+    async fn setup_new_connection(&mut self) -> Result<(), CSError> {
         let state = match self.remote {
             true => WebRTCConnectionState::Follower,
             false => WebRTCConnectionState::Initializer,
         };
-        let mut conn = self.web_rtc.lock().unwrap()(state)?;
+        let ice_servers = self.ice_servers.lock().unwrap().clone();
+        let mut conn = self.web_rtc.lock().unwrap()(state, &ice_servers)?;
         let sender = self.input_tx.clone();
         let log = self.logger.clone();
         conn.set_callback(Box::new(move |msg| {
@@ -205,46 +362,93 @@ impl ConnectionState {
             }
         }))
         .await;
-        self.state = CSEnum::Setup;
-        self.output_tx
-            .send(CSOutput::State(self.state.clone(), None))
-            .map_err(|e| e.to_string())?;
         self.setup = Some(conn);
+        self.set_state(CSEnum::Setup)?;
         Ok(())
     }
 
-    async fn setup_peer_message(&mut self, pi_message: PeerMessage) -> Result<(), String> {
+    fn wrong_message(remote: bool, message: PeerMessage) -> CSError {
+        let state = match remote {
+            true => WebRTCConnectionState::Follower,
+            false => WebRTCConnectionState::Initializer,
+        };
+        CSError::WrongMessage { message, state }
+    }
+
+    async fn setup_peer_message(&mut self, pi_message: PeerMessage) -> Result<(), CSError> {
         let setup = self.setup.as_mut().unwrap();
-        match pi_message {
+        let reply = match pi_message {
             PeerMessage::Init => {
                 if self.remote {
-                    return Err("Only Initializer can initialize".into());
+                    return Err(Self::wrong_message(self.remote, PeerMessage::Init));
                 }
-                let offer = setup.make_offer().await?;
-                self.output_tx
-                    .send(CSOutput::WebSocket(PeerMessage::Offer(offer)))
-                    .map_err(|e| e.to_string())?;
+                Some(PeerMessage::Offer(setup.make_offer().await?))
             }
             PeerMessage::Offer(offer) => {
                 if !self.remote {
-                    return Err("Only follower can treat offer".into());
+                    return Err(Self::wrong_message(self.remote, PeerMessage::Offer(offer)));
                 }
-                let answer = setup.make_answer(offer).await?;
-                self.output_tx
-                    .send(CSOutput::WebSocket(PeerMessage::Answer(answer)))
-                    .map_err(|e| e.to_string())?;
+                Some(PeerMessage::Answer(setup.make_answer(offer).await?))
             }
             PeerMessage::Answer(answer) => {
                 if self.remote {
-                    return Err("Only initializer can treat answer".into());
+                    return Err(Self::wrong_message(
+                        self.remote,
+                        PeerMessage::Answer(answer),
+                    ));
                 }
                 setup.use_answer(answer).await?;
+                None
             }
             PeerMessage::IceCandidate(ice) => {
                 setup.wait_gathering().await?;
                 setup.ice_put(ice).await?;
+                None
             }
+        };
+        if let Some(msg) = reply {
+            self.send_peer(msg)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{node::types::now, simul::SimulLogger};
+    use std::rc::Rc;
+
+    #[test]
+    // ConnectionState takes the spawner in an Arc, though it isn't Send.
+    #[allow(clippy::arc_with_non_send_sync)]
+    fn full_queue_drops_oldest() -> Result<(), CSError> {
+        let spawner: WebRTCSpawner =
+            Box::new(|_, _| Err(SetupError::Spawn("not needed".to_string())));
+        let mut cs = ConnectionState::new(
+            true,
+            Box::new(SimulLogger::new("cs")),
+            Arc::new(Mutex::new(spawner)),
+            Arc::new(Mutex::new(vec![])),
+            Rc::new(now),
+            &Wakeup::new(),
+        )?;
+        for i in 0..=SEND_QUEUE_MAX {
+            cs.queue_msg(format!("msg{}", i))?;
         }
+        let dropped: Vec<String> = cs
+            .output_rx
+            .try_iter()
+            .filter_map(|out| match out {
+                CSOutput::Dropped(msg) => Some(msg),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(vec!["msg0"], dropped);
+        let queue = cs.take_queue();
+        assert_eq!(SEND_QUEUE_MAX, queue.len());
+        assert_eq!("msg1", queue[0]);
+        assert_eq!(format!("msg{}", SEND_QUEUE_MAX), queue[SEND_QUEUE_MAX - 1]);
         Ok(())
     }
 }
@@ -1,15 +1,21 @@
 use crate::{
     node::{
+        events::{channel, Receiver, Sender, Wakeup},
         ext_interface::Logger,
-        network::connection_state::{CSEnum, CSInput, CSOutput, ConnectionState},
+        logging::Level,
+        network::{
+            connection_state::{CSEnum, CSError, CSInput, CSOutput, ConnectionState},
+            timeline::ConnectionTimeline,
+        },
+        types::Clock,
     },
-    signal::web_rtc::{ConnectionStateMap, PeerMessage, WebRTCConnectionState},
+    signal::web_rtc::{ConnectionStateMap, IceServer, PeerMessage, WebRTCConnectionState},
 };
 
 use crate::signal::web_rtc::WebRTCSpawner;
-use std::sync::{
-    mpsc::{channel, Receiver, Sender},
-    Arc, Mutex,
+use std::{
+    rc::Rc,
+    sync::{Arc, Mutex},
 };
 
 #[derive(Debug)]
@@ -22,14 +28,25 @@ pub enum NCOutput {
     WebSocket(PeerMessage, bool),
     WebRTCMessage(String),
     State(WebRTCConnectionState, CSEnum, Option<ConnectionStateMap>),
+    Dropped(String),
+    /// A message to be sent through the signal server.
+    Relay(String),
+    /// Messages are sent through the signal server (true) or over WebRTC
+    /// again (false).
+    Relayed(bool),
 }
 
+/// While relaying, a new outgoing WebRTC connection is tried after this time.
+pub const RELAY_RETRY_MS: f64 = 60_000.;
+
 /// There might be up to two connections per remote node.
 /// This is in the case both nodes try to set up a connection at the same time.
 /// This race condition is very difficult to catch, so it's easier to just allow
 /// two connections per remote node.
 /// If a second, third, or later incoming connection from the same node happens, the previous
 /// connection is considered stale and discarded.
+/// If the setup of a connection fails and no other connection is available,
+/// the messages are relayed through the signal server, if enabled.
 pub struct NodeConnection {
     // outgoing connections are the preferred ones.
     pub outgoing: ConnectionState,
@@ -42,37 +59,67 @@ pub struct NodeConnection {
     output_tx: Sender<NCOutput>,
     input_rx: Receiver<NCInput>,
 
-    _logger: Box<dyn Logger>,
+    logger: Box<dyn Logger>,
     states: Vec<Option<ConnectionStateMap>>,
+    // last known CSEnum of the outgoing and incoming connection.
+    conn_states: Vec<CSEnum>,
+    relay_fallback: bool,
+    relayed: bool,
+    // when the last outgoing connection has been tried while relaying.
+    last_retry: f64,
+    clock: Clock,
 }
 
 impl NodeConnection {
     pub fn new(
         logger: Box<dyn Logger>,
         web_rtc: Arc<Mutex<WebRTCSpawner>>,
-    ) -> Result<NodeConnection, String> {
-        let (output_tx, output_rx) = channel::<NCOutput>();
-        let (input_tx, input_rx) = channel::<NCInput>();
+        ice_servers: Arc<Mutex<Vec<IceServer>>>,
+        relay_fallback: bool,
+        clock: Clock,
+        wakeup: &Wakeup,
+    ) -> Result<NodeConnection, CSError> {
+        let (output_tx, output_rx) = channel::<NCOutput>(wakeup);
+        let (input_tx, input_rx) = channel::<NCInput>(wakeup);
         let nc = NodeConnection {
-            outgoing: ConnectionState::new(false, logger.clone(), Arc::clone(&web_rtc))?,
-            incoming: ConnectionState::new(true, logger.clone(), Arc::clone(&web_rtc))?,
+            outgoing: ConnectionState::new(
+                false,
+                logger.with_context(logger.module(), &[("dir", "outgoing".to_string())]),
+                Arc::clone(&web_rtc),
+                Arc::clone(&ice_servers),
+                Rc::clone(&clock),
+                wakeup,
+            )?,
+            incoming: ConnectionState::new(
+                true,
+                logger.with_context(logger.module(), &[("dir", "incoming".to_string())]),
+                Arc::clone(&web_rtc),
+                ice_servers,
+                Rc::clone(&clock),
+                wakeup,
+            )?,
             output_tx,
             output_rx,
             input_tx,
             input_rx,
-            _logger: logger,
+            logger,
             states: vec![None, None],
+            conn_states: vec![CSEnum::Idle, CSEnum::Idle],
+            relay_fallback,
+            relayed: false,
+            last_retry: 0.,
+            clock,
         };
         Ok(nc)
     }
 
     /// Processes all messages waiting from the submodules, and calls the submodules to
     /// process waiting messages.
-    pub async fn process(&mut self) -> Result<(), String> {
-        self.process_connection(true, self.incoming.output_rx.try_iter().collect())
-            .await?;
-        self.process_connection(false, self.outgoing.output_rx.try_iter().collect())
-            .await?;
+    pub async fn process(&mut self) -> Result<(), CSError> {
+        let incoming = self.incoming.output_rx.try_iter().collect();
+        self.process_connection(true, incoming).await?;
+        let outgoing = self.outgoing.output_rx.try_iter().collect();
+        self.process_connection(false, outgoing).await?;
         self.process_incoming().await?;
         self.incoming.process().await?;
         self.outgoing.process().await?;
@@ -82,29 +129,51 @@ impl NodeConnection {
     /// Tries to send a message over the webrtc connection.
     /// If the connection is in setup phase, the message is queued.
     /// If the connection is idle, an error is returned.
-    pub fn send(&mut self, msg: String) -> Result<(), String> {
-        // self.logger.info("dbg: Sending to node");
+    /// If the connection is relayed, the message is sent through the signal server.
+    pub fn send(&mut self, msg: String) -> Result<(), CSError> {
+        self.logger.debug("Sending message");
+        if self.relayed && !self.is_connected() {
+            self.retry()?;
+            return Ok(self.output_tx.send(NCOutput::Relay(msg))?);
+        }
         match self.get_connection_channel() {
-            Some(chan) => chan.send(CSInput::Send(msg)).map_err(|e| e.to_string()),
-            None => self
-                .outgoing
-                .input_tx
-                .send(CSInput::Send(msg))
-                .map_err(|e| e.to_string()),
+            Some(chan) => chan.send(CSInput::Send(msg))?,
+            None => self.outgoing.input_tx.send(CSInput::Send(msg))?,
         }
+        Ok(())
     }
 
     /// Return the stats of outgoing / incoming connection. Every time this method
     /// is called, a new state is requested. But the returned state is the last one
     /// received.
-    pub async fn get_stats(&self) -> Result<Vec<Option<ConnectionStateMap>>, String> {
+    pub async fn get_stats(&self) -> Result<Vec<Option<ConnectionStateMap>>, CSError> {
         for chan in &[&self.incoming.input_tx, &self.outgoing.input_tx] {
-            chan.send(CSInput::GetState).map_err(|e| e.to_string())?;
+            chan.send(CSInput::GetState)?;
         }
         Ok(self.states.clone())
     }
 
-    async fn process_incoming(&mut self) -> Result<(), String> {
+    /// Returns true if messages to this node are sent through the signal server.
+    pub fn is_relayed(&self) -> bool {
+        self.relayed
+    }
+
+    /// Called when the remote node relayed a message, which means it couldn't
+    /// connect either. Unless a connection is available, the answers are
+    /// relayed, too.
+    pub fn relayed_by_peer(&mut self) -> Result<(), CSError> {
+        self.relay_queued()
+    }
+
+    /// Returns the recorded events of the outgoing and incoming connection.
+    pub fn timeline(&self) -> ConnectionTimeline {
+        ConnectionTimeline {
+            outgoing: self.outgoing.timeline(),
+            incoming: self.incoming.timeline(),
+        }
+    }
+
+    async fn process_incoming(&mut self) -> Result<(), CSError> {
         let msgs: Vec<NCInput> = self.input_rx.try_iter().collect();
         for msg in msgs {
             match msg {
@@ -114,12 +183,13 @@ impl NodeConnection {
         Ok(())
     }
 
-    async fn process_ws(&mut self, ws_msg: PeerMessage, remote: bool) -> Result<(), String> {
+    async fn process_ws(&mut self, ws_msg: PeerMessage, remote: bool) -> Result<(), CSError> {
         let msg = CSInput::ProcessPeerMessage(ws_msg);
         match remote {
-            false => self.outgoing.input_tx.send(msg).map_err(|e| e.to_string()),
-            true => self.incoming.input_tx.send(msg).map_err(|e| e.to_string()),
+            false => self.outgoing.input_tx.send(msg)?,
+            true => self.incoming.input_tx.send(msg)?,
         }
+        Ok(())
     }
 
     /// Processes incoming messages from a connection.
@@ -127,34 +197,106 @@ impl NodeConnection {
         &mut self,
         remote: bool,
         cmds: Vec<CSOutput>,
-    ) -> Result<(), String> {
+    ) -> Result<(), CSError> {
         for cmd in cmds {
-            // self.logger.info(&format!("dbg: NodeConnect::process {:?}", cmd));
+            self.logger.log(
+                Level::Trace,
+                "Output from connection state",
+                &[
+                    ("remote", remote.to_string()),
+                    ("output", format!("{:?}", cmd)),
+                ],
+            );
             match cmd {
                 CSOutput::State(cs, stat) => {
-                    let dir = if remote {
-                        self.states[1] = stat;
-                        WebRTCConnectionState::Follower
+                    let (dir, index) = if remote {
+                        (WebRTCConnectionState::Follower, 1)
                     } else {
-                        self.states[0] = stat;
-                        WebRTCConnectionState::Initializer
+                        (WebRTCConnectionState::Initializer, 0)
                     };
-                    self.output_tx.send(NCOutput::State(dir, cs, stat))
-                    .map_err(|e| e.to_string())?;
+                    self.states[index] = stat;
+                    let dropped =
+                        self.conn_states[index] == CSEnum::Connected && cs == CSEnum::Idle;
+                    let failed = self.conn_states[index] == CSEnum::Setup && cs == CSEnum::Idle;
+                    self.conn_states[index] = cs.clone();
+                    self.output_tx
+                        .send(NCOutput::State(dir, cs.clone(), stat))?;
+                    if dropped {
+                        self.reconnect()?;
+                    }
+                    if failed {
+                        self.relay_queued()?;
+                    }
+                    if cs == CSEnum::Connected {
+                        self.set_relayed(false)?;
+                    }
+                }
+                CSOutput::WebSocket(msg) => {
+                    self.output_tx.send(NCOutput::WebSocket(msg, remote))?
                 }
-                CSOutput::WebSocket(msg) => self
-                    .output_tx
-                    .send(NCOutput::WebSocket(msg, remote))
-                    .map_err(|e| e.to_string())?,
-                CSOutput::WebRTCMessage(msg) => self
-                    .output_tx
-                    .send(NCOutput::WebRTCMessage(msg))
-                    .map_err(|e| e.to_string())?,
+                CSOutput::WebRTCMessage(msg) => {
+                    self.output_tx.send(NCOutput::WebRTCMessage(msg))?
+                }
+                CSOutput::Dropped(msg) => self.output_tx.send(NCOutput::Dropped(msg))?,
             }
         }
         Ok(())
     }
 
+    /// Called when one of the connections dropped. If the outgoing connection
+    /// is not usable anymore, a new outgoing connection is set up.
+    fn reconnect(&mut self) -> Result<(), CSError> {
+        if self.outgoing.state != CSEnum::Idle {
+            return Ok(());
+        }
+        Ok(self
+            .outgoing
+            .input_tx
+            .send(CSInput::ProcessPeerMessage(PeerMessage::Init))?)
+    }
+
+    /// Sends the queued messages of both connections through the signal server,
+    /// if relaying is enabled and no connection is available.
+    fn relay_queued(&mut self) -> Result<(), CSError> {
+        if !self.relay_fallback || self.is_connected() {
+            return Ok(());
+        }
+        if !self.relayed {
+            self.last_retry = (self.clock)();
+        }
+        self.set_relayed(true)?;
+        let mut queued = self.outgoing.take_queue();
+        queued.append(&mut self.incoming.take_queue());
+        for msg in queued {
+            self.output_tx.send(NCOutput::Relay(msg))?;
+        }
+        Ok(())
+    }
+
+    fn set_relayed(&mut self, relayed: bool) -> Result<(), CSError> {
+        if self.relayed != relayed {
+            self.logger
+                .info(&format!("Relaying through signal server: {}", relayed));
+            self.relayed = relayed;
+            self.output_tx.send(NCOutput::Relayed(relayed))?;
+        }
+        Ok(())
+    }
+
+    /// Tries a new outgoing connection from time to time while relaying.
+    fn retry(&mut self) -> Result<(), CSError> {
+        let time = (self.clock)();
+        if self.outgoing.state != CSEnum::Idle || time - self.last_retry < RELAY_RETRY_MS {
+            return Ok(());
+        }
+        self.last_retry = time;
+        self.reconnect()
+    }
+
+    fn is_connected(&self) -> bool {
+        self.outgoing.state == CSEnum::Connected || self.incoming.state == CSEnum::Connected
+    }
+
     /// Return a connected direction, preferably outgoing.
     /// Else if one of the connections is setup, return setup (incoming first).
     /// If all else fails, return None.
@@ -0,0 +1,330 @@
+//! Authenticates and encrypts the connections of the direct transports using
+//! the Noise protocol. Both nodes prove that they own their key with the XX
+//! handshake: the static key is the X25519 form of the ed25519 key of the
+//! node, and the ed25519 public key is sent as payload together with it. So
+//! the connection is bound to the public key of the remote node, and a node
+//! dialing another one checks that it reached the expected node.
+//! The payload also holds the ID of the network of the node, and both nodes
+//! close the connection if it is not their own.
+//!
+//! Everything works on bytes, so the same code is used with blocking sockets
+//! and with the callbacks of node.js. On the wire, every handshake message and
+//! every encrypted message is a frame starting with its length as a 4-byte
+//! big-endian number.
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use snow::{Builder, HandshakeState, StatelessTransportState};
+use std::{convert::TryInto, sync::Arc};
+use thiserror::Error;
+
+use crate::node::types::U256;
+
+const PATTERN: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+
+/// The biggest message Noise encrypts at once. Longer messages are split.
+const MAX_NOISE: usize = 65535;
+
+const TAG_LEN: usize = 16;
+
+/// Frames bigger than this close the connection.
+pub const MAX_FRAME: usize = 16 * 1024 * 1024;
+
+#[derive(Error, Debug)]
+pub enum NoiseError {
+    #[error(transparent)]
+    Noise(#[from] snow::Error),
+    #[error("remote key is invalid: {0}")]
+    Key(String),
+    #[error("remote node is in network {0}")]
+    Network(U256),
+    #[error("invalid frame: {0}")]
+    Frame(String),
+    #[error(transparent)]
+    Utf8(#[from] std::string::FromUtf8Error),
+}
+
+/// The handshake of a new connection. The node calls `write` and sends the
+/// result while `is_my_turn` returns true, else it passes the received frames
+/// to `read`, until `is_finished` returns true.
+pub struct Handshake {
+    state: HandshakeState,
+    public: U256,
+    network: U256,
+    // the node the initiator dialed
+    expected: Option<U256>,
+    remote: Option<U256>,
+    messages: usize,
+}
+
+impl Handshake {
+    /// Starts the handshake with the node `remote` of the network `network`.
+    /// It fails if another node answers.
+    pub fn initiate(
+        secret: &SigningKey,
+        network: &U256,
+        remote: &U256,
+    ) -> Result<Handshake, NoiseError> {
+        Handshake::new(secret, network, Some(remote.clone()))
+    }
+
+    /// Waits for the handshake of a node of the network `network` connecting
+    /// to this one.
+    pub fn respond(secret: &SigningKey, network: &U256) -> Result<Handshake, NoiseError> {
+        Handshake::new(secret, network, None)
+    }
+
+    fn new(
+        secret: &SigningKey,
+        network: &U256,
+        expected: Option<U256>,
+    ) -> Result<Handshake, NoiseError> {
+        let private = secret.to_scalar_bytes();
+        let builder = Builder::new(PATTERN.parse()?).local_private_key(&private);
+        let state = match expected {
+            Some(_) => builder.build_initiator()?,
+            None => builder.build_responder()?,
+        };
+        Ok(Handshake {
+            state,
+            public: secret.verifying_key().to_bytes().into(),
+            network: network.clone(),
+            expected,
+            remote: None,
+            messages: 0,
+        })
+    }
+
+    pub fn is_my_turn(&self) -> bool {
+        self.state.is_my_turn()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.state.is_handshake_finished()
+    }
+
+    /// Returns the next message of the handshake. All but the first message
+    /// hold the public key and the network of this node.
+    pub fn write(&mut self) -> Result<Vec<u8>, NoiseError> {
+        let mut identity = self.public.to_bytes().to_vec();
+        identity.extend_from_slice(&self.network.to_bytes());
+        let payload: &[u8] = match self.messages {
+            0 => &[],
+            _ => &identity,
+        };
+        let mut msg = vec![0u8; MAX_NOISE];
+        let len = self.state.write_message(payload, &mut msg)?;
+        msg.truncate(len);
+        self.messages += 1;
+        Ok(msg)
+    }
+
+    /// Reads a message of the remote node. If it holds its public key, the key
+    /// is checked against the static key of the handshake, and the network
+    /// against the one of this node.
+    pub fn read(&mut self, msg: &[u8]) -> Result<(), NoiseError> {
+        let mut payload = vec![0u8; MAX_NOISE];
+        let len = self.state.read_message(msg, &mut payload)?;
+        self.messages += 1;
+        if len > 0 {
+            self.remote = Some(self.check_remote(&payload[..len])?);
+        }
+        Ok(())
+    }
+
+    fn check_remote(&self, payload: &[u8]) -> Result<U256, NoiseError> {
+        if payload.len() != 64 {
+            return Err(NoiseError::Key(format!("{} bytes", payload.len())));
+        }
+        let network: [u8; 32] = payload[32..].try_into().unwrap();
+        let network = U256::from(network);
+        if network != self.network {
+            return Err(NoiseError::Network(network));
+        }
+        let bytes: [u8; 32] = payload[..32].try_into().unwrap();
+        let key = VerifyingKey::from_bytes(&bytes).map_err(|e| NoiseError::Key(e.to_string()))?;
+        if self.state.get_remote_static() != Some(&key.to_montgomery().to_bytes()[..]) {
+            return Err(NoiseError::Key("doesn't match the static key".to_string()));
+        }
+        let remote = U256::from(bytes);
+        match &self.expected {
+            Some(expected) if expected != &remote => Err(NoiseError::Key(format!(
+                "expected {}, got {}",
+                expected, remote
+            ))),
+            _ => Ok(remote),
+        }
+    }
+
+    /// Returns the authenticated public key of the remote node, and the two
+    /// halves of the encrypted connection.
+    pub fn finish(self) -> Result<(U256, NoiseWriter, NoiseReader), NoiseError> {
+        let remote = self
+            .remote
+            .ok_or_else(|| NoiseError::Key("not received".to_string()))?;
+        let state = Arc::new(self.state.into_stateless_transport_mode()?);
+        Ok((
+            remote,
+            NoiseWriter {
+                state: Arc::clone(&state),
+                nonce: 0,
+            },
+            NoiseReader { state, nonce: 0 },
+        ))
+    }
+}
+
+/// Encrypts the messages sent over a connection.
+pub struct NoiseWriter {
+    state: Arc<StatelessTransportState>,
+    nonce: u64,
+}
+
+impl NoiseWriter {
+    /// Encrypts a message, splitting it in parts Noise can handle. The result
+    /// is sent as one frame.
+    pub fn encrypt(&mut self, msg: &str) -> Result<Vec<u8>, NoiseError> {
+        let mut out = vec![];
+        let parts: Vec<&[u8]> = match msg.len() {
+            0 => vec![&[]],
+            _ => msg.as_bytes().chunks(MAX_NOISE - TAG_LEN).collect(),
+        };
+        for part in parts {
+            let start = out.len();
+            out.resize(start + part.len() + TAG_LEN, 0);
+            self.state
+                .write_message(self.nonce, part, &mut out[start..])?;
+            self.nonce += 1;
+        }
+        Ok(out)
+    }
+}
+
+/// Decrypts the messages received over a connection.
+pub struct NoiseReader {
+    state: Arc<StatelessTransportState>,
+    nonce: u64,
+}
+
+impl NoiseReader {
+    /// Decrypts a frame created by `NoiseWriter::encrypt`.
+    pub fn decrypt(&mut self, frame: &[u8]) -> Result<String, NoiseError> {
+        if frame.is_empty() {
+            return Err(NoiseError::Frame("empty".to_string()));
+        }
+        let mut msg = vec![];
+        for part in frame.chunks(MAX_NOISE) {
+            let start = msg.len();
+            msg.resize(start + part.len(), 0);
+            let len = self
+                .state
+                .read_message(self.nonce, part, &mut msg[start..])?;
+            msg.truncate(start + len);
+            self.nonce += 1;
+        }
+        Ok(String::from_utf8(msg)?)
+    }
+}
+
+/// Prepends the length to the data.
+pub fn frame(data: &[u8]) -> Vec<u8> {
+    let mut out = (data.len() as u32).to_be_bytes().to_vec();
+    out.extend_from_slice(data);
+    out
+}
+
+/// Collects the bytes received in arbitrary pieces and returns the frames
+/// once they are complete.
+#[derive(Default)]
+pub struct Frames(Vec<u8>);
+
+impl Frames {
+    pub fn push(&mut self, data: &[u8]) {
+        self.0.extend_from_slice(data);
+    }
+
+    /// Returns the next complete frame, if any.
+    pub fn next_frame(&mut self) -> Result<Option<Vec<u8>>, NoiseError> {
+        if self.0.len() < 4 {
+            return Ok(None);
+        }
+        let len = frame_len(self.0[..4].try_into().unwrap())?;
+        if self.0.len() < 4 + len {
+            return Ok(None);
+        }
+        let frame = self.0[4..4 + len].to_vec();
+        self.0.drain(..4 + len);
+        Ok(Some(frame))
+    }
+}
+
+/// Returns the length of a frame from its first four bytes.
+pub fn frame_len(bytes: [u8; 4]) -> Result<usize, NoiseError> {
+    let len = u32::from_be_bytes(bytes) as usize;
+    if len > MAX_FRAME {
+        return Err(NoiseError::Frame(format!("{} bytes is too big", len)));
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::random;
+
+    fn handshake(init: &mut Handshake, resp: &mut Handshake) -> Result<(), NoiseError> {
+        while !init.is_finished() || !resp.is_finished() {
+            let (from, to) = match init.is_my_turn() {
+                true => (&mut *init, &mut *resp),
+                false => (&mut *resp, &mut *init),
+            };
+            let mut frames = Frames::default();
+            frames.push(&frame(&from.write()?));
+            to.read(&frames.next_frame()?.unwrap())?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn authenticated() -> Result<(), NoiseError> {
+        let (a, b) = (
+            SigningKey::from_bytes(&random()),
+            SigningKey::from_bytes(&random()),
+        );
+        let id_a: U256 = a.verifying_key().to_bytes().into();
+        let id_b: U256 = b.verifying_key().to_bytes().into();
+        let network = U256::rnd();
+        let mut init = Handshake::initiate(&a, &network, &id_b)?;
+        let mut resp = Handshake::respond(&b, &network)?;
+        handshake(&mut init, &mut resp)?;
+        let (remote_b, mut writer, _) = init.finish()?;
+        let (remote_a, _, mut reader) = resp.finish()?;
+        assert_eq!(id_b, remote_b);
+        assert_eq!(id_a, remote_a);
+
+        let long = "x".repeat(3 * MAX_NOISE);
+        for msg in &["", "ping", &long] {
+            assert_eq!(*msg, reader.decrypt(&writer.encrypt(msg)?)?);
+        }
+        // A replayed message is rejected.
+        let msg = writer.encrypt("once")?;
+        reader.decrypt(&msg)?;
+        assert!(reader.decrypt(&msg).is_err());
+
+        // A node answering in place of another one is rejected.
+        let c = SigningKey::from_bytes(&random());
+        let mut init = Handshake::initiate(&a, &network, &id_b)?;
+        let mut resp = Handshake::respond(&c, &network)?;
+        assert!(matches!(
+            handshake(&mut init, &mut resp),
+            Err(NoiseError::Key(_))
+        ));
+
+        // A node of another network is refused.
+        let mut init = Handshake::initiate(&a, &U256::rnd(), &id_b)?;
+        let mut resp = Handshake::respond(&b, &network)?;
+        assert!(matches!(
+            handshake(&mut init, &mut resp),
+            Err(NoiseError::Network(_))
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,438 @@
+//! A transport over TCP for nodes with a public address, like server nodes,
+//! which don't need the signal server to connect to each other.
+//! The connections are authenticated and encrypted with Noise, see `noise`,
+//! so a node only accepts messages from the node whose key it checked, and
+//! only from nodes of its own network.
+//! Every connection has its own thread reading the frames, which wakes up
+//! the node for every message.
+use async_trait::async_trait;
+use ed25519_dalek::SigningKey;
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    node::{
+        config::NodeInfo,
+        events::{channel, Receiver, Sender, Wakeup},
+        ext_interface::Logger,
+        network::{
+            connection_state::CSEnum,
+            noise::{frame_len, Handshake, NoiseError, NoiseReader, NoiseWriter},
+            transport::{Transport, TransportError, TransportKind, TransportOutput},
+        },
+        types::U256,
+    },
+    signal::web_rtc::WebRTCConnectionState,
+};
+
+/// How long connecting and the handshake may take.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Events from the threads handling the sockets.
+enum TcpEvent {
+    Connected(U256, Link, WebRTCConnectionState),
+    Message(U256, String),
+    Closed(U256, WebRTCConnectionState, String),
+}
+
+/// The sending half of an authenticated connection.
+struct Link {
+    stream: TcpStream,
+    writer: NoiseWriter,
+}
+
+impl Link {
+    fn send(&mut self, msg: &str) -> io::Result<()> {
+        let data = self.writer.encrypt(msg).map_err(invalid)?;
+        write_frame(&mut self.stream, &data)
+    }
+}
+
+pub struct TcpTransport {
+    secret: SigningKey,
+    network: U256,
+    // the address the listener binds to
+    listen: String,
+    // the address advertised to the other nodes
+    address: Option<String>,
+    links: HashMap<U256, Link>,
+    // messages waiting for the connection to be set up
+    queues: HashMap<U256, Vec<String>>,
+    addresses: HashMap<U256, String>,
+    events_tx: Sender<TcpEvent>,
+    events_rx: Receiver<TcpEvent>,
+    logger: Box<dyn Logger>,
+}
+
+impl TcpTransport {
+    /// Creates a transport that listens on `listen`, e.g., "0.0.0.0:8766".
+    /// The other nodes connect to `address`, which must hold the public IP
+    /// or hostname of this node, e.g., "node.example.org:8766".
+    /// If no address is given, the address of the listener is used, which is
+    /// enough for nodes on the same host.
+    /// The secret key of the node authenticates the connections, which are
+    /// only accepted from and to nodes of the network `network`.
+    pub fn new(
+        logger: Box<dyn Logger>,
+        secret: SigningKey,
+        network: U256,
+        listen: &str,
+        address: Option<&str>,
+        wakeup: &Wakeup,
+    ) -> TcpTransport {
+        let (events_tx, events_rx) = channel(wakeup);
+        TcpTransport {
+            secret,
+            network,
+            listen: listen.to_string(),
+            address: address.map(|a| a.to_string()),
+            links: HashMap::new(),
+            queues: HashMap::new(),
+            addresses: HashMap::new(),
+            events_tx,
+            events_rx,
+            logger,
+        }
+    }
+
+    /// Starts a thread connecting to the node. Until it is connected, the
+    /// messages are queued.
+    fn dial(&mut self, id: U256, addr: String) {
+        self.queues.entry(id.clone()).or_default();
+        let secret = self.secret.clone();
+        let network = self.network.clone();
+        let events = self.events_tx.clone();
+        thread::spawn(move || {
+            if let Err(e) = handle_outgoing(&addr, &secret, &network, &id, &events) {
+                let _ = events.send(TcpEvent::Closed(
+                    id,
+                    WebRTCConnectionState::Initializer,
+                    e.to_string(),
+                ));
+            }
+        });
+    }
+
+    /// Sends all queued messages of the node.
+    fn flush(&mut self, node: &U256) {
+        for msg in self.queues.remove(node).unwrap_or_default() {
+            if let Err(e) = self.send(node, msg) {
+                self.logger
+                    .warn(&format!("Couldn't send queued message: {}", e));
+            }
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Transport for TcpTransport {
+    fn kind(&self) -> TransportKind {
+        TransportKind::TCP
+    }
+
+    fn accept(&mut self) -> Result<Option<String>, TransportError> {
+        let listener =
+            TcpListener::bind(&self.listen).map_err(|e| TransportError::Accept(e.to_string()))?;
+        let address = match &self.address {
+            Some(address) => address.clone(),
+            None => listener
+                .local_addr()
+                .map_err(|e| TransportError::Accept(e.to_string()))?
+                .to_string(),
+        };
+        let secret = self.secret.clone();
+        let network = self.network.clone();
+        let events = self.events_tx.clone();
+        let logger = self.logger.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let secret = secret.clone();
+                let network = network.clone();
+                let events = events.clone();
+                let logger = logger.clone();
+                match stream {
+                    Ok(stream) => {
+                        thread::spawn(move || {
+                            if let Err(e) = handle_incoming(stream, &secret, &network, &events) {
+                                logger.warn(&format!("Incoming connection failed: {}", e));
+                            }
+                        });
+                    }
+                    Err(e) => logger.warn(&format!("Couldn't accept connection: {}", e)),
+                }
+            }
+        });
+        Ok(Some(address))
+    }
+
+    /// Connects to the address the node advertises in its NodeInfo.
+    fn connect(&mut self, node: &NodeInfo) -> Result<(), TransportError> {
+        if self.links.contains_key(&node.public) {
+            return Ok(());
+        }
+        let address = node
+            .address(TransportKind::TCP)
+            .ok_or_else(|| TransportError::Connect(format!("{} has no address", node.public)))?
+            .to_string();
+        self.addresses.insert(node.public.clone(), address.clone());
+        self.dial(node.public.clone(), address);
+        Ok(())
+    }
+
+    /// Sends the message if the node is connected, else queues it and
+    /// connects again if the address of the node is known.
+    fn send(&mut self, node: &U256, msg: String) -> Result<(), TransportError> {
+        if let Some(link) = self.links.get_mut(node) {
+            if let Err(e) = link.send(&msg) {
+                self.links.remove(node);
+                return Err(TransportError::Send(e.to_string()));
+            }
+            return Ok(());
+        }
+        if !self.queues.contains_key(node) {
+            let addr = self
+                .addresses
+                .get(node)
+                .cloned()
+                .ok_or_else(|| TransportError::Send(format!("{} is not connected", node)))?;
+            self.dial(node.clone(), addr);
+        }
+        self.queues.get_mut(node).unwrap().push(msg);
+        Ok(())
+    }
+
+    async fn receive(&mut self) -> Result<Vec<TransportOutput>, TransportError> {
+        let mut outputs = vec![];
+        let events: Vec<TcpEvent> = self.events_rx.try_iter().collect();
+        for event in events {
+            match event {
+                TcpEvent::Connected(id, link, dir) => {
+                    self.logger.info(&format!("Connected {:?} to {}", dir, id));
+                    self.links.insert(id.clone(), link);
+                    self.flush(&id);
+                    outputs.push(TransportOutput::State(id, dir, CSEnum::Connected, None));
+                }
+                TcpEvent::Message(id, msg) => outputs.push(TransportOutput::Message(id, msg)),
+                TcpEvent::Closed(id, dir, reason) => {
+                    self.logger
+                        .warn(&format!("Connection to {} closed: {}", id, reason));
+                    self.links.remove(&id);
+                    for msg in self.queues.remove(&id).unwrap_or_default() {
+                        outputs.push(TransportOutput::Dropped(id.clone(), msg));
+                    }
+                    outputs.push(TransportOutput::State(id, dir, CSEnum::Idle, None));
+                }
+            }
+        }
+        Ok(outputs)
+    }
+}
+
+/// Authenticates the remote node and checks its network, then passes all
+/// messages on.
+fn handle_incoming(
+    mut stream: TcpStream,
+    secret: &SigningKey,
+    network: &U256,
+    events: &Sender<TcpEvent>,
+) -> io::Result<()> {
+    let handshake = Handshake::respond(secret, network).map_err(invalid)?;
+    let (id, writer, reader) = run_handshake(&mut stream, handshake)?;
+    let link = Link {
+        stream: stream.try_clone()?,
+        writer,
+    };
+    let dir = WebRTCConnectionState::Follower;
+    send_event(events, TcpEvent::Connected(id.clone(), link, dir))?;
+    read_messages(stream, reader, id, dir, events)
+}
+
+/// Connects to the node and checks its key, then passes all messages on.
+fn handle_outgoing(
+    addr: &str,
+    secret: &SigningKey,
+    network: &U256,
+    id: &U256,
+    events: &Sender<TcpEvent>,
+) -> io::Result<()> {
+    let sock_addr = addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, addr.to_string()))?;
+    let mut stream = TcpStream::connect_timeout(&sock_addr, CONNECT_TIMEOUT)?;
+    let handshake = Handshake::initiate(secret, network, id).map_err(invalid)?;
+    let (_, writer, reader) = run_handshake(&mut stream, handshake)?;
+    let link = Link {
+        stream: stream.try_clone()?,
+        writer,
+    };
+    let dir = WebRTCConnectionState::Initializer;
+    send_event(events, TcpEvent::Connected(id.clone(), link, dir))?;
+    read_messages(stream, reader, id.clone(), dir, events)
+}
+
+/// Exchanges the handshake messages, which must not take longer than
+/// CONNECT_TIMEOUT.
+fn run_handshake(
+    stream: &mut TcpStream,
+    mut handshake: Handshake,
+) -> io::Result<(U256, NoiseWriter, NoiseReader)> {
+    stream.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+    while !handshake.is_finished() {
+        if handshake.is_my_turn() {
+            write_frame(stream, &handshake.write().map_err(invalid)?)?;
+        } else {
+            handshake.read(&read_frame(stream)?).map_err(invalid)?;
+        }
+    }
+    stream.set_read_timeout(None)?;
+    handshake.finish().map_err(invalid)
+}
+
+fn read_messages(
+    mut stream: TcpStream,
+    mut reader: NoiseReader,
+    id: U256,
+    dir: WebRTCConnectionState,
+    events: &Sender<TcpEvent>,
+) -> io::Result<()> {
+    loop {
+        let msg = read_frame(&mut stream).and_then(|f| reader.decrypt(&f).map_err(invalid));
+        match msg {
+            Ok(msg) => send_event(events, TcpEvent::Message(id.clone(), msg))?,
+            Err(e) => return send_event(events, TcpEvent::Closed(id, dir, e.to_string())),
+        }
+    }
+}
+
+fn invalid(e: NoiseError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+fn send_event(events: &Sender<TcpEvent>, event: TcpEvent) -> io::Result<()> {
+    events
+        .send(event)
+        .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e))
+}
+
+fn write_frame(stream: &mut TcpStream, data: &[u8]) -> io::Result<()> {
+    stream.write_all(&(data.len() as u32).to_be_bytes())?;
+    stream.write_all(data)?;
+    stream.flush()
+}
+
+fn read_frame(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len)?;
+    let mut buf = vec![0u8; frame_len(len).map_err(invalid)?];
+    stream.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simul::SimulLogger;
+    use futures::executor::block_on;
+    use rand::random;
+    use std::{
+        env,
+        error::Error,
+        process::Command,
+        time::{Duration, Instant},
+    };
+
+    const PEER_ADDR: &str = "FLEDGER_TCP_PEER_ADDR";
+    const PEER_SECRET: &str = "FLEDGER_TCP_PEER_SECRET";
+
+    fn network() -> U256 {
+        U256::from_sha256(b"tcp test")
+    }
+
+    /// Run by `two_processes` in a child process: sends back every message
+    /// until it gets "quit".
+    #[test]
+    #[ignore]
+    fn echo_peer() {
+        let (addr, secret) = match (env::var(PEER_ADDR), env::var(PEER_SECRET)) {
+            (Ok(addr), Ok(secret)) => (addr, serde_json::from_str(&secret).unwrap()),
+            _ => return,
+        };
+        let wakeup = Wakeup::new();
+        let logger = Box::new(SimulLogger::new("peer"));
+        let mut tcp = TcpTransport::new(
+            logger,
+            SigningKey::from_bytes(&secret),
+            network(),
+            &addr,
+            None,
+            &wakeup,
+        );
+        tcp.accept().unwrap();
+        loop {
+            block_on(wakeup.wait());
+            for output in block_on(tcp.receive()).unwrap() {
+                if let TransportOutput::Message(from, msg) = output {
+                    if msg == "quit" {
+                        return;
+                    }
+                    tcp.send(&from, msg).unwrap();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn two_processes() -> Result<(), Box<dyn Error>> {
+        let addr = TcpListener::bind("127.0.0.1:0")?.local_addr()?.to_string();
+        let secret: [u8; 32] = random();
+        let peer: U256 = SigningKey::from_bytes(&secret)
+            .verifying_key()
+            .to_bytes()
+            .into();
+        let mut child = Command::new(env::current_exe()?)
+            .args([
+                "--ignored",
+                "--exact",
+                "node::network::tcp_transport::tests::echo_peer",
+            ])
+            .env(PEER_ADDR, &addr)
+            .env(PEER_SECRET, serde_json::to_string(&secret)?)
+            .spawn()?;
+
+        let wakeup = Wakeup::new();
+        let logger = Box::new(SimulLogger::new("node"));
+        let key = SigningKey::from_bytes(&random());
+        let mut tcp = TcpTransport::new(logger, key, network(), "127.0.0.1:0", None, &wakeup);
+        let info = NodeInfo {
+            addresses: vec![format!("tcp://{}", addr)],
+            ..NodeInfo::new(peer.clone())
+        };
+        tcp.connect(&info)?;
+        tcp.send(&peer, "ping".to_string())?;
+
+        // The child might not listen yet, so the message is sent again
+        // whenever the connection fails.
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut echo = None;
+        while echo.is_none() && Instant::now() < deadline {
+            for output in block_on(tcp.receive())? {
+                match output {
+                    TransportOutput::Message(_, msg) => echo = Some(msg),
+                    TransportOutput::Dropped(_, msg) => tcp.send(&peer, msg)?,
+                    _ => {}
+                }
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        tcp.send(&peer, "quit".to_string())?;
+        assert!(child.wait()?.success());
+        assert_eq!(Some("ping".to_string()), echo);
+        Ok(())
+    }
+}
@@ -0,0 +1,99 @@
+//! Records what happens during the setup of a connection, so that it is
+//! possible to find out which step of Init -> Offer -> Answer -> IceCandidate
+//! failed. Every ConnectionState keeps its own timeline, which can be exported
+//! as JSON for a given peer.
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+use crate::node::network::connection_state::CSEnum;
+
+/// How many events are kept per connection. Older events are dropped.
+pub const TIMELINE_MAX: usize = 200;
+
+/// One step during the setup or the lifetime of a connection.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value")]
+pub enum TimelineEvent {
+    /// A PeerMessage received through the signalling server.
+    PeerReceived(String),
+    /// A PeerMessage sent through the signalling server.
+    PeerSent(String),
+    /// The connection changed its state.
+    State(CSEnum),
+    /// A callback from the WebRTC setup: "Ice" or "Connection".
+    Setup(String),
+    /// A change of an established connection, like a closed channel.
+    Connection(String),
+    /// An error while treating one of the above.
+    Error(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TimelineEntry {
+    /// Milliseconds since the UNIX epoch.
+    pub time: f64,
+    pub event: TimelineEvent,
+}
+
+/// A bounded list of timestamped events.
+#[derive(Debug, Clone, Default)]
+pub struct Timeline {
+    entries: VecDeque<TimelineEntry>,
+}
+
+impl Timeline {
+    pub fn new() -> Timeline {
+        Timeline::default()
+    }
+
+    /// Adds an event at the given time, dropping the oldest event if the
+    /// timeline is full.
+    pub fn push(&mut self, time: f64, event: TimelineEvent) {
+        self.entries.push_back(TimelineEntry { time, event });
+        while self.entries.len() > TIMELINE_MAX {
+            self.entries.pop_front();
+        }
+    }
+
+    pub fn entries(&self) -> Vec<TimelineEntry> {
+        self.entries.iter().cloned().collect()
+    }
+}
+
+/// The timelines of the outgoing and the incoming connection to one peer.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConnectionTimeline {
+    pub outgoing: Vec<TimelineEntry>,
+    pub incoming: Vec<TimelineEntry>,
+}
+
+impl ConnectionTimeline {
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string_pretty(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounded() -> Result<(), serde_json::Error> {
+        let mut tl = Timeline::new();
+        for i in 0..TIMELINE_MAX + 10 {
+            tl.push(i as f64, TimelineEvent::PeerReceived(format!("{}", i)));
+        }
+        let entries = tl.entries();
+        assert_eq!(entries.len(), TIMELINE_MAX);
+        assert_eq!(entries[0].event, TimelineEvent::PeerReceived("10".into()));
+
+        let ct = ConnectionTimeline {
+            outgoing: entries,
+            incoming: vec![],
+        };
+        let back: ConnectionTimeline = serde_json::from_str(&ct.to_json()?)?;
+        assert_eq!(back.outgoing.len(), TIMELINE_MAX);
+        assert_eq!(back.outgoing[9].event, ct.outgoing[9].event);
+        Ok(())
+    }
+}
@@ -0,0 +1,111 @@
+//! Transports carry the messages between two nodes. WebRTC works in browsers,
+//! but needs the signal server to set up a connection. Other transports can
+//! connect directly to the nodes that advertise them in their NodeInfo.
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    node::{
+        config::NodeInfo,
+        events::ChannelError,
+        network::{
+            connection_state::{CSEnum, CSError},
+            timeline::ConnectionTimeline,
+        },
+        types::U256,
+    },
+    signal::web_rtc::{ConnectionStateMap, WSSignalMessage, WebRTCConnectionState},
+};
+
+#[derive(Error, Debug)]
+pub enum TransportError {
+    #[error(transparent)]
+    Channel(#[from] ChannelError),
+    #[error(transparent)]
+    Connection(#[from] CSError),
+    #[error("couldn't accept connections: {0}")]
+    Accept(String),
+    #[error("couldn't connect: {0}")]
+    Connect(String),
+    #[error("couldn't send: {0}")]
+    Send(String),
+    #[error("got {0} for another node")]
+    Alien(String),
+}
+
+/// The kinds of transports a node can advertise. They are sorted by
+/// preference: if two nodes share more than one kind, the first one is used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum TransportKind {
+    /// Direct connections to nodes with a public address.
+    TCP,
+    WebRTC,
+}
+
+impl TransportKind {
+    /// The prefix of the addresses of this transport in the NodeInfo.
+    pub fn scheme(&self) -> &'static str {
+        match self {
+            TransportKind::TCP => "tcp",
+            TransportKind::WebRTC => "webrtc",
+        }
+    }
+}
+
+/// What happened in a transport since the last call to `receive`.
+#[derive(Debug)]
+pub enum TransportOutput {
+    /// A message from another node.
+    Message(U256, String),
+    /// The state of a connection to another node changed.
+    State(
+        U256,
+        WebRTCConnectionState,
+        CSEnum,
+        Option<ConnectionStateMap>,
+    ),
+    /// A message to the node couldn't be queued and has been dropped.
+    Dropped(U256, String),
+    /// Messages to the node go through the signal server, or not anymore.
+    Relayed(U256, bool),
+    /// A message to be sent to the signal server.
+    Signal(Box<WSSignalMessage>),
+}
+
+#[async_trait(?Send)]
+pub trait Transport {
+    fn kind(&self) -> TransportKind;
+
+    /// Starts accepting connections from other nodes. Returns the address
+    /// other nodes can connect to, or None if the connections are set up
+    /// through the signal server.
+    fn accept(&mut self) -> Result<Option<String>, TransportError>;
+
+    /// Starts setting up a connection to the given node. Messages sent before
+    /// the connection is ready are queued.
+    fn connect(&mut self, node: &NodeInfo) -> Result<(), TransportError>;
+
+    /// Sends a message to the node, connecting to it if necessary.
+    fn send(&mut self, node: &U256, msg: String) -> Result<(), TransportError>;
+
+    /// Treats a message from the signal server. Transports that don't need the
+    /// signal server ignore it.
+    fn signal(&mut self, _msg: &WSSignalMessage) -> Result<(), TransportError> {
+        Ok(())
+    }
+
+    /// Processes waiting messages and returns the received messages and the
+    /// changes of the connections.
+    async fn receive(&mut self) -> Result<Vec<TransportOutput>, TransportError>;
+
+    /// Returns the setup events of the connections to the given node.
+    fn timeline(&self, _node: &U256) -> Option<ConnectionTimeline> {
+        None
+    }
+
+    /// Logs the state of all connections.
+    async fn print_states(&self) -> Result<(), TransportError> {
+        Ok(())
+    }
+}
@@ -0,0 +1,210 @@
+use async_trait::async_trait;
+use std::{
+    collections::HashMap,
+    rc::Rc,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    node::{
+        config::NodeInfo,
+        events::Wakeup,
+        ext_interface::Logger,
+        logging::Level,
+        network::{
+            node_connection::{NCInput, NCOutput, NodeConnection},
+            timeline::ConnectionTimeline,
+            transport::{Transport, TransportError, TransportKind, TransportOutput},
+        },
+        types::{Clock, U256},
+    },
+    signal::web_rtc::{IceServer, PeerInfo, RelayMessage, WSSignalMessage, WebRTCSpawner},
+};
+
+/// Sets up WebRTC connections to other nodes using the signal server.
+/// Every remote node has its own NodeConnection.
+pub struct WebRTCTransport {
+    public: U256,
+    web_rtc: Arc<Mutex<WebRTCSpawner>>,
+    // the ICE servers from the configuration
+    config_ice: Vec<IceServer>,
+    // the ICE servers used by the connections, including the ones from the signal server
+    ice_servers: Arc<Mutex<Vec<IceServer>>>,
+    relay_fallback: bool,
+    connections: HashMap<U256, NodeConnection>,
+    // messages relayed by the signal server, returned by the next receive
+    relayed: Vec<TransportOutput>,
+    logger: Box<dyn Logger>,
+    clock: Clock,
+    wakeup: Wakeup,
+}
+
+impl WebRTCTransport {
+    pub fn new(
+        logger: Box<dyn Logger>,
+        public: U256,
+        ice_servers: Vec<IceServer>,
+        relay_fallback: bool,
+        web_rtc: WebRTCSpawner,
+        clock: Clock,
+        wakeup: Wakeup,
+    ) -> WebRTCTransport {
+        WebRTCTransport {
+            public,
+            web_rtc: Arc::new(Mutex::new(web_rtc)),
+            ice_servers: Arc::new(Mutex::new(ice_servers.clone())),
+            config_ice: ice_servers,
+            relay_fallback,
+            connections: HashMap::new(),
+            relayed: vec![],
+            logger,
+            clock,
+            wakeup,
+        }
+    }
+
+    /// Returns the connection to the given node, creating it if it doesn't exist yet.
+    fn connection(&mut self, id: &U256) -> Result<&mut NodeConnection, TransportError> {
+        if !self.connections.contains_key(id) {
+            let conn = NodeConnection::new(
+                self.logger
+                    .with_context("network::connection", &[("peer", id.to_string())]),
+                Arc::clone(&self.web_rtc),
+                Arc::clone(&self.ice_servers),
+                self.relay_fallback,
+                Rc::clone(&self.clock),
+                &self.wakeup,
+            )?;
+            self.connections.insert(id.clone(), conn);
+        }
+        Ok(self.connections.get_mut(id).unwrap())
+    }
+}
+
+#[async_trait(?Send)]
+impl Transport for WebRTCTransport {
+    fn kind(&self) -> TransportKind {
+        TransportKind::WebRTC
+    }
+
+    fn accept(&mut self) -> Result<Option<String>, TransportError> {
+        Ok(None)
+    }
+
+    fn connect(&mut self, node: &NodeInfo) -> Result<(), TransportError> {
+        self.connection(&node.public)?;
+        Ok(())
+    }
+
+    /// If no connection is active yet, a new one will be created.
+    /// NodeConnection will take care of putting the message in a queue while
+    /// the setup is finishing.
+    fn send(&mut self, node: &U256, msg: String) -> Result<(), TransportError> {
+        Ok(self.connection(node)?.send(msg)?)
+    }
+
+    fn signal(&mut self, msg: &WSSignalMessage) -> Result<(), TransportError> {
+        match msg {
+            WSSignalMessage::PeerSetup(pi) => {
+                let remote_node = pi
+                    .get_remote(&self.public)
+                    .ok_or_else(|| TransportError::Alien("PeerSetup".to_string()))?;
+                self.logger.log(
+                    Level::Debug,
+                    "Processing PeerSetup",
+                    &[
+                        ("peer", remote_node.to_string()),
+                        ("type", pi.message.to_string()),
+                    ],
+                );
+                let remote = remote_node == pi.id_init;
+                let conn = self.connection(&remote_node)?;
+                conn.input_tx
+                    .send(NCInput::WebSocket(pi.message.clone(), remote))?;
+            }
+            WSSignalMessage::IceServers(servers) => {
+                self.logger.log(
+                    Level::Debug,
+                    "Got ICE servers from signal server",
+                    &[("servers", format!("{:?}", servers))],
+                );
+                *self.ice_servers.lock().unwrap() =
+                    self.config_ice.iter().chain(servers).cloned().collect();
+            }
+            WSSignalMessage::Relay(rm) => {
+                // A wrong message from the signal server must not stop the
+                // other messages of the same batch.
+                if rm.to != self.public {
+                    self.logger.log(
+                        Level::Warn,
+                        "Ignoring relay message for another node",
+                        &[("from", rm.from.to_string()), ("to", rm.to.to_string())],
+                    );
+                    return Ok(());
+                }
+                self.connection(&rm.from)?.relayed_by_peer()?;
+                self.relayed
+                    .push(TransportOutput::Message(rm.from.clone(), rm.msg.clone()));
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    async fn receive(&mut self) -> Result<Vec<TransportOutput>, TransportError> {
+        let mut outputs: Vec<TransportOutput> = self.relayed.drain(..).collect();
+        for (id, conn) in self.connections.iter_mut() {
+            for output in conn.output_rx.try_iter() {
+                self.logger.log(
+                    Level::Trace,
+                    "Output from connection",
+                    &[
+                        ("peer", id.to_string()),
+                        ("output", format!("{:?}", output)),
+                    ],
+                );
+                outputs.push(match output {
+                    NCOutput::WebSocket(message, remote) => {
+                        let (id_init, id_follow) = match remote {
+                            true => (id.clone(), self.public.clone()),
+                            false => (self.public.clone(), id.clone()),
+                        };
+                        TransportOutput::Signal(Box::new(WSSignalMessage::PeerSetup(PeerInfo {
+                            id_init,
+                            id_follow,
+                            message,
+                        })))
+                    }
+                    NCOutput::WebRTCMessage(msg) => TransportOutput::Message(id.clone(), msg),
+                    NCOutput::State(dir, c, sta) => TransportOutput::State(id.clone(), dir, c, sta),
+                    NCOutput::Dropped(msg) => TransportOutput::Dropped(id.clone(), msg),
+                    NCOutput::Relay(msg) => {
+                        TransportOutput::Signal(Box::new(WSSignalMessage::Relay(RelayMessage {
+                            from: self.public.clone(),
+                            to: id.clone(),
+                            msg,
+                        })))
+                    }
+                    NCOutput::Relayed(relayed) => TransportOutput::Relayed(id.clone(), relayed),
+                });
+            }
+            conn.process().await?;
+        }
+        Ok(outputs)
+    }
+
+    fn timeline(&self, node: &U256) -> Option<ConnectionTimeline> {
+        self.connections.get(node).map(|conn| conn.timeline())
+    }
+
+    async fn print_states(&self) -> Result<(), TransportError> {
+        for (_id, conn) in self.connections.iter() {
+            for dir in conn.get_stats().await? {
+                if let Some(stats) = dir {
+                    self.logger.info(&format!("Connection is: {:?}", stats));
+                }
+            }
+        }
+        Ok(())
+    }
+}
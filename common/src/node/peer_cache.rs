@@ -0,0 +1,216 @@
+//! Remembers the peers this node connected to, so that after a restart it
+//! can reconnect to them without waiting for the list of the signal server.
+use std::{cmp::Ordering, collections::HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    config::NodeInfo,
+    ext_interface::{DataStorage, StorageError},
+    logic::{ConnState, Stat},
+    storage::StorageOp,
+    types::U256,
+};
+
+/// The namespace of the peers in the storage of the node.
+pub const PEER_CACHE_NAME: &str = "peers";
+
+/// How many peers are kept. If there are more, the ones connected the
+/// longest time ago are removed.
+const MAX_PEERS: usize = 100;
+/// A connected peer is written again after this time, or earlier if the
+/// type of its connection changes.
+const UPDATE_INTERVAL_MS: f64 = 60_000.;
+/// Peers connected within this time are tried first when reconnecting.
+const RECENT_MS: f64 = 24. * 3600. * 1000.;
+
+/// What is known about a peer from the last time it was connected.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CachedPeer {
+    pub node_info: NodeInfo,
+    /// The last time a connection was up, in ms since the epoch.
+    pub last_connected: f64,
+    pub conn_type: ConnState,
+    /// The smoothed round-trip time, if a pong has been received.
+    pub rtt_ms: Option<f64>,
+}
+
+impl CachedPeer {
+    /// Recently connected peers come first, then the ones with the shortest
+    /// round-trip time, then the ones connected last.
+    fn cmp_rank(&self, other: &CachedPeer, time: f64) -> Ordering {
+        let rank = |p: &CachedPeer| {
+            (
+                time - p.last_connected > RECENT_MS,
+                p.rtt_ms.unwrap_or(f64::INFINITY),
+                -p.last_connected,
+            )
+        };
+        rank(self)
+            .partial_cmp(&rank(other))
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+/// The peers of the node, stored with their public key as key.
+pub struct PeerCache {
+    storage: Box<dyn DataStorage>,
+    peers: HashMap<U256, CachedPeer>,
+}
+
+impl PeerCache {
+    /// Reads the peers from the storage. Peers with an invalid signature or
+    /// without a protocol in common with our node are removed.
+    pub fn load(
+        storage: Box<dyn DataStorage>,
+        our_node: &NodeInfo,
+    ) -> Result<PeerCache, StorageError> {
+        let mut peers = HashMap::new();
+        let mut invalid = vec![];
+        for key in storage.keys("")? {
+            let peer = storage
+                .get(&key)?
+                .and_then(|value| serde_json::from_str::<CachedPeer>(&value).ok())
+                .filter(|peer| {
+                    let ni = &peer.node_info;
+                    ni.public.to_string() == key
+                        && ni.public != our_node.public
+                        && ni.verify().is_ok()
+                        && our_node.compatible(ni)
+                });
+            match peer {
+                Some(peer) => {
+                    peers.insert(peer.node_info.public.clone(), peer);
+                }
+                None => invalid.push(StorageOp::Remove(key)),
+            }
+        }
+        storage.apply(invalid)?;
+        Ok(PeerCache { storage, peers })
+    }
+
+    /// Returns up to `count` peers, starting with the best one to reconnect to
+    /// at the given time.
+    pub fn best(&self, count: usize, time: f64) -> Vec<CachedPeer> {
+        let mut peers: Vec<&CachedPeer> = self.peers.values().collect();
+        peers.sort_by(|a, b| a.cmp_rank(b, time));
+        peers.into_iter().take(count).cloned().collect()
+    }
+
+    pub fn get(&self, id: &U256) -> Option<&CachedPeer> {
+        self.peers.get(id)
+    }
+
+    /// Stores the peers with a connection that is up at the given time. Only
+    /// peers known through their signed NodeInfo are kept.
+    pub fn update(&mut self, stats: &HashMap<U256, Stat>, time: f64) -> Result<(), StorageError> {
+        let mut ops = vec![];
+        for (id, stat) in stats.iter() {
+            let (node_info, conn_type) = match (&stat.node_info, stat.connection()) {
+                (Some(ni), Some(conn_type)) => (ni, conn_type),
+                _ => continue,
+            };
+            let previous = self.peers.get(id);
+            if let Some(peer) = previous {
+                if peer.conn_type == conn_type && time - peer.last_connected < UPDATE_INTERVAL_MS {
+                    continue;
+                }
+            }
+            let peer = CachedPeer {
+                node_info: node_info.clone(),
+                last_connected: time,
+                conn_type,
+                rtt_ms: stat.latency.rtt_avg_ms.or(previous.and_then(|p| p.rtt_ms)),
+            };
+            let value =
+                serde_json::to_string(&peer).map_err(|e| StorageError::Save(e.to_string()))?;
+            ops.push(StorageOp::Save(id.to_string(), value));
+            self.peers.insert(id.clone(), peer);
+        }
+        if self.peers.len() > MAX_PEERS {
+            let mut oldest: Vec<(f64, U256)> = self
+                .peers
+                .values()
+                .map(|p| (p.last_connected, p.node_info.public.clone()))
+                .collect();
+            oldest.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+            for (_, id) in oldest.into_iter().take(self.peers.len() - MAX_PEERS) {
+                self.peers.remove(&id);
+                ops.push(StorageOp::Remove(id.to_string()));
+            }
+        }
+        if ops.is_empty() {
+            return Ok(());
+        }
+        self.storage.apply(ops)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::{config::NodeConfig, storage::MemoryStorage, types::now};
+
+    fn peer() -> NodeInfo {
+        let config = NodeConfig::new("".to_string()).unwrap();
+        let mut info = config.our_node;
+        info.sign(&config.secret);
+        info
+    }
+
+    #[test]
+    fn update_load() -> Result<(), StorageError> {
+        let storage: Box<dyn DataStorage> = Box::new(MemoryStorage::default());
+        let our_node = peer();
+        let mut cache = PeerCache::load(storage.namespace(PEER_CACHE_NAME), &our_node)?;
+
+        let (fast, slow, idle) = (peer(), peer(), peer());
+        let mut stats = HashMap::new();
+        for (ni, rtt) in [(&fast, Some(20.)), (&slow, Some(200.)), (&idle, None)].iter() {
+            let mut stat = Stat::new(Some((*ni).clone()), now());
+            stat.outgoing = ConnState::STUN;
+            stat.latency.rtt_avg_ms = *rtt;
+            stats.insert(ni.public.clone(), stat);
+        }
+        stats.get_mut(&idle.public).unwrap().outgoing = ConnState::Setup;
+        cache.update(&stats, now())?;
+
+        // a forged entry is removed when loading
+        let mut forged = cache.get(&slow.public).unwrap().clone();
+        forged.node_info.info = "forged".to_string();
+        storage.save(
+            &format!("{}/{}", PEER_CACHE_NAME, slow.public),
+            &serde_json::to_string(&forged).unwrap(),
+        )?;
+
+        let cache = PeerCache::load(storage.namespace(PEER_CACHE_NAME), &our_node)?;
+        let best = cache.best(10, now());
+        assert_eq!(1, best.len());
+        assert_eq!(fast, best[0].node_info);
+        assert_eq!(ConnState::STUN, best[0].conn_type);
+        assert_eq!(Some(20.), best[0].rtt_ms);
+        assert_eq!(1, storage.keys(PEER_CACHE_NAME)?.len());
+        Ok(())
+    }
+
+    #[test]
+    fn ranking() {
+        let time = now();
+        let cached = |last_connected: f64, rtt_ms: Option<f64>| CachedPeer {
+            node_info: NodeInfo::new(U256::rnd()),
+            last_connected,
+            conn_type: ConnState::Host,
+            rtt_ms,
+        };
+        let old_fast = cached(time - 2. * RECENT_MS, Some(10.));
+        let recent_slow = cached(time - 1000., Some(300.));
+        let recent_unknown = cached(time, None);
+        let recent_fast = cached(time - 5000., Some(30.));
+        let mut peers = vec![&old_fast, &recent_unknown, &recent_slow, &recent_fast];
+        peers.sort_by(|a, b| a.cmp_rank(b, time));
+        assert_eq!(
+            vec![&recent_fast, &recent_slow, &recent_unknown, &old_fast],
+            peers
+        );
+    }
+}
@@ -0,0 +1,572 @@
+//! Helpers and implementations for the `DataStorage` trait: an in-memory
+//! storage, namespaces, and a storage in a directory for nodes running
+//! outside of the browser.
+use std::{cell::RefCell, collections::BTreeMap, rc::Rc};
+
+use super::ext_interface::{DataStorage, StorageError};
+
+/// One write in a batch given to `DataStorage::apply`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StorageOp {
+    Save(String, String),
+    Remove(String),
+}
+
+impl StorageOp {
+    /// Stores a binary value the same way as `DataStorage::save_bytes`.
+    pub fn save_bytes(key: &str, value: &[u8]) -> StorageOp {
+        StorageOp::Save(key.to_string(), to_hex(value))
+    }
+
+    pub fn key(&self) -> &str {
+        match self {
+            StorageOp::Save(key, _) => key,
+            StorageOp::Remove(key) => key,
+        }
+    }
+
+    fn with_prefix(self, prefix: &str) -> StorageOp {
+        match self {
+            StorageOp::Save(key, value) => StorageOp::Save(format!("{}{}", prefix, key), value),
+            StorageOp::Remove(key) => StorageOp::Remove(format!("{}{}", prefix, key)),
+        }
+    }
+}
+
+pub fn to_hex(value: &[u8]) -> String {
+    value.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(value: &str) -> Result<Vec<u8>, StorageError> {
+    if value.len() % 2 == 1 || !value.is_ascii() {
+        return Err(StorageError::Load("invalid binary value".to_string()));
+    }
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&value[i..i + 2], 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .map_err(|e| StorageError::Load(e.to_string()))
+}
+
+/// Escapes a key so it can be used as a file name: everything except ASCII
+/// letters, digits, `-` and `_` is written as `.` followed by its hex value.
+pub fn escape_key(key: &str) -> String {
+    key.bytes()
+        .map(|b| match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => (b as char).to_string(),
+            _ => format!(".{:02x}", b),
+        })
+        .collect()
+}
+
+/// Returns the key of an escaped file name, or None if the name was not
+/// created by `escape_key`.
+pub fn unescape_key(name: &str) -> Option<String> {
+    let mut bytes = vec![];
+    let mut chars = name.bytes();
+    while let Some(b) = chars.next() {
+        match b {
+            b'.' => {
+                let hex = [chars.next()?, chars.next()?];
+                let hex = std::str::from_utf8(&hex).ok()?;
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            }
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => bytes.push(b),
+            _ => return None,
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// Prefixes all keys with the name of the namespace, followed by a `/`.
+pub struct Namespace {
+    storage: Box<dyn DataStorage>,
+    prefix: String,
+}
+
+impl Namespace {
+    pub fn new(storage: Box<dyn DataStorage>, name: &str) -> Namespace {
+        Namespace {
+            storage,
+            prefix: format!("{}/", name),
+        }
+    }
+
+    fn key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+}
+
+impl DataStorage for Namespace {
+    fn get(&self, key: &str) -> Result<Option<String>, StorageError> {
+        self.storage.get(&self.key(key))
+    }
+
+    fn save(&self, key: &str, value: &str) -> Result<(), StorageError> {
+        self.storage.save(&self.key(key), value)
+    }
+
+    fn remove(&self, key: &str) -> Result<(), StorageError> {
+        self.storage.remove(&self.key(key))
+    }
+
+    fn keys(&self, prefix: &str) -> Result<Vec<String>, StorageError> {
+        Ok(self
+            .storage
+            .keys(&self.key(prefix))?
+            .into_iter()
+            .map(|key| key[self.prefix.len()..].to_string())
+            .collect())
+    }
+
+    fn box_clone(&self) -> Box<dyn DataStorage> {
+        Box::new(Namespace {
+            storage: self.storage.box_clone(),
+            prefix: self.prefix.clone(),
+        })
+    }
+
+    fn size(&self, key: &str) -> Result<Option<usize>, StorageError> {
+        self.storage.size(&self.key(key))
+    }
+
+    /// Passes the batch on, so it is as atomic as in the underlying storage.
+    fn apply(&self, ops: Vec<StorageOp>) -> Result<(), StorageError> {
+        self.storage.apply(
+            ops.into_iter()
+                .map(|op| op.with_prefix(&self.prefix))
+                .collect(),
+        )
+    }
+}
+
+/// Keeps the data in memory only. Clones share the same data.
+#[derive(Default)]
+pub struct MemoryStorage {
+    data: Rc<RefCell<BTreeMap<String, String>>>,
+}
+
+impl DataStorage for MemoryStorage {
+    fn get(&self, key: &str) -> Result<Option<String>, StorageError> {
+        Ok(self.data.borrow().get(key).cloned())
+    }
+
+    fn save(&self, key: &str, value: &str) -> Result<(), StorageError> {
+        self.data
+            .borrow_mut()
+            .insert(key.to_string(), value.to_string());
+        Ok(())
+    }
+
+    fn remove(&self, key: &str) -> Result<(), StorageError> {
+        self.data.borrow_mut().remove(key);
+        Ok(())
+    }
+
+    fn keys(&self, prefix: &str) -> Result<Vec<String>, StorageError> {
+        Ok(self
+            .data
+            .borrow()
+            .range(prefix.to_string()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, _)| key.clone())
+            .collect())
+    }
+
+    fn box_clone(&self) -> Box<dyn DataStorage> {
+        Box::new(MemoryStorage {
+            data: Rc::clone(&self.data),
+        })
+    }
+
+    /// Writing to memory cannot fail, so all operations are simply applied.
+    fn apply(&self, ops: Vec<StorageOp>) -> Result<(), StorageError> {
+        let mut data = self.data.borrow_mut();
+        for op in ops {
+            match op {
+                StorageOp::Save(key, value) => data.insert(key, value),
+                StorageOp::Remove(key) => data.remove(&key),
+            };
+        }
+        Ok(())
+    }
+}
+
+/// The few file operations `FileStorage` needs, so it can be used with the
+/// standard library as well as with the `fs` module of node.js. Paths are
+/// separated by `/`.
+pub trait FileSystem {
+    /// Returns the content of the file, or None if it doesn't exist.
+    fn read(&self, path: &str) -> Result<Option<String>, String>;
+
+    fn write(&self, path: &str, value: &str) -> Result<(), String>;
+
+    /// Replaces the destination, if it exists.
+    fn rename(&self, from: &str, to: &str) -> Result<(), String>;
+
+    /// Removing a missing file is not an error.
+    fn remove(&self, path: &str) -> Result<(), String>;
+
+    /// Returns the names of the files in the directory, which is created if
+    /// it doesn't exist yet.
+    fn list(&self, dir: &str) -> Result<Vec<String>, String>;
+
+    fn size(&self, path: &str) -> Result<Option<usize>, String> {
+        Ok(self.read(path)?.map(|value| value.len()))
+    }
+}
+
+/// Stores every key in its own file in a directory. Values are first
+/// written to a temporary file, which is then renamed, so a crash never
+/// leaves a half-written value.
+///
+/// A batch given to `apply` is written to temporary files, then a journal
+/// listing its operations is stored, and only then the files are renamed.
+/// If the node stops before the journal is removed, the next `FileStorage`
+/// opening the directory finishes the batch. Without a journal the temporary
+/// files of an unfinished batch are deleted.
+pub struct FileStorage {
+    fs: Rc<dyn FileSystem>,
+    dir: String,
+    files: Rc<Vec<(String, String)>>,
+}
+
+// Escaped keys never contain a `~`, so neither the temporary files nor the
+// journal are returned by `keys`.
+const JOURNAL: &str = "~journal";
+
+impl FileStorage {
+    /// Uses the file system of the standard library and creates the
+    /// directory if it doesn't exist yet.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn new<P: AsRef<std::path::Path>>(dir: P) -> Result<FileStorage, StorageError> {
+        let dir = dir.as_ref().to_string_lossy().to_string();
+        FileStorage::with_fs(Rc::new(StdFileSystem {}), &dir)
+    }
+
+    /// Creates the directory if it doesn't exist yet, and finishes or
+    /// discards a batch that was interrupted.
+    pub fn with_fs(fs: Rc<dyn FileSystem>, dir: &str) -> Result<FileStorage, StorageError> {
+        fs.list(dir).map_err(StorageError::Unavailable)?;
+        let storage = FileStorage {
+            fs,
+            dir: dir.to_string(),
+            files: Rc::new(vec![]),
+        };
+        storage.recover()?;
+        Ok(storage)
+    }
+
+    /// Stores the key in the given file instead of the directory.
+    pub fn with_file(mut self, key: &str, path: &str) -> FileStorage {
+        Rc::make_mut(&mut self.files).push((key.to_string(), path.to_string()));
+        self
+    }
+
+    fn path(&self, key: &str) -> String {
+        match self.files.iter().find(|(k, _)| k == key) {
+            Some((_, path)) => path.clone(),
+            None => format!("{}/{}", self.dir, escape_key(key)),
+        }
+    }
+
+    fn tmp_path(&self, key: &str) -> String {
+        format!("{}~", self.path(key))
+    }
+
+    fn journal_path(&self) -> String {
+        format!("{}/{}", self.dir, JOURNAL)
+    }
+
+    fn write_journal(&self, ops: &[StorageOp]) -> Result<(), StorageError> {
+        let journal: String = ops
+            .iter()
+            .map(|op| match op {
+                StorageOp::Save(key, _) => format!("save {}\n", escape_key(key)),
+                StorageOp::Remove(key) => format!("remove {}\n", escape_key(key)),
+            })
+            .collect();
+        let path = self.journal_path();
+        let tmp = format!("{}~", path);
+        self.fs
+            .write(&tmp, &journal)
+            .and_then(|_| self.fs.rename(&tmp, &path))
+            .map_err(StorageError::Save)
+    }
+
+    fn recover(&self) -> Result<(), StorageError> {
+        let journal = self
+            .fs
+            .read(&self.journal_path())
+            .map_err(StorageError::Load)?;
+        match journal {
+            Some(journal) => {
+                let mut ops = vec![];
+                for line in journal.lines() {
+                    let mut parts = line.splitn(2, ' ');
+                    match (parts.next(), parts.next().and_then(unescape_key)) {
+                        (Some("save"), Some(key)) => ops.push(StorageOp::Save(key, String::new())),
+                        (Some("remove"), Some(key)) => ops.push(StorageOp::Remove(key)),
+                        _ => return Err(StorageError::Load(format!("invalid journal: {}", line))),
+                    }
+                }
+                self.commit(&ops)
+            }
+            None => {
+                let names = self.fs.list(&self.dir).map_err(StorageError::List)?;
+                for name in names.iter().filter(|name| name.ends_with('~')) {
+                    self.fs
+                        .remove(&format!("{}/{}", self.dir, name))
+                        .map_err(StorageError::Remove)?;
+                }
+                for (_, path) in self.files.iter() {
+                    self.fs
+                        .remove(&format!("{}~", path))
+                        .map_err(StorageError::Remove)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // Renames the temporary files and removes the keys. A temporary file
+    // that is missing has already been renamed, so this can be repeated.
+    fn commit(&self, ops: &[StorageOp]) -> Result<(), StorageError> {
+        for op in ops.iter() {
+            match op {
+                StorageOp::Save(key, _) => {
+                    if self
+                        .fs
+                        .size(&self.tmp_path(key))
+                        .map_err(StorageError::Load)?
+                        .is_some()
+                    {
+                        self.fs
+                            .rename(&self.tmp_path(key), &self.path(key))
+                            .map_err(StorageError::Save)?;
+                    }
+                }
+                StorageOp::Remove(key) => self.remove(key)?,
+            }
+        }
+        self.fs
+            .remove(&self.journal_path())
+            .map_err(StorageError::Remove)
+    }
+}
+
+impl DataStorage for FileStorage {
+    fn get(&self, key: &str) -> Result<Option<String>, StorageError> {
+        self.fs.read(&self.path(key)).map_err(StorageError::Load)
+    }
+
+    fn save(&self, key: &str, value: &str) -> Result<(), StorageError> {
+        self.apply(vec![StorageOp::Save(key.to_string(), value.to_string())])
+    }
+
+    fn remove(&self, key: &str) -> Result<(), StorageError> {
+        self.fs
+            .remove(&self.path(key))
+            .map_err(StorageError::Remove)
+    }
+
+    fn keys(&self, prefix: &str) -> Result<Vec<String>, StorageError> {
+        let mut keys: Vec<String> = self
+            .fs
+            .list(&self.dir)
+            .map_err(StorageError::List)?
+            .iter()
+            .filter_map(|name| unescape_key(name))
+            .filter(|key| key.starts_with(prefix) && !self.files.iter().any(|(k, _)| k == key))
+            .collect();
+        for (key, _) in self.files.iter() {
+            if key.starts_with(prefix) && self.size(key)?.is_some() {
+                keys.push(key.clone());
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn box_clone(&self) -> Box<dyn DataStorage> {
+        Box::new(FileStorage {
+            fs: Rc::clone(&self.fs),
+            dir: self.dir.clone(),
+            files: Rc::clone(&self.files),
+        })
+    }
+
+    fn size(&self, key: &str) -> Result<Option<usize>, StorageError> {
+        self.fs.size(&self.path(key)).map_err(StorageError::Load)
+    }
+
+    /// All new values are written to temporary files first. Only if this
+    /// succeeds, the journal is written, and the files are renamed and the
+    /// removed keys are deleted. A single operation needs no journal.
+    fn apply(&self, ops: Vec<StorageOp>) -> Result<(), StorageError> {
+        for op in ops.iter() {
+            if let StorageOp::Save(key, value) = op {
+                if let Err(e) = self.fs.write(&self.tmp_path(key), value) {
+                    for op in ops.iter() {
+                        let _ = self.fs.remove(&self.tmp_path(op.key()));
+                    }
+                    return Err(StorageError::Save(e));
+                }
+            }
+        }
+        if ops.len() > 1 {
+            if let Err(e) = self.write_journal(&ops) {
+                for op in ops.iter() {
+                    let _ = self.fs.remove(&self.tmp_path(op.key()));
+                }
+                return Err(e);
+            }
+        }
+        self.commit(&ops)
+    }
+}
+
+/// The file system of the standard library.
+#[cfg(not(target_arch = "wasm32"))]
+pub struct StdFileSystem {}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl FileSystem for StdFileSystem {
+    fn read(&self, path: &str) -> Result<Option<String>, String> {
+        match std::fs::read_to_string(path) {
+            Ok(value) => Ok(Some(value)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    fn write(&self, path: &str, value: &str) -> Result<(), String> {
+        std::fs::write(path, value).map_err(|e| e.to_string())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), String> {
+        std::fs::rename(from, to).map_err(|e| e.to_string())
+    }
+
+    fn remove(&self, path: &str) -> Result<(), String> {
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    fn list(&self, dir: &str) -> Result<Vec<String>, String> {
+        std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        let mut names = vec![];
+        for entry in std::fs::read_dir(dir).map_err(|e| e.to_string())? {
+            let entry = entry.map_err(|e| e.to_string())?;
+            if let Some(name) = entry.file_name().to_str() {
+                names.push(name.to_string());
+            }
+        }
+        Ok(names)
+    }
+
+    fn size(&self, path: &str) -> Result<Option<usize>, String> {
+        match std::fs::metadata(path) {
+            Ok(meta) => Ok(Some(meta.len() as usize)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(storage: Box<dyn DataStorage>) -> Result<(), StorageError> {
+        storage.save("config", "value")?;
+        storage.save_bytes("blocks/1", &[0, 1, 255])?;
+        storage.save("blocks/2", "")?;
+        assert_eq!("value", storage.load("config")?);
+        assert_eq!(Some(vec![0, 1, 255]), storage.load_bytes("blocks/1")?);
+        assert_eq!(None, storage.get("missing")?);
+        assert_eq!(Some(6), storage.size("blocks/1")?);
+        assert_eq!(vec!["blocks/1", "blocks/2"], storage.keys("blocks/")?);
+        assert_eq!(6, storage.used("blocks/")?);
+
+        storage.remove("blocks/2")?;
+        storage.remove("blocks/2")?;
+        assert_eq!(vec!["blocks/1"], storage.keys("blocks/")?);
+
+        storage.apply(vec![
+            StorageOp::Remove("blocks/1".to_string()),
+            StorageOp::Save("config".to_string(), "new".to_string()),
+        ])?;
+        assert_eq!(Vec::<String>::new(), storage.keys("blocks/")?);
+        assert_eq!("new", storage.load("config")?);
+
+        let peers = storage.namespace("peers");
+        peers.save("a", "1")?;
+        assert_eq!(Some("1".to_string()), storage.get("peers/a")?);
+        assert_eq!(vec!["a"], peers.keys("")?);
+        Ok(())
+    }
+
+    #[test]
+    fn memory() -> Result<(), StorageError> {
+        check(Box::new(MemoryStorage::default()))
+    }
+
+    #[test]
+    fn files() -> Result<(), StorageError> {
+        let dir = std::env::temp_dir().join(format!("fledger-{}", crate::node::types::U256::rnd()));
+        let res = check(Box::new(FileStorage::new(&dir)?));
+        let _ = std::fs::remove_dir_all(&dir);
+        res
+    }
+
+    #[test]
+    fn journal() -> Result<(), StorageError> {
+        let dir = std::env::temp_dir().join(format!("fledger-{}", crate::node::types::U256::rnd()));
+        let res = (|| {
+            let storage =
+                FileStorage::new(&dir)?.with_file("config", &format!("{}.toml", dir.display()));
+            storage.save("a", "old")?;
+            storage.save("b", "old")?;
+            storage.save("config", "old")?;
+
+            // The node stopped after writing the journal: the batch is finished.
+            let ops = vec![
+                StorageOp::Save("a".to_string(), "new".to_string()),
+                StorageOp::Save("config".to_string(), "new".to_string()),
+                StorageOp::Remove("b".to_string()),
+            ];
+            for op in ops.iter() {
+                if let StorageOp::Save(key, value) = op {
+                    storage.fs.write(&storage.tmp_path(key), value).unwrap();
+                }
+            }
+            storage.write_journal(&ops)?;
+            storage.recover()?;
+            assert_eq!("new", storage.load("a")?);
+            assert_eq!("new", storage.load("config")?);
+            assert_eq!(vec!["a", "config"], storage.keys("")?);
+
+            // The node stopped before writing the journal: the batch is dropped.
+            storage.fs.write(&storage.tmp_path("a"), "newer").unwrap();
+            let storage = FileStorage::new(&dir)?;
+            assert_eq!("new", storage.load("a")?);
+            assert_eq!(vec!["a"], storage.fs.list(&storage.dir).unwrap());
+            Ok(())
+        })();
+        let _ = std::fs::remove_dir_all(&dir);
+        let _ = std::fs::remove_file(format!("{}.toml", dir.display()));
+        res
+    }
+
+    #[test]
+    fn escape() {
+        for key in ["nodeConfig", "peers/a.b", "ünïcode ~"].iter() {
+            assert_eq!(Some(key.to_string()), unescape_key(&escape_key(key)));
+        }
+        assert_eq!(None, unescape_key("bad.4"));
+        assert_eq!(None, unescape_key("tmp~"));
+    }
+}
@@ -1,11 +1,44 @@
 use core::fmt;
-use std::num::ParseIntError;
+use std::{ops::BitXor, rc::Rc, str::FromStr};
 
 use rand::random;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
 
-/// Nicely formatted 256 bit structure
-#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
+#[derive(Error, Debug, PartialEq)]
+pub enum U256Error {
+    #[error("need 64 hex chars, optionally in 4 groups of 16 separated by '-', got {0:?}")]
+    Format(String),
+    #[error("invalid hex char {0:?}")]
+    Hex(char),
+}
+
+/// Returns the current time in milliseconds since the UNIX epoch.
+/// In the browser `SystemTime` is not available, so `Date::now` is used.
+pub fn now() -> f64 {
+    #[cfg(target_arch = "wasm32")]
+    return js_sys::Date::now();
+
+    #[cfg(not(target_arch = "wasm32"))]
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs_f64() * 1000.)
+        .unwrap_or(0.)
+}
+
+/// Returns the time in milliseconds since the UNIX epoch. The node reads the
+/// time through a clock, so the simulator can use its virtual clock.
+pub type Clock = Rc<dyn Fn() -> f64>;
+
+/// Returns the clock following `now`.
+pub fn system_clock() -> Clock {
+    Rc::new(now)
+}
+
+/// Nicely formatted 256 bit structure. The bytes are big-endian, so U256s
+/// are ordered like the numbers they represent.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct U256([u8; 32]);
 
 impl fmt::Display for U256 {
@@ -32,28 +65,179 @@ impl fmt::Debug for U256 {
     }
 }
 
+impl From<[u8; 32]> for U256 {
+    fn from(bytes: [u8; 32]) -> U256 {
+        U256(bytes)
+    }
+}
+
 impl U256 {
     pub fn rnd() -> U256 {
-        U256 { 0: random() }
-    }
-
-    /// Convert a hexadecimal string to a U256.
-    /// If less than 64 characters are given, the U256 is filled from
-    /// the left with the remaining u8 initialized to 0.
-    /// So
-    ///   `U256.from_str("1234") == U256.from_str("123400")`
-    /// something
-    pub fn from_str(s: &str) -> Result<U256, String> {
-        if s.len() > 64 {
-            return Err("give no more than 64 hex chars".to_string());
-        }
-        let v: Vec<u8> = (0..s.len())
-            .step_by(2)
-            .map(|i| u8::from_str_radix(&s[i..i + 2], 16))
-            .collect::<Result<Vec<u8>, ParseIntError>>()
-            .map_err(|e| e.to_string())?;
-        let mut u = U256 { 0: [0u8; 32] };
-        v.iter().enumerate().for_each(|(i, b)| u.0[i] = *b);
+        U256(random())
+    }
+
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.0
+    }
+
+    /// Returns the SHA-256 hash of the data.
+    pub fn from_sha256(data: &[u8]) -> U256 {
+        U256(Sha256::digest(data).into())
+    }
+
+    /// Returns the BLAKE3 hash of the data.
+    pub fn from_blake3(data: &[u8]) -> U256 {
+        U256(blake3::hash(data).into())
+    }
+
+    /// The XOR distance between two U256s, as used for routing: the more
+    /// leading bits they share, the closer they are.
+    pub fn distance(&self, other: &U256) -> U256 {
+        self ^ other
+    }
+
+    /// Returns the bit at the given index, where 0 is the most significant
+    /// bit. Panics if the index is 256 or more.
+    pub fn bit(&self, index: usize) -> bool {
+        self.0[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+
+    /// Sets the bit at the given index, where 0 is the most significant bit.
+    /// Panics if the index is 256 or more.
+    pub fn set_bit(&mut self, index: usize, value: bool) {
+        let mask = 0x80 >> (index % 8);
+        if value {
+            self.0[index / 8] |= mask;
+        } else {
+            self.0[index / 8] &= !mask;
+        }
+    }
+
+    /// The number of zero bits before the first one, 256 for zero.
+    pub fn leading_zeros(&self) -> u32 {
+        match self.0.iter().position(|b| *b != 0) {
+            Some(index) => index as u32 * 8 + self.0[index].leading_zeros(),
+            None => 256,
+        }
+    }
+}
+
+impl BitXor for &U256 {
+    type Output = U256;
+
+    fn bitxor(self, other: &U256) -> U256 {
+        let mut bytes = self.0;
+        bytes
+            .iter_mut()
+            .zip(other.0.iter())
+            .for_each(|(a, b)| *a ^= b);
+        U256(bytes)
+    }
+}
+
+/// Parses the 64 hex chars written by `Display`, either plain or with the
+/// dashes between the groups of 16 chars.
+impl FromStr for U256 {
+    type Err = U256Error;
+
+    fn from_str(s: &str) -> Result<U256, U256Error> {
+        let groups: Vec<&str> = s.split('-').collect();
+        let valid = match groups.len() {
+            1 => s.len() == 64,
+            4 => groups.iter().all(|g| g.len() == 16),
+            _ => false,
+        };
+        if !valid {
+            return Err(U256Error::Format(s.to_string()));
+        }
+        let digits = groups
+            .concat()
+            .chars()
+            .map(|c| c.to_digit(16).ok_or(U256Error::Hex(c)))
+            .collect::<Result<Vec<u32>, U256Error>>()?;
+        let mut u = U256([0u8; 32]);
+        for (byte, pair) in u.0.iter_mut().zip(digits.chunks(2)) {
+            *byte = (pair[0] * 16 + pair[1]) as u8;
+        }
         Ok(u)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn u256() -> impl Strategy<Value = U256> {
+        any::<[u8; 32]>().prop_map(U256::from)
+    }
+
+    proptest! {
+        #[test]
+        fn parse_display(u in u256()) {
+            let dashed = u.to_string();
+            prop_assert_eq!(&u, &dashed.parse::<U256>()?);
+            prop_assert_eq!(&u, &dashed.replace("-", "").parse::<U256>()?);
+            prop_assert_eq!(&u, &dashed.to_uppercase().parse::<U256>()?);
+        }
+
+        #[test]
+        fn parse_invalid(s in "[0-9a-f-]{0,70}") {
+            let valid = s.len() == 64 && !s.contains('-')
+                || s.len() == 67 && s.split('-').map(str::len).eq([16, 16, 16, 16]);
+            prop_assert_eq!(valid, s.parse::<U256>().is_ok());
+        }
+
+        #[test]
+        fn ordering(a in u256(), b in u256()) {
+            let num = |u: &U256| u.to_bytes().iter().fold(0f64, |n, b| n * 256. + *b as f64);
+            if num(&a) < num(&b) {
+                prop_assert!(a < b);
+            }
+            prop_assert_eq!(a.cmp(&b), a.to_string().cmp(&b.to_string()));
+        }
+
+        #[test]
+        fn distance(a in u256(), b in u256(), c in u256()) {
+            prop_assert_eq!(a.distance(&b), b.distance(&a));
+            prop_assert_eq!(256, a.distance(&a).leading_zeros());
+            prop_assert_eq!(&a.distance(&b) ^ &b.distance(&c), a.distance(&c));
+            // sharing more leading bits means being closer
+            let (ab, ac) = (a.distance(&b), a.distance(&c));
+            if ab.leading_zeros() > ac.leading_zeros() {
+                prop_assert!(ab < ac);
+            }
+        }
+
+        #[test]
+        fn bits(u in u256(), index in 0usize..256, value: bool) {
+            let mut v = u.clone();
+            v.set_bit(index, value);
+            prop_assert_eq!(value, v.bit(index));
+            prop_assert!((0..256).filter(|i| *i != index).all(|i| u.bit(i) == v.bit(i)));
+            let first = (0..256).find(|i| u.bit(*i)).unwrap_or(256);
+            prop_assert_eq!(first as u32, u.leading_zeros());
+        }
+
+        #[test]
+        fn hashes(data: Vec<u8>, other: Vec<u8>) {
+            prop_assert_eq!(U256::from_sha256(&data), U256::from_sha256(&data));
+            prop_assert_eq!(data == other, U256::from_sha256(&data) == U256::from_sha256(&other));
+            prop_assert_eq!(data == other, U256::from_blake3(&data) == U256::from_blake3(&other));
+        }
+    }
+
+    #[test]
+    fn known_hashes() -> Result<(), U256Error> {
+        assert_eq!(
+            "e3b0c44298fc1c14-9afbf4c8996fb924-27ae41e4649b934c-a495991b7852b855".parse::<U256>()?,
+            U256::from_sha256(b"")
+        );
+        assert_eq!(
+            "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262".parse::<U256>()?,
+            U256::from_blake3(b"")
+        );
+        assert_eq!(Err(U256Error::Hex('g')), "g".repeat(64).parse::<U256>());
+        Ok(())
+    }
+}
@@ -1,2 +1,3 @@
+pub mod relay;
 pub mod web_rtc;
 pub mod websocket;
@@ -0,0 +1,59 @@
+//! Limits for messages that the signal server relays between nodes which
+//! couldn't set up a WebRTC connection. Relaying uses the bandwidth of the
+//! server, so every node can only send a limited number of messages.
+
+/// How many messages per second a node can relay on average.
+pub const RELAY_RATE: f64 = 20.;
+/// How many messages a node can relay in a burst.
+pub const RELAY_BURST: f64 = 50.;
+/// Messages bigger than this are never relayed.
+pub const RELAY_MAX_SIZE: usize = 64 * 1024;
+
+/// A token bucket that refills at RELAY_RATE tokens per second, up to
+/// RELAY_BURST tokens. The time is passed in by the caller, so that the
+/// simulator can use its virtual clock.
+#[derive(Debug, Clone)]
+pub struct RateLimit {
+    tokens: f64,
+    last_ms: f64,
+}
+
+impl RateLimit {
+    pub fn new(now_ms: f64) -> RateLimit {
+        RateLimit {
+            tokens: RELAY_BURST,
+            last_ms: now_ms,
+        }
+    }
+
+    /// Returns true if a message of the given size can be relayed at now_ms.
+    pub fn allow(&mut self, now_ms: f64, size: usize) -> bool {
+        let elapsed = (now_ms - self.last_ms).max(0.) / 1000.;
+        self.tokens = (self.tokens + elapsed * RELAY_RATE).min(RELAY_BURST);
+        self.last_ms = now_ms;
+        if size > RELAY_MAX_SIZE || self.tokens < 1. {
+            return false;
+        }
+        self.tokens -= 1.;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn burst_and_refill() {
+        let mut rl = RateLimit::new(0.);
+        for _ in 0..RELAY_BURST as usize {
+            assert!(rl.allow(0., 10));
+        }
+        assert!(!rl.allow(0., 10));
+        assert!(rl.allow(1000. / RELAY_RATE, 10));
+        assert!(!rl.allow(1000. / RELAY_RATE, 10));
+
+        let mut rl = RateLimit::new(0.);
+        assert!(!rl.allow(0., RELAY_MAX_SIZE + 1));
+    }
+}
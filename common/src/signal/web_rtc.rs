@@ -1,41 +1,118 @@
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
 use std::fmt;
+use thiserror::Error;
 
-use crate::node::{config::NodeInfo, types::U256};
+use crate::{
+    node::{config::NodeInfo, types::U256},
+    signal::websocket::WSError,
+};
 
-pub type WebRTCSpawner =
-    Box<dyn Fn(WebRTCConnectionState) -> Result<Box<dyn WebRTCConnectionSetup>, String>>;
+/// Errors while setting up a new WebRTC connection.
+#[derive(Error, Debug)]
+pub enum SetupError {
+    #[error("couldn't create connection: {0}")]
+    Spawn(String),
+    #[error("method not available to {0:?}")]
+    WrongState(WebRTCConnectionState),
+    #[error("couldn't create offer: {0}")]
+    Offer(String),
+    #[error("couldn't use offer: {0}")]
+    Answer(String),
+    #[error("couldn't use answer: {0}")]
+    UseAnswer(String),
+    #[error("couldn't use ice: {0}")]
+    Ice(String),
+    #[error("ice gathering didn't start")]
+    Gathering,
+}
+
+/// Errors of an established WebRTC connection.
+#[derive(Error, Debug)]
+pub enum ConnectionError {
+    #[error("connection is closed")]
+    Closed,
+    #[error("couldn't send message: {0}")]
+    Send(String),
+    #[error("couldn't get state: {0}")]
+    State(String),
+}
+
+/// A STUN or TURN server used to find a path between two nodes.
+/// TURN servers need a username and a credential.
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+pub struct IceServer {
+    pub urls: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credential: Option<String>,
+}
+
+/// The credential is not shown, so the servers can be logged.
+impl fmt::Debug for IceServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IceServer")
+            .field("urls", &self.urls)
+            .field("username", &self.username)
+            .field("credential", &self.credential.as_ref().map(|_| "***"))
+            .finish()
+    }
+}
+
+impl IceServer {
+    /// A server that needs no credentials, like a STUN server.
+    pub fn new(url: &str) -> IceServer {
+        IceServer {
+            urls: vec![url.to_string()],
+            username: None,
+            credential: None,
+        }
+    }
+}
+
+/// Creates a new connection using the given ICE servers.
+pub type WebRTCSpawner = Box<
+    dyn Fn(
+        WebRTCConnectionState,
+        &[IceServer],
+    ) -> Result<Box<dyn WebRTCConnectionSetup>, SetupError>,
+>;
 
 pub type WebRTCSetupCB = Box<dyn Fn(WebRTCSetupCBMessage)>;
 
 #[async_trait(?Send)]
 pub trait WebRTCConnectionSetup {
     /// Returns the offer string that needs to be sent to the `Follower` node.
-    async fn make_offer(&mut self) -> Result<String, String>;
+    async fn make_offer(&mut self) -> Result<String, SetupError>;
 
     /// Takes the offer string
-    async fn make_answer(&mut self, offer: String) -> Result<String, String>;
+    async fn make_answer(&mut self, offer: String) -> Result<String, SetupError>;
 
     /// Takes the answer string and finalizes the first part of the connection.
-    async fn use_answer(&mut self, answer: String) -> Result<(), String>;
+    async fn use_answer(&mut self, answer: String) -> Result<(), SetupError>;
 
     /// Returns either an ice string or a connection
     async fn set_callback(&mut self, cb: WebRTCSetupCB);
 
     /// Sends the ICE string to the WebRTC.
-    async fn ice_put(&mut self, ice: String) -> Result<(), String>;
+    async fn ice_put(&mut self, ice: String) -> Result<(), SetupError>;
 
     /// TODO: this can probably go away
-    async fn wait_gathering(&mut self) -> Result<(), String>;
+    async fn wait_gathering(&mut self) -> Result<(), SetupError>;
 
     /// Debugging output of the RTC state
     async fn print_states(&mut self);
+
+    /// Closes the connection being set up and removes its callbacks.
+    fn close(&mut self);
 }
 
 pub enum WebRTCSetupCBMessage {
     Ice(String),
     Connection(Box<dyn WebRTCConnection>),
+    /// ICE couldn't find a path to the other node.
+    Failed(String),
 }
 
 impl fmt::Debug for WebRTCSetupCBMessage {
@@ -43,23 +120,31 @@ impl fmt::Debug for WebRTCSetupCBMessage {
         match self {
             WebRTCSetupCBMessage::Ice(_) => f.write_str("Ice")?,
             WebRTCSetupCBMessage::Connection(_) => f.write_str("Connection")?,
+            WebRTCSetupCBMessage::Failed(_) => f.write_str("Failed")?,
         }
         Ok(())
     }
 }
 
-
 #[async_trait(?Send)]
 pub trait WebRTCConnection {
     /// Send a message to the other node. This call blocks until the message
     /// is queued.
-    fn send(&self, s: String) -> Result<(), String>;
+    fn send(&self, s: String) -> Result<(), ConnectionError>;
 
     /// Sets the callback for incoming messages.
     fn set_cb_message(&self, cb: WebRTCMessageCB);
 
+    /// Sets the callback for changes in the state of the connection, e.g., when the
+    /// data channel is closed or the ICE connection failed.
+    fn set_cb_state(&self, cb: WebRTCStateCB);
+
     /// Return some statistics on the connection
-    async fn get_state(&self) -> Result<ConnectionStateMap, String>;
+    async fn get_state(&self) -> Result<ConnectionStateMap, ConnectionError>;
+
+    /// Closes the connection and removes its callbacks. No state change is
+    /// reported for a connection closed this way.
+    fn close(&self);
 }
 
 #[derive(PartialEq, Debug, Clone, Copy)]
@@ -71,6 +156,19 @@ pub enum ConnType {
     TURN,
 }
 
+impl ConnType {
+    /// Converts the `candidateType` of an RTCIceCandidateStats entry.
+    pub fn from_candidate_type(typ: &str) -> ConnType {
+        match typ {
+            "host" => ConnType::Host,
+            "prflx" => ConnType::STUNPeer,
+            "srflx" => ConnType::STUNServer,
+            "relay" => ConnType::TURN,
+            _ => ConnType::Unknown,
+        }
+    }
+}
+
 /// Some statistics about the connection
 #[derive(PartialEq, Debug, Clone, Copy)]
 pub struct ConnectionStateMap {
@@ -83,6 +181,28 @@ pub struct ConnectionStateMap {
 
 pub type WebRTCMessageCB = Box<dyn FnMut(String)>;
 
+/// Changes in the state of an established connection.
+#[derive(PartialEq, Debug, Clone)]
+pub enum WebRTCConnectionEvent {
+    /// The data channel has been closed, either by the remote node or locally.
+    Closed,
+    /// The data channel reported an error.
+    Error(String),
+    /// The ICE connection has been lost, but might come back.
+    Disconnected,
+    /// The ICE connection failed and will not come back.
+    Failed,
+}
+
+impl WebRTCConnectionEvent {
+    /// Returns true if the connection cannot be used anymore.
+    pub fn is_fatal(&self) -> bool {
+        !matches!(self, WebRTCConnectionEvent::Disconnected)
+    }
+}
+
+pub type WebRTCStateCB = Box<dyn FnMut(WebRTCConnectionEvent)>;
+
 /// What type of node this is
 #[derive(PartialEq, Debug, Clone, Copy)]
 pub enum WebRTCConnectionState {
@@ -142,8 +262,8 @@ pub struct WebSocketMessage {
 }
 
 impl WebSocketMessage {
-    pub fn from_str(s: &str) -> Result<WebSocketMessage, String> {
-        serde_json::from_str(s).map_err(|err| err.to_string())
+    pub fn from_str(s: &str) -> Result<WebSocketMessage, WSError> {
+        Ok(serde_json::from_str(s)?)
     }
 
     pub fn to_string(&self) -> String {
@@ -160,6 +280,11 @@ impl WebSocketMessage {
 /// - PeerRequest is sent by a node to ask to connect to another node. The
 /// server will send a 'PeerReply' to the corresponding node, which will continue
 /// the protocol by sending its own PeerRequest.
+/// - IceServers is sent by the server after the Challenge, if it knows STUN or
+///   TURN servers the nodes should use in addition to their configured ones.
+/// - Relay carries a message between two nodes that couldn't set up a WebRTC
+///   connection. The server forwards it as long as the sender stays below the
+///   rate limit.
 /// - Done is a standard message that can be sent back to indicate all is well.
 ///
 /// TODO: use the "Challenge" to sign with the private key of the node, so that the server
@@ -172,6 +297,8 @@ pub enum WSSignalMessage {
     ListIDsReply(Vec<NodeInfo>),
     ClearNodes,
     PeerSetup(PeerInfo),
+    IceServers(Vec<IceServer>),
+    Relay(RelayMessage),
     Done,
 }
 
@@ -184,6 +311,8 @@ impl std::fmt::Display for WSSignalMessage {
             WSSignalMessage::ListIDsReply(_) => write!(f, "ListIDsReply"),
             WSSignalMessage::ClearNodes => write!(f, "ClearNodes"),
             WSSignalMessage::PeerSetup(_) => write!(f, "PeerSetup"),
+            WSSignalMessage::IceServers(_) => write!(f, "IceServers"),
+            WSSignalMessage::Relay(_) => write!(f, "Relay"),
             WSSignalMessage::Done => write!(f, "Done"),
         }
     }
@@ -195,3 +324,12 @@ pub struct MessageAnnounce {
     pub challenge: U256,
     pub node_info: NodeInfo,
 }
+
+/// A message from one node to another, sent through the signal server.
+/// The server overwrites `from` with the public key of the sending node.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct RelayMessage {
+    pub from: U256,
+    pub to: U256,
+    pub msg: String,
+}
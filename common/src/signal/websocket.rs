@@ -1,5 +1,16 @@
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum WSError {
+    #[error("signal server unreachable: {0}")]
+    Unreachable(String),
+    #[error("couldn't send message: {0}")]
+    Send(String),
+    #[error("invalid message: {0}")]
+    InvalidMessage(#[from] serde_json::Error),
+}
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub enum WSMessage {
@@ -14,8 +25,8 @@ pub type MessageCallback = Box<dyn FnMut(WSMessage)>;
 #[async_trait(?Send)]
 pub trait WebSocketConnection {
     fn set_cb_wsmessage(&mut self, cb: MessageCallback);
-    fn send(&mut self, msg: String) -> Result<(), String>;
-    fn reconnect(&mut self) -> Result<(), String>;
+    fn send(&mut self, msg: String) -> Result<(), WSError>;
+    fn reconnect(&mut self) -> Result<(), WSError>;
 }
 
 //
@@ -28,7 +39,7 @@ pub type MessageCallbackSend = Box<dyn FnMut(WSMessage) + Send>;
 #[async_trait]
 pub trait WebSocketConnectionSend: Send {
     fn set_cb_wsmessage(&mut self, cb: MessageCallbackSend);
-    async fn send(&mut self, msg: String) -> Result<(), String>;
+    async fn send(&mut self, msg: String) -> Result<(), WSError>;
 }
 
 pub type NewConnectionCallback = Box<dyn FnMut(Box<dyn WebSocketConnectionSend + Send>) + Send>;
@@ -0,0 +1,570 @@
+//! A deterministic in-memory network to test multiple nodes with a plain `cargo test`.
+//! It replaces the websocket connection to the signalling server, the signalling
+//! server itself, and the WebRTC connections with mock implementations.
+//! All messages are passed through a virtual clock, and latency, loss, and
+//! partitions of the network can be controlled.
+use futures::executor::block_on;
+use std::{cell::RefCell, rc::Rc};
+
+use crate::{
+    node::{
+        ext_interface::{DataStorage, Logger},
+        logging::Record,
+        storage::MemoryStorage,
+        Node, NodeError, CONFIG_NAME,
+    },
+    signal::{web_rtc::WebRTCSetupCBMessage, websocket::WSMessage},
+};
+
+pub mod network;
+pub mod signal;
+pub mod web_rtc;
+
+use network::{NetworkConditions, SimulEvent, SimulNet};
+use signal::{SignalServer, SimulWebSocket};
+use web_rtc::SimulConnection;
+
+/// How many milliseconds the virtual clock advances for every step.
+pub const STEP_MS: u64 = 10;
+
+/// Simulator holds a number of nodes connected through a simulated network.
+pub struct Simulator {
+    pub nodes: Vec<Node>,
+    net: Rc<RefCell<SimulNet>>,
+    server: SignalServer,
+    logger: Box<dyn Logger>,
+}
+
+impl Simulator {
+    /// Returns a new simulator with no nodes. The seed is used for all random
+    /// decisions of the network.
+    pub fn new(seed: u64) -> Simulator {
+        let logger = Box::new(SimulLogger::new("simul"));
+        Simulator {
+            nodes: vec![],
+            net: Rc::new(RefCell::new(SimulNet::new(seed))),
+            server: SignalServer::new(logger.clone()),
+            logger,
+        }
+    }
+
+    /// Adds a new node which immediately connects to the signalling server.
+    /// Returns the index of the node.
+    pub fn add_node(&mut self) -> Result<usize, NodeError> {
+        self.add_node_with_config("")
+    }
+
+    /// Adds a new node like `add_node`, which starts with the given config.
+    pub fn add_node_with_config(&mut self, config: &str) -> Result<usize, NodeError> {
+        let storage = MemoryStorage::default();
+        storage.save(CONFIG_NAME, config)?;
+        let idx = self.net.borrow_mut().add_node();
+        let net = Rc::clone(&self.net);
+        let node = Node::with_clock(
+            Box::new(storage),
+            None,
+            Box::new(SimulLogger::new(&format!("node{}", idx))),
+            Box::new(SimulWebSocket::new(Rc::clone(&self.net), idx)),
+            web_rtc::spawner(Rc::clone(&self.net), idx),
+            Rc::new(move || net.borrow().now as f64),
+        )?;
+        self.nodes.push(node);
+        self.server.connect(&mut self.net.borrow_mut(), idx);
+        Ok(idx)
+    }
+
+    /// Sets new conditions for all future messages.
+    pub fn set_conditions(&mut self, conditions: NetworkConditions) {
+        self.net.borrow_mut().conditions = conditions;
+    }
+
+    /// Separates the given nodes from all other nodes. The signalling server
+    /// is still reachable from all nodes.
+    pub fn partition(&mut self, nodes: &[usize]) {
+        self.net.borrow_mut().partition(nodes);
+    }
+
+    /// Lets WebRTC setups between partitioned nodes fail after the given time,
+    /// instead of waiting for the network to heal.
+    pub fn set_ice_timeout(&mut self, ms: Option<u64>) {
+        self.net.borrow_mut().ice_timeout_ms = ms;
+    }
+
+    /// Lets the WebRTC connections between the two nodes fail. Unlike with
+    /// `partition`, they can connect again right away.
+    pub fn disconnect(&mut self, a: usize, b: usize) {
+        self.net.borrow_mut().disconnect(a, b);
+    }
+
+    /// Stops the signalling server. The nodes are told that their websocket
+    /// is closed, and all messages to and from the server are lost.
+    pub fn stop_server(&mut self) {
+        self.net.borrow_mut().server_down = true;
+        let cbs = self.net.borrow().ws_cbs.clone();
+        for cb in cbs {
+            if let Some(cb) = cb.borrow_mut().as_mut() {
+                cb(WSMessage::Closed("signal server stopped".to_string()));
+            };
+        }
+    }
+
+    /// Removes all partitions.
+    pub fn heal(&mut self) {
+        self.net.borrow_mut().heal();
+    }
+
+    /// Returns the time of the virtual clock in milliseconds.
+    pub fn now(&self) -> u64 {
+        self.net.borrow().now
+    }
+
+    /// Runs the simulation for the given time. In every step, all due messages are
+    /// delivered, and then every node processes its messages.
+    pub fn run(&mut self, ms: u64) {
+        let end = self.now() + ms;
+        while self.now() < end {
+            self.step();
+        }
+    }
+
+    /// Advances the virtual clock by STEP_MS.
+    pub fn step(&mut self) {
+        self.net.borrow_mut().now += STEP_MS;
+        loop {
+            let ev = self.net.borrow_mut().pop_due();
+            match ev {
+                Some(ev) => self.deliver(ev),
+                None => break,
+            }
+        }
+        self.process_nodes();
+    }
+
+    /// Lets every node process its messages.
+    fn process_nodes(&mut self) {
+        for (i, node) in self.nodes.iter_mut().enumerate() {
+            if let Err(e) = block_on(node.process()) {
+                self.logger
+                    .warn(&format!("Node {} couldn't process: {}", i, e));
+            }
+        }
+    }
+
+    /// Delivers one event. The callbacks are called without holding a borrow on
+    /// the network, as they might need to access it.
+    fn deliver(&mut self, ev: SimulEvent) {
+        match ev {
+            SimulEvent::ToServer(_, _) | SimulEvent::ToNode(_, _)
+                if self.net.borrow().server_down => {}
+            SimulEvent::ToServer(node, msg) => {
+                self.server.receive(&mut self.net.borrow_mut(), node, msg)
+            }
+            SimulEvent::ToNode(node, msg) => {
+                let cb = Rc::clone(&self.net.borrow().ws_cbs[node]);
+                if let Some(cb) = cb.borrow_mut().as_mut() {
+                    cb(WSMessage::MessageString(msg));
+                };
+            }
+            SimulEvent::SetupIce(ep, ice) => {
+                let cb = Rc::clone(&self.net.borrow().endpoints[ep].setup_cb);
+                if let Some(cb) = cb.borrow().as_ref() {
+                    cb(WebRTCSetupCBMessage::Ice(ice));
+                };
+            }
+            SimulEvent::SetupConnected(ep) => {
+                let cb = Rc::clone(&self.net.borrow().endpoints[ep].setup_cb);
+                let conn = Box::new(SimulConnection::new(Rc::clone(&self.net), ep));
+                if let Some(cb) = cb.borrow().as_ref() {
+                    cb(WebRTCSetupCBMessage::Connection(conn));
+                };
+            }
+            SimulEvent::SetupFailed(ep) => {
+                let cb = Rc::clone(&self.net.borrow().endpoints[ep].setup_cb);
+                if let Some(cb) = cb.borrow().as_ref() {
+                    cb(WebRTCSetupCBMessage::Failed("peer unreachable".to_string()));
+                };
+            }
+            SimulEvent::WebRTCMessage(ep, msg) => {
+                let cb = {
+                    let mut net = self.net.borrow_mut();
+                    let endpoint = &mut net.endpoints[ep];
+                    if !endpoint.connected {
+                        return;
+                    }
+                    endpoint.rx_bytes += msg.len() as u64;
+                    if endpoint.msg_cb.borrow().is_none() {
+                        endpoint.pending.push(msg);
+                        return;
+                    }
+                    Rc::clone(&endpoint.msg_cb)
+                };
+                if let Some(cb) = cb.borrow_mut().as_mut() {
+                    cb(msg);
+                };
+            }
+            SimulEvent::WebRTCEvent(ep, ev) => {
+                let cb = Rc::clone(&self.net.borrow().endpoints[ep].state_cb);
+                if let Some(cb) = cb.borrow_mut().as_mut() {
+                    cb(ev);
+                };
+            }
+        }
+    }
+}
+
+/// Logs everything to stdout, prefixed by the name of the node.
+pub struct SimulLogger {
+    prefix: String,
+}
+
+impl SimulLogger {
+    pub fn new(prefix: &str) -> SimulLogger {
+        SimulLogger {
+            prefix: prefix.to_string(),
+        }
+    }
+}
+
+impl Logger for SimulLogger {
+    fn write(&self, record: &Record) {
+        println!("{}: {:5}: {}", self.prefix, record.level, record);
+    }
+
+    fn clone(&self) -> Box<dyn Logger> {
+        Box::new(SimulLogger::new(&self.prefix))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::{
+        logic::ConnState,
+        network::{tcp_transport::TcpTransport, transport::TransportKind},
+        types::U256,
+    };
+    use crate::signal::web_rtc::{RelayMessage, WSSignalMessage, WebSocketMessage};
+    use futures::future::select;
+
+    fn ping_rx(sim: &Simulator, dst: usize, src: usize) -> u64 {
+        let id = &sim.nodes[src].info.public;
+        sim.nodes[dst]
+            .logic
+            .stats
+            .get(id)
+            .map(|s| s.ping_rx)
+            .unwrap_or(0)
+    }
+
+    fn public(sim: &Simulator, node: usize) -> U256 {
+        sim.nodes[node].info.public.clone()
+    }
+
+    #[test]
+    fn queued_messages_are_delivered() -> Result<(), NodeError> {
+        let mut sim = Simulator::new(1);
+        sim.add_node()?;
+        sim.add_node()?;
+        sim.run(100);
+
+        // The connection is not set up yet, so these messages are queued.
+        let dst = public(&sim, 1);
+        for i in 0..5 {
+            sim.nodes[0].send(&dst, format!("msg{}", i))?;
+        }
+        sim.run(1000);
+        assert_eq!(5, ping_rx(&sim, 1, 0));
+        Ok(())
+    }
+
+    #[test]
+    fn transports_are_advertised() -> Result<(), NodeError> {
+        let mut sim = Simulator::new(7);
+        sim.add_node()?;
+        sim.add_node()?;
+        sim.run(100);
+        sim.nodes[0].list()?;
+        sim.run(100);
+        let list = sim.nodes[0].get_list();
+        assert_eq!(1, list.len());
+        assert_eq!(vec![TransportKind::WebRTC], list[0].transports);
+        Ok(())
+    }
+
+    #[test]
+    fn networks_are_apart() -> Result<(), NodeError> {
+        let mut sim = Simulator::new(10);
+        sim.add_node()?;
+        sim.add_node_with_config("network = \"testnet\"")?;
+        sim.run(100);
+        let dst = public(&sim, 1);
+        sim.nodes[0].send(&dst, "ping".to_string())?;
+        sim.run(1000);
+        assert_eq!(0, ping_rx(&sim, 1, 0));
+        Ok(())
+    }
+
+    #[test]
+    fn misaddressed_relay_is_ignored() -> Result<(), NodeError> {
+        let mut sim = Simulator::new(11);
+        sim.add_node()?;
+        sim.add_node()?;
+        sim.run(100);
+        let dst = public(&sim, 1);
+        sim.nodes[0].send(&dst, "ping".to_string())?;
+
+        // A relay message for another node arrives right before the first
+        // PeerSetup, so node 1 processes both in the same batch.
+        let relay = WebSocketMessage {
+            msg: WSSignalMessage::Relay(RelayMessage {
+                from: public(&sim, 0),
+                to: U256::rnd(),
+                msg: "lost".to_string(),
+            }),
+        }
+        .to_string();
+        let mut slipped = false;
+        while !slipped {
+            sim.net.borrow_mut().now += STEP_MS;
+            loop {
+                let ev = sim.net.borrow_mut().pop_due();
+                match ev {
+                    Some(SimulEvent::ToNode(1, msg)) if msg.contains("PeerSetup") => {
+                        sim.deliver(SimulEvent::ToNode(1, relay.clone()));
+                        sim.deliver(SimulEvent::ToNode(1, msg));
+                        slipped = true;
+                    }
+                    Some(ev) => sim.deliver(ev),
+                    None => break,
+                }
+            }
+            sim.process_nodes();
+        }
+        sim.run(1000);
+        assert_eq!(1, ping_rx(&sim, 1, 0));
+        Ok(())
+    }
+
+    #[test]
+    fn alias_is_announced() -> Result<(), NodeError> {
+        let mut sim = Simulator::new(9);
+        sim.add_node()?;
+        sim.add_node()?;
+        sim.run(100);
+        sim.nodes[1].set_alias(Some("kitchen"))?;
+        sim.run(100);
+        sim.nodes[0].list()?;
+        sim.run(100);
+        let list = sim.nodes[0].get_list();
+        assert_eq!(Some("kitchen".to_string()), list[0].alias);
+        Ok(())
+    }
+
+    #[test]
+    fn tcp_between_nodes() -> Result<(), NodeError> {
+        let mut sim = Simulator::new(8);
+        for i in 0..2 {
+            sim.add_node()?;
+            let tcp = TcpTransport::new(
+                Box::new(SimulLogger::new(&format!("tcp{}", i))),
+                sim.nodes[i].secret(),
+                sim.nodes[i].info.network.clone(),
+                "127.0.0.1:0",
+                None,
+                &sim.nodes[i].wakeup(),
+            );
+            sim.nodes[i].add_transport(Box::new(tcp))?;
+        }
+        // WebRTC cannot connect, so the message must go over TCP.
+        sim.partition(&[1]);
+        sim.run(100);
+        sim.nodes[0].list()?;
+        sim.run(100);
+        let dst = public(&sim, 1);
+        sim.nodes[0].send(&dst, "over tcp".to_string())?;
+
+        // The sockets don't follow the virtual clock, so a step is only done
+        // once they wake up one of the nodes.
+        for _ in 0..100 {
+            if ping_rx(&sim, 1, 0) > 0 {
+                break;
+            }
+            let (w0, w1) = (sim.nodes[0].wakeup(), sim.nodes[1].wakeup());
+            block_on(select(w0.wait(), w1.wait()));
+            sim.step();
+        }
+        assert_eq!(1, ping_rx(&sim, 1, 0));
+        assert_eq!(
+            ConnState::Connected,
+            sim.nodes[0].logic.stats[&dst].outgoing
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn ping_all_nodes() -> Result<(), NodeError> {
+        let mut sim = Simulator::new(2);
+        sim.set_conditions(NetworkConditions {
+            latency_ms: 20,
+            jitter_ms: 30,
+            loss: 0.,
+        });
+        let nbr = 5;
+        for _ in 0..nbr {
+            sim.add_node()?;
+        }
+        sim.run(500);
+        for node in sim.nodes.iter_mut() {
+            node.list()?;
+        }
+        sim.run(500);
+        for node in sim.nodes.iter_mut() {
+            block_on(node.ping("ping"))?;
+        }
+        sim.run(2000);
+        for dst in 0..nbr {
+            for src in 0..nbr {
+                if src != dst {
+                    assert_eq!(1, ping_rx(&sim, dst, src), "{} -> {}", src, dst);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn latency_matrix() -> Result<(), NodeError> {
+        let mut sim = Simulator::new(5);
+        sim.set_conditions(NetworkConditions {
+            latency_ms: 20,
+            jitter_ms: 0,
+            loss: 0.,
+        });
+        let nbr = 3;
+        for _ in 0..nbr {
+            sim.add_node()?;
+        }
+        sim.run(500);
+        for node in sim.nodes.iter_mut() {
+            node.list()?;
+        }
+        sim.run(500);
+        // The second round lets the pongs carry the measurements of the first.
+        for _ in 0..2 {
+            for node in sim.nodes.iter_mut() {
+                block_on(node.ping("ping"))?;
+            }
+            sim.run(1000);
+        }
+        let matrix = sim.nodes[0].latency_matrix();
+        assert_eq!(nbr, matrix.nodes.len());
+        assert_eq!(public(&sim, 0), matrix.nodes[0]);
+        for (i, row) in matrix.rtt_ms.iter().enumerate() {
+            for (j, rtt) in row.iter().enumerate() {
+                assert_eq!(i != j, rtt.is_some(), "{} -> {}", i, j);
+            }
+        }
+        let stat = &sim.nodes[1].logic.stats[&public(&sim, 0)];
+        assert_eq!(2, stat.latency.received);
+        assert_eq!(0, stat.latency.lost);
+        // The first ping waited for the connection. The second one takes
+        // 20ms both ways, but might wait for the next step to be sent.
+        let rtt = stat.latency.rtt_ms.unwrap();
+        assert!((40. ..=40. + STEP_MS as f64).contains(&rtt), "rtt: {}", rtt);
+        assert!(stat.latency.rtt_avg_ms.unwrap() > rtt);
+        Ok(())
+    }
+
+    #[test]
+    fn partition_and_heal() -> Result<(), NodeError> {
+        let mut sim = Simulator::new(3);
+        sim.add_node()?;
+        sim.add_node()?;
+        sim.run(100);
+        let dst = public(&sim, 1);
+        sim.nodes[0].send(&dst, "before".to_string())?;
+        sim.run(1000);
+        assert_eq!(1, ping_rx(&sim, 1, 0));
+
+        sim.partition(&[1]);
+        sim.run(500);
+        sim.nodes[0].send(&dst, "during".to_string())?;
+        sim.run(1000);
+        assert_eq!(1, ping_rx(&sim, 1, 0));
+
+        sim.heal();
+        sim.run(1000);
+        assert_eq!(2, ping_rx(&sim, 1, 0));
+        Ok(())
+    }
+
+    #[test]
+    fn relay_fallback() -> Result<(), NodeError> {
+        let mut sim = Simulator::new(6);
+        sim.set_ice_timeout(Some(500));
+        sim.add_node()?;
+        sim.add_node()?;
+        sim.run(100);
+        sim.nodes[0].list()?;
+        sim.partition(&[1]);
+        sim.run(100);
+
+        let dst = public(&sim, 1);
+        sim.nodes[0].send(&dst, "relayed".to_string())?;
+        sim.run(1000);
+        assert_eq!(1, ping_rx(&sim, 1, 0));
+        let stat = &sim.nodes[0].logic.stats[&dst];
+        assert!(stat.relayed);
+        assert_eq!(ConnState::Relay, stat.outgoing);
+
+        // The pong comes back through the signal server, too.
+        block_on(sim.nodes[0].ping("ping"))?;
+        sim.run(500);
+        assert_eq!(2, ping_rx(&sim, 1, 0));
+        assert_eq!(1, sim.nodes[0].logic.stats[&dst].latency.received);
+        Ok(())
+    }
+
+    #[test]
+    fn signalling_through_peer() -> Result<(), NodeError> {
+        let mut sim = Simulator::new(12);
+        for _ in 0..3 {
+            sim.add_node()?;
+        }
+        sim.run(100);
+        for &(src, dst) in &[(0, 1), (0, 2), (1, 2)] {
+            let dst = public(&sim, dst);
+            sim.nodes[src].send(&dst, "before".to_string())?;
+        }
+        sim.run(1000);
+        assert_eq!(1, ping_rx(&sim, 1, 0));
+
+        // Nodes 0 and 1 set up their connection again through node 2.
+        sim.stop_server();
+        sim.disconnect(0, 1);
+        sim.run(100);
+        let dst = public(&sim, 1);
+        sim.nodes[0].send(&dst, "after".to_string())?;
+        sim.run(1000);
+        assert_eq!(2, ping_rx(&sim, 1, 0));
+        Ok(())
+    }
+
+    #[test]
+    fn lossy_signalling() -> Result<(), NodeError> {
+        let mut sim = Simulator::new(4);
+        sim.set_conditions(NetworkConditions {
+            latency_ms: 10,
+            jitter_ms: 0,
+            loss: 1.,
+        });
+        sim.add_node()?;
+        sim.add_node()?;
+        sim.run(100);
+        let dst = public(&sim, 1);
+        sim.nodes[0].send(&dst, "lost".to_string())?;
+        sim.run(1000);
+        assert_eq!(0, ping_rx(&sim, 1, 0));
+        Ok(())
+    }
+}
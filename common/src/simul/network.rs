@@ -0,0 +1,287 @@
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    rc::Rc,
+};
+
+use crate::signal::{
+    web_rtc::{WebRTCConnectionEvent, WebRTCMessageCB, WebRTCSetupCB, WebRTCStateCB},
+    websocket::MessageCallback,
+};
+
+/// A callback that is shared between the simulated network and the mock
+/// implementations handed out to the nodes.
+pub type SharedCB<T> = Rc<RefCell<Option<T>>>;
+
+/// Conditions of the simulated network. They apply to the websocket
+/// connections to the signalling server as well as to the WebRTC connections.
+#[derive(Debug, Clone)]
+pub struct NetworkConditions {
+    /// Base delay for every message
+    pub latency_ms: u64,
+    /// A random delay between 0 and jitter_ms is added to every message
+    pub jitter_ms: u64,
+    /// Probability between 0 and 1 that a message is lost
+    pub loss: f64,
+}
+
+impl Default for NetworkConditions {
+    fn default() -> Self {
+        NetworkConditions {
+            latency_ms: 10,
+            jitter_ms: 0,
+            loss: 0.,
+        }
+    }
+}
+
+/// All events that can happen in the simulated network. Each event is
+/// scheduled at a given time of the virtual clock.
+#[derive(Debug)]
+pub enum SimulEvent {
+    /// Message from a node to the signalling server
+    ToServer(usize, String),
+    /// Message from the signalling server to a node
+    ToNode(usize, String),
+    /// ICE candidate of a WebRTC endpoint is available
+    SetupIce(usize, String),
+    /// WebRTC endpoint is connected to its peer
+    SetupConnected(usize),
+    /// WebRTC endpoint couldn't reach its peer
+    SetupFailed(usize),
+    /// Message for a WebRTC endpoint
+    WebRTCMessage(usize, String),
+    /// State change of a WebRTC endpoint
+    WebRTCEvent(usize, WebRTCConnectionEvent),
+}
+
+/// One side of a simulated WebRTC connection.
+pub struct Endpoint {
+    pub node: usize,
+    pub peer: Option<usize>,
+    pub local_description: bool,
+    pub remote_ice: bool,
+    pub connected: bool,
+    // once a connection is closed, it cannot be used anymore.
+    pub closed: bool,
+    pub setup_cb: SharedCB<WebRTCSetupCB>,
+    pub msg_cb: SharedCB<WebRTCMessageCB>,
+    pub state_cb: SharedCB<WebRTCStateCB>,
+    pub pending: Vec<String>,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+}
+
+impl Endpoint {
+    fn new(node: usize) -> Endpoint {
+        Endpoint {
+            node,
+            peer: None,
+            local_description: false,
+            remote_ice: false,
+            connected: false,
+            closed: false,
+            setup_cb: Rc::new(RefCell::new(None)),
+            msg_cb: Rc::new(RefCell::new(None)),
+            state_cb: Rc::new(RefCell::new(None)),
+            pending: vec![],
+            rx_bytes: 0,
+            tx_bytes: 0,
+        }
+    }
+}
+
+/// SimulNet holds the virtual clock and all messages in transit.
+/// Every random decision is taken from a seeded RNG, so that a simulation with
+/// the same seed and the same inputs gives the same result.
+pub struct SimulNet {
+    pub now: u64,
+    pub conditions: NetworkConditions,
+    /// If set, WebRTC setups between unreachable nodes fail after this time,
+    /// like ICE does. Else they wait until the network is healed.
+    pub ice_timeout_ms: Option<u64>,
+    /// If set, the signalling server is down and all messages to and from it
+    /// are lost.
+    pub server_down: bool,
+    pub endpoints: Vec<Endpoint>,
+    pub ws_cbs: Vec<SharedCB<MessageCallback>>,
+    rng: StdRng,
+    seq: u64,
+    events: BTreeMap<(u64, u64), SimulEvent>,
+    groups: Vec<usize>,
+    // websockets and data channels deliver their messages in order, so every
+    // message must arrive after the previous one on the same channel.
+    last_arrival: HashMap<(u8, usize), u64>,
+}
+
+impl SimulNet {
+    pub fn new(seed: u64) -> SimulNet {
+        SimulNet {
+            now: 0,
+            conditions: NetworkConditions::default(),
+            ice_timeout_ms: None,
+            server_down: false,
+            endpoints: vec![],
+            ws_cbs: vec![],
+            rng: StdRng::seed_from_u64(seed),
+            seq: 0,
+            events: BTreeMap::new(),
+            groups: vec![],
+            last_arrival: HashMap::new(),
+        }
+    }
+
+    /// Adds a new node to the network and returns its index.
+    pub fn add_node(&mut self) -> usize {
+        self.ws_cbs.push(Rc::new(RefCell::new(None)));
+        self.groups.push(0);
+        self.ws_cbs.len() - 1
+    }
+
+    /// Adds a new WebRTC endpoint for the given node and returns its index.
+    pub fn add_endpoint(&mut self, node: usize) -> usize {
+        self.endpoints.push(Endpoint::new(node));
+        self.endpoints.len() - 1
+    }
+
+    /// Schedules an event after the given delay.
+    pub fn schedule(&mut self, delay: u64, ev: SimulEvent) {
+        self.seq += 1;
+        self.events.insert((self.now + delay, self.seq), ev);
+    }
+
+    /// Returns the next event that is due, if any.
+    pub fn pop_due(&mut self) -> Option<SimulEvent> {
+        let key = *self.events.keys().next()?;
+        if key.0 > self.now {
+            return None;
+        }
+        self.events.remove(&key)
+    }
+
+    /// Returns the delay for a message, following the network conditions.
+    pub fn delay(&mut self) -> u64 {
+        let jitter = match self.conditions.jitter_ms {
+            0 => 0,
+            j => self.rng.gen_range(0..=j),
+        };
+        self.conditions.latency_ms + jitter
+    }
+
+    /// Returns true if the message is to be lost.
+    pub fn lost(&mut self) -> bool {
+        self.conditions.loss > 0. && self.rng.gen_bool(self.conditions.loss.min(1.))
+    }
+
+    /// Sends a message over the lossy network. Messages on the same channel
+    /// keep their order.
+    pub fn send_lossy(&mut self, ev: SimulEvent) {
+        if self.lost() {
+            return;
+        }
+        let channel = match ev {
+            SimulEvent::ToServer(node, _) => (0, node),
+            SimulEvent::ToNode(node, _) => (1, node),
+            SimulEvent::WebRTCMessage(ep, _) => (2, ep),
+            _ => (3, 0),
+        };
+        let earliest = self.last_arrival.get(&channel).cloned().unwrap_or(0);
+        let arrival = (self.now + self.delay()).max(earliest);
+        self.last_arrival.insert(channel, arrival);
+        self.schedule(arrival - self.now, ev);
+    }
+
+    /// Returns true if the two nodes are in the same partition.
+    pub fn reachable(&self, a: usize, b: usize) -> bool {
+        self.groups[a] == self.groups[b]
+    }
+
+    /// Puts the given nodes in a separate partition. All WebRTC connections
+    /// between the partitions fail.
+    pub fn partition(&mut self, nodes: &[usize]) {
+        for &n in nodes {
+            self.groups[n] = 1;
+        }
+        self.fail_connections(|net, a, b| !net.reachable(a, b));
+    }
+
+    /// Lets all WebRTC connections between the two nodes fail, while they can
+    /// still set up new ones.
+    pub fn disconnect(&mut self, a: usize, b: usize) {
+        self.fail_connections(|_, x, y| (x, y) == (a, b) || (x, y) == (b, a));
+    }
+
+    // Lets the connected endpoints fail if `broken` returns true for their
+    // node and the node of their peer.
+    fn fail_connections(&mut self, broken: impl Fn(&SimulNet, usize, usize) -> bool) {
+        for ep in 0..self.endpoints.len() {
+            let e = &self.endpoints[ep];
+            if let (true, Some(peer)) = (e.connected, e.peer) {
+                if broken(self, e.node, self.endpoints[peer].node) {
+                    self.endpoints[ep].connected = false;
+                    self.endpoints[ep].closed = true;
+                    self.schedule(
+                        0,
+                        SimulEvent::WebRTCEvent(ep, WebRTCConnectionEvent::Failed),
+                    );
+                }
+            }
+        }
+    }
+
+    /// Removes all partitions and lets waiting WebRTC setups finish.
+    pub fn heal(&mut self) {
+        self.groups.iter_mut().for_each(|g| *g = 0);
+        for ep in 0..self.endpoints.len() {
+            self.try_connect(ep);
+        }
+    }
+
+    /// Connects the endpoint with its peer if both sides exchanged all
+    /// necessary information and are reachable.
+    pub fn try_connect(&mut self, ep: usize) {
+        let peer = match self.endpoints[ep].peer {
+            Some(p) => p,
+            None => return,
+        };
+        let ready = |e: &Endpoint| e.local_description && e.remote_ice && !e.connected && !e.closed;
+        if !ready(&self.endpoints[ep]) || !ready(&self.endpoints[peer]) {
+            return;
+        }
+        if !self.reachable(self.endpoints[ep].node, self.endpoints[peer].node) {
+            if let Some(timeout) = self.ice_timeout_ms {
+                for e in &[ep, peer] {
+                    self.endpoints[*e].closed = true;
+                    self.schedule(timeout, SimulEvent::SetupFailed(*e));
+                }
+            }
+            return;
+        }
+        for e in &[ep, peer] {
+            self.endpoints[*e].connected = true;
+            let delay = self.delay();
+            self.schedule(delay, SimulEvent::SetupConnected(*e));
+        }
+    }
+
+    /// Closes the connection of the given endpoint and informs the peer.
+    pub fn close(&mut self, ep: usize) {
+        if !self.endpoints[ep].connected {
+            return;
+        }
+        self.endpoints[ep].connected = false;
+        self.endpoints[ep].closed = true;
+        if let Some(peer) = self.endpoints[ep].peer {
+            if self.endpoints[peer].connected {
+                self.endpoints[peer].connected = false;
+                self.endpoints[peer].closed = true;
+                let delay = self.delay();
+                self.schedule(
+                    delay,
+                    SimulEvent::WebRTCEvent(peer, WebRTCConnectionEvent::Closed),
+                );
+            }
+        }
+    }
+}
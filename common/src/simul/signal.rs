@@ -0,0 +1,185 @@
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    rc::Rc,
+};
+
+use crate::{
+    node::{config::NodeInfo, ext_interface::Logger, types::U256},
+    signal::{
+        relay::RateLimit,
+        web_rtc::{WSSignalMessage, WebSocketMessage},
+        websocket::{MessageCallback, WSError, WebSocketConnection},
+    },
+};
+
+use super::network::{SharedCB, SimulEvent, SimulNet};
+
+/// A mock signalling server that behaves like the one in `cli/signal`, but
+/// only uses the simulated network.
+pub struct SignalServer {
+    infos: BTreeMap<usize, NodeInfo>,
+    limits: HashMap<usize, RateLimit>,
+    logger: Box<dyn Logger>,
+}
+
+impl SignalServer {
+    pub fn new(logger: Box<dyn Logger>) -> SignalServer {
+        SignalServer {
+            infos: BTreeMap::new(),
+            limits: HashMap::new(),
+            logger,
+        }
+    }
+
+    /// A new node connected to the server and gets its challenge.
+    pub fn connect(&mut self, net: &mut SimulNet, node: usize) {
+        self.send(net, node, WSSignalMessage::Challenge(U256::rnd()));
+    }
+
+    /// Treats a message from a node.
+    pub fn receive(&mut self, net: &mut SimulNet, node: usize, msg: String) {
+        let msg = match WebSocketMessage::from_str(&msg) {
+            Ok(wsm) => wsm.msg,
+            Err(e) => {
+                self.logger
+                    .error(&format!("Couldn't parse message from {}: {}", node, e));
+                return;
+            }
+        };
+        match msg {
+            WSSignalMessage::Announce(ma) => {
+                if let Err(e) = ma.node_info.verify_fresh() {
+                    self.logger
+                        .warn(&format!("Ignoring announce of {}: {}", node, e));
+                    return;
+                }
+                let public = ma.node_info.public.clone();
+                self.infos.retain(|_, ni| ni.public != public);
+                self.infos.insert(node, ma.node_info);
+            }
+            WSSignalMessage::ClearNodes => self.infos.clear(),
+            WSSignalMessage::ListIDsRequest => {
+                let network = match self.infos.get(&node) {
+                    Some(ni) => ni.network.clone(),
+                    None => return,
+                };
+                let list = self
+                    .infos
+                    .values()
+                    .filter(|ni| ni.network == network)
+                    .cloned()
+                    .collect();
+                self.send(net, node, WSSignalMessage::ListIDsReply(list));
+            }
+            WSSignalMessage::PeerSetup(pi) => {
+                let src = match self.infos.get(&node) {
+                    Some(ni) => ni.public.clone(),
+                    None => return,
+                };
+                let dst = match pi.get_remote(&src) {
+                    Some(dst) => dst,
+                    None => {
+                        self.logger
+                            .error("Node sent a PeerSetup without including itself");
+                        return;
+                    }
+                };
+                match self.node(&dst) {
+                    Some(dst_node) if self.same_network(node, dst_node) => {
+                        self.send(net, dst_node, WSSignalMessage::PeerSetup(pi))
+                    }
+                    Some(_) => self.logger.warn(&format!(
+                        "Dropping PeerSetup from {} to another network",
+                        node
+                    )),
+                    None => {}
+                }
+            }
+            WSSignalMessage::Relay(mut rm) => {
+                rm.from = match self.infos.get(&node) {
+                    Some(ni) => ni.public.clone(),
+                    None => return,
+                };
+                let now = net.now as f64;
+                let limit = self
+                    .limits
+                    .entry(node)
+                    .or_insert_with(|| RateLimit::new(now));
+                if !limit.allow(now, rm.msg.len()) {
+                    self.logger
+                        .warn(&format!("Node {} is over the relay limit", node));
+                    return;
+                }
+                match self.node(&rm.to) {
+                    Some(dst_node) if self.same_network(node, dst_node) => {
+                        self.send(net, dst_node, WSSignalMessage::Relay(rm))
+                    }
+                    Some(_) => self
+                        .logger
+                        .warn(&format!("Dropping relay message from {}", node)),
+                    None => {}
+                }
+            }
+            msg => self
+                .logger
+                .info(&format!("Got unusable message from {}: {}", node, msg)),
+        }
+    }
+
+    /// Returns the index of the node with the given public key.
+    fn node(&self, public: &U256) -> Option<usize> {
+        self.infos
+            .iter()
+            .find(|(_, ni)| &ni.public == public)
+            .map(|(n, _)| *n)
+    }
+
+    /// Nodes can only set up connections to nodes of the same network.
+    fn same_network(&self, a: usize, b: usize) -> bool {
+        match (self.infos.get(&a), self.infos.get(&b)) {
+            (Some(na), Some(nb)) => na.network == nb.network,
+            _ => false,
+        }
+    }
+
+    fn send(&self, net: &mut SimulNet, node: usize, msg: WSSignalMessage) {
+        net.send_lossy(SimulEvent::ToNode(
+            node,
+            WebSocketMessage { msg }.to_string(),
+        ));
+    }
+}
+
+/// The websocket connection of a node to the mock signalling server.
+pub struct SimulWebSocket {
+    node: usize,
+    net: Rc<RefCell<SimulNet>>,
+    cb: SharedCB<MessageCallback>,
+}
+
+impl SimulWebSocket {
+    pub fn new(net: Rc<RefCell<SimulNet>>, node: usize) -> SimulWebSocket {
+        let cb = Rc::clone(&net.borrow().ws_cbs[node]);
+        SimulWebSocket { node, net, cb }
+    }
+}
+
+impl WebSocketConnection for SimulWebSocket {
+    fn set_cb_wsmessage(&mut self, cb: MessageCallback) {
+        self.cb.borrow_mut().replace(cb);
+    }
+
+    fn send(&mut self, msg: String) -> Result<(), WSError> {
+        let mut net = self.net.borrow_mut();
+        if net.server_down {
+            return Err(WSError::Unreachable("signal server is down".to_string()));
+        }
+        net.send_lossy(SimulEvent::ToServer(self.node, msg));
+        Ok(())
+    }
+
+    fn reconnect(&mut self) -> Result<(), WSError> {
+        Ok(())
+    }
+}
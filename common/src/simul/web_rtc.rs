@@ -0,0 +1,189 @@
+use async_trait::async_trait;
+use std::{cell::RefCell, rc::Rc};
+
+use crate::signal::web_rtc::{
+    ConnType, ConnectionError, ConnectionStateMap, SetupError, WebRTCConnection,
+    WebRTCConnectionSetup, WebRTCConnectionState, WebRTCMessageCB, WebRTCSetupCB, WebRTCSpawner,
+    WebRTCStateCB,
+};
+
+use super::network::{SimulEvent, SimulNet};
+
+/// Returns a spawner that creates simulated WebRTC connections for the given node.
+pub fn spawner(net: Rc<RefCell<SimulNet>>, node: usize) -> WebRTCSpawner {
+    Box::new(move |state, _ice_servers| {
+        let ep = net.borrow_mut().add_endpoint(node);
+        Ok(Box::new(SimulSetup {
+            ep,
+            state,
+            net: Rc::clone(&net),
+        }))
+    })
+}
+
+/// Parses strings of the form "prefix:index" as passed between the endpoints.
+fn parse_id(prefix: &str, s: &str) -> Option<usize> {
+    s.strip_prefix(prefix)
+        .and_then(|id| id.parse::<usize>().ok())
+}
+
+/// Simulates the setup of a WebRTC connection. Instead of SDPs and ICE
+/// candidates, the index of the endpoint is passed around.
+pub struct SimulSetup {
+    ep: usize,
+    state: WebRTCConnectionState,
+    net: Rc<RefCell<SimulNet>>,
+}
+
+impl SimulSetup {
+    fn assert_state(&self, state: WebRTCConnectionState) -> Result<(), SetupError> {
+        if self.state != state {
+            return Err(SetupError::WrongState(self.state));
+        }
+        Ok(())
+    }
+
+    fn set_local_description(&self) {
+        let mut net = self.net.borrow_mut();
+        net.endpoints[self.ep].local_description = true;
+        net.schedule(0, SimulEvent::SetupIce(self.ep, format!("ice:{}", self.ep)));
+    }
+}
+
+#[async_trait(?Send)]
+impl WebRTCConnectionSetup for SimulSetup {
+    async fn make_offer(&mut self) -> Result<String, SetupError> {
+        self.assert_state(WebRTCConnectionState::Initializer)?;
+        self.set_local_description();
+        Ok(format!("offer:{}", self.ep))
+    }
+
+    async fn make_answer(&mut self, offer: String) -> Result<String, SetupError> {
+        self.assert_state(WebRTCConnectionState::Follower)?;
+        let peer = parse_id("offer:", &offer)
+            .ok_or_else(|| SetupError::Answer(format!("Invalid offer {}", offer)))?;
+        {
+            let mut net = self.net.borrow_mut();
+            if peer >= net.endpoints.len() {
+                return Err(SetupError::Answer(format!("Unknown offer {}", offer)));
+            }
+            net.endpoints[self.ep].peer = Some(peer);
+            net.endpoints[peer].peer = Some(self.ep);
+        }
+        self.set_local_description();
+        Ok(format!("answer:{}", self.ep))
+    }
+
+    async fn use_answer(&mut self, answer: String) -> Result<(), SetupError> {
+        self.assert_state(WebRTCConnectionState::Initializer)?;
+        let peer = parse_id("answer:", &answer);
+        match self.net.borrow().endpoints[self.ep].peer {
+            Some(p) if Some(p) == peer => Ok(()),
+            _ => Err(SetupError::UseAnswer(format!(
+                "Answer {} doesn't match offer",
+                answer
+            ))),
+        }
+    }
+
+    async fn set_callback(&mut self, cb: WebRTCSetupCB) {
+        let setup_cb = Rc::clone(&self.net.borrow().endpoints[self.ep].setup_cb);
+        setup_cb.borrow_mut().replace(cb);
+    }
+
+    async fn ice_put(&mut self, ice: String) -> Result<(), SetupError> {
+        let peer = parse_id("ice:", &ice);
+        let mut net = self.net.borrow_mut();
+        if peer.is_none() || net.endpoints[self.ep].peer != peer {
+            return Err(SetupError::Ice(format!("Got ice {} from wrong peer", ice)));
+        }
+        net.endpoints[self.ep].remote_ice = true;
+        net.try_connect(self.ep);
+        Ok(())
+    }
+
+    async fn wait_gathering(&mut self) -> Result<(), SetupError> {
+        Ok(())
+    }
+
+    async fn print_states(&mut self) {}
+
+    fn close(&mut self) {
+        self.net.borrow_mut().close(self.ep);
+    }
+}
+
+/// An established simulated WebRTC connection.
+pub struct SimulConnection {
+    ep: usize,
+    net: Rc<RefCell<SimulNet>>,
+}
+
+impl SimulConnection {
+    pub fn new(net: Rc<RefCell<SimulNet>>, ep: usize) -> SimulConnection {
+        SimulConnection { ep, net }
+    }
+}
+
+impl Drop for SimulConnection {
+    fn drop(&mut self) {
+        if let Ok(mut net) = self.net.try_borrow_mut() {
+            net.close(self.ep);
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl WebRTCConnection for SimulConnection {
+    fn send(&self, s: String) -> Result<(), ConnectionError> {
+        let mut net = self.net.borrow_mut();
+        let (connected, peer) = {
+            let ep = &net.endpoints[self.ep];
+            (ep.connected, ep.peer)
+        };
+        match (connected, peer) {
+            (true, Some(peer)) => {
+                net.endpoints[self.ep].tx_bytes += s.len() as u64;
+                net.send_lossy(SimulEvent::WebRTCMessage(peer, s));
+                Ok(())
+            }
+            _ => Err(ConnectionError::Closed),
+        }
+    }
+
+    fn set_cb_message(&self, mut cb: WebRTCMessageCB) {
+        let (pending, msg_cb) = {
+            let mut net = self.net.borrow_mut();
+            let ep = &mut net.endpoints[self.ep];
+            (
+                ep.pending.drain(..).collect::<Vec<String>>(),
+                Rc::clone(&ep.msg_cb),
+            )
+        };
+        for msg in pending {
+            cb(msg);
+        }
+        msg_cb.borrow_mut().replace(cb);
+    }
+
+    fn set_cb_state(&self, cb: WebRTCStateCB) {
+        let state_cb = Rc::clone(&self.net.borrow().endpoints[self.ep].state_cb);
+        state_cb.borrow_mut().replace(cb);
+    }
+
+    async fn get_state(&self) -> Result<ConnectionStateMap, ConnectionError> {
+        let net = self.net.borrow();
+        let ep = &net.endpoints[self.ep];
+        Ok(ConnectionStateMap {
+            type_local: ConnType::Host,
+            type_remote: ConnType::Host,
+            rx_bytes: ep.rx_bytes,
+            tx_bytes: ep.tx_bytes,
+            delay_ms: net.conditions.latency_ms as u32,
+        })
+    }
+
+    fn close(&self) {
+        self.net.borrow_mut().close(self.ep);
+    }
+}
@@ -0,0 +1,353 @@
+//! Stores the data of the node in IndexedDB, which can hold a lot more data
+//! than the localStorage.
+//! `IndexedDb` gives asynchronous access to the database. As the node uses the
+//! synchronous `DataStorage`, `IndexedDbStorage` keeps a copy of all values in
+//! memory and writes the changes to the database in the background.
+//! So the data of the node is limited by the memory of the tab, not only by
+//! the quota of the browser. Big data that is not needed synchronously
+//! should use `IndexedDb` directly, with keys the node doesn't use.
+use std::{cell::RefCell, collections::BTreeMap, rc::Rc};
+
+use common::node::{
+    ext_interface::{DataStorage, StorageError},
+    storage::StorageOp,
+    CONFIG_BACKUP_NAME, CONFIG_NAME,
+};
+use js_sys::{Array, Function, Promise, Reflect};
+use wasm_bindgen::{prelude::*, JsCast};
+use wasm_bindgen_futures::{spawn_local, JsFuture};
+use web_sys::{window, IdbDatabase, IdbRequest, IdbTransaction, IdbTransactionMode};
+
+use crate::{logs::wait_ms, storage_logs::LocalStorage};
+
+const DB_NAME: &str = "fledger";
+const DB_VERSION: u32 = 1;
+const STORE_NAME: &str = "data";
+
+/// The keys moved out of the localStorage when the database is opened.
+const LOCAL_STORAGE_KEYS: [&str; 2] = [CONFIG_NAME, CONFIG_BACKUP_NAME];
+
+fn js_error(e: JsValue) -> String {
+    match e.dyn_ref::<js_sys::Error>() {
+        Some(err) => String::from(err.message()),
+        None => format!("{:?}", e),
+    }
+}
+
+/// Resolves with the result of the request once it succeeded.
+async fn request(req: &IdbRequest) -> Result<JsValue, JsValue> {
+    let promise = Promise::new(&mut |resolve: Function, reject: Function| {
+        let r = req.clone();
+        req.set_onsuccess(Some(
+            Closure::once_into_js(move || {
+                let _ = resolve.call1(&JsValue::NULL, &r.result().unwrap_or(JsValue::UNDEFINED));
+            })
+            .unchecked_ref(),
+        ));
+        let r = req.clone();
+        req.set_onerror(Some(
+            Closure::once_into_js(move || {
+                let error = r.error().ok().flatten().map(JsValue::from);
+                let _ = reject.call1(&JsValue::NULL, &error.unwrap_or(JsValue::UNDEFINED));
+            })
+            .unchecked_ref(),
+        ));
+    });
+    JsFuture::from(promise).await
+}
+
+/// Resolves once all requests of the transaction are written.
+async fn complete(tx: &IdbTransaction) -> Result<JsValue, JsValue> {
+    let promise = Promise::new(&mut |resolve: Function, reject: Function| {
+        tx.set_oncomplete(Some(
+            Closure::once_into_js(move || {
+                let _ = resolve.call0(&JsValue::NULL);
+            })
+            .unchecked_ref(),
+        ));
+        let t = tx.clone();
+        let failed = Closure::once_into_js(move || {
+            let error = t.error().map(JsValue::from);
+            let _ = reject.call1(&JsValue::NULL, &error.unwrap_or(JsValue::UNDEFINED));
+        });
+        tx.set_onerror(Some(failed.unchecked_ref()));
+        tx.set_onabort(Some(failed.unchecked_ref()));
+    });
+    JsFuture::from(promise).await
+}
+
+/// Asynchronous access to the key/value store of the node in IndexedDB.
+pub struct IndexedDb {
+    db: IdbDatabase,
+}
+
+impl IndexedDb {
+    /// Opens the database, creating it if necessary.
+    pub async fn open() -> Result<IndexedDb, StorageError> {
+        let factory = window()
+            .ok_or_else(|| StorageError::Unavailable("no window".to_string()))?
+            .indexed_db()
+            .map_err(|e| StorageError::Unavailable(js_error(e)))?
+            .ok_or_else(|| StorageError::Unavailable("no IndexedDB".to_string()))?;
+        let req = factory
+            .open_with_u32(DB_NAME, DB_VERSION)
+            .map_err(|e| StorageError::Unavailable(js_error(e)))?;
+        let r = req.clone();
+        req.set_onupgradeneeded(Some(
+            Closure::once_into_js(move || {
+                if let Ok(db) = r.result() {
+                    let db: IdbDatabase = db.unchecked_into();
+                    if !db.object_store_names().contains(STORE_NAME) {
+                        let _ = db.create_object_store(STORE_NAME);
+                    }
+                }
+            })
+            .unchecked_ref(),
+        ));
+        let db = request(&req)
+            .await
+            .map_err(|e| StorageError::Unavailable(js_error(e)))?;
+        Ok(IndexedDb {
+            db: db.unchecked_into(),
+        })
+    }
+
+    fn transaction(&self, mode: IdbTransactionMode) -> Result<IdbTransaction, JsValue> {
+        self.db.transaction_with_str_and_mode(STORE_NAME, mode)
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Option<String>, StorageError> {
+        let get = async {
+            let store = self
+                .transaction(IdbTransactionMode::Readonly)?
+                .object_store(STORE_NAME)?;
+            request(&store.get(&JsValue::from_str(key))?).await
+        };
+        Ok(get
+            .await
+            .map_err(|e| StorageError::Load(js_error(e)))?
+            .as_string())
+    }
+
+    /// Returns all keys and values, sorted by key.
+    pub async fn get_all(&self) -> Result<Vec<(String, String)>, StorageError> {
+        let get_all = async {
+            let store = self
+                .transaction(IdbTransactionMode::Readonly)?
+                .object_store(STORE_NAME)?;
+            let keys = store.get_all_keys()?;
+            let values = store.get_all()?;
+            Ok((request(&keys).await?, request(&values).await?))
+        };
+        let (keys, values): (JsValue, JsValue) = get_all
+            .await
+            .map_err(|e: JsValue| StorageError::Load(js_error(e)))?;
+        Ok(Array::from(&keys)
+            .iter()
+            .zip(Array::from(&values).iter())
+            .filter_map(|(key, value)| Some((key.as_string()?, value.as_string()?)))
+            .collect())
+    }
+
+    /// Writes all operations in one transaction, so either all or none of
+    /// them are applied.
+    pub async fn apply(&self, ops: &[StorageOp]) -> Result<(), StorageError> {
+        let apply = async {
+            let tx = self.transaction(IdbTransactionMode::Readwrite)?;
+            let store = tx.object_store(STORE_NAME)?;
+            for op in ops {
+                match op {
+                    StorageOp::Save(key, value) => {
+                        store.put_with_key(&JsValue::from_str(value), &JsValue::from_str(key))?
+                    }
+                    StorageOp::Remove(key) => store.delete(&JsValue::from_str(key))?,
+                };
+            }
+            complete(&tx).await
+        };
+        apply
+            .await
+            .map(|_| ())
+            .map_err(|e| StorageError::Save(js_error(e)))
+    }
+
+    /// Returns the bytes used by this site, and the bytes it may use, as
+    /// estimated by the browser.
+    pub async fn estimate() -> Result<(f64, f64), StorageError> {
+        let estimate = async {
+            let promise = window()
+                .ok_or_else(|| JsValue::from_str("no window"))?
+                .navigator()
+                .storage()
+                .estimate()?;
+            let estimate = JsFuture::from(promise).await?;
+            let usage = Reflect::get(&estimate, &"usage".into())?;
+            let quota = Reflect::get(&estimate, &"quota".into())?;
+            Ok((usage.as_f64().unwrap_or(0.), quota.as_f64().unwrap_or(f64::INFINITY)))
+        };
+        estimate
+            .await
+            .map_err(|e: JsValue| StorageError::Unavailable(js_error(e)))
+    }
+
+    /// Moves the config from the localStorage, where it was kept before, to
+    /// the database. A config already in the database is kept.
+    async fn migrate_local_storage(&self) -> Result<(), StorageError> {
+        let local = LocalStorage {};
+        let mut ops = vec![];
+        for key in LOCAL_STORAGE_KEYS.iter() {
+            if let Some(value) = local.get(key)? {
+                if self.get(key).await?.is_none() {
+                    ops.push(StorageOp::Save(key.to_string(), value));
+                }
+            }
+        }
+        self.apply(&ops).await?;
+        for key in LOCAL_STORAGE_KEYS.iter() {
+            local.remove(key)?;
+        }
+        Ok(())
+    }
+}
+
+/// Implements `DataStorage` on top of IndexedDB. All values are read when
+/// the database is opened and kept in memory, and changes are written in the
+/// background.
+/// Writes that don't fit in the quota of the browser fail right away. Other
+/// failures are only known once the write ran, so they are returned by
+/// `flush`, and the value in memory is not the stored one anymore.
+pub struct IndexedDbStorage {
+    db: Rc<IndexedDb>,
+    cache: Rc<RefCell<BTreeMap<String, String>>>,
+    // the bytes that can still be written
+    available: Rc<RefCell<f64>>,
+    // the number of writes not yet done
+    pending: Rc<RefCell<usize>>,
+    // the first write that failed since the last flush
+    failed: Rc<RefCell<Option<StorageError>>>,
+}
+
+impl IndexedDbStorage {
+    /// Opens the database, moves the config out of the localStorage, and
+    /// reads all values.
+    pub async fn open() -> Result<IndexedDbStorage, StorageError> {
+        let db = IndexedDb::open().await?;
+        db.migrate_local_storage().await?;
+        let cache = db.get_all().await?.into_iter().collect();
+        let available = match IndexedDb::estimate().await {
+            Ok((usage, quota)) => quota - usage,
+            Err(_) => f64::INFINITY,
+        };
+        Ok(IndexedDbStorage {
+            db: Rc::new(db),
+            cache: Rc::new(RefCell::new(cache)),
+            available: Rc::new(RefCell::new(available)),
+            pending: Rc::new(RefCell::new(0)),
+            failed: Rc::new(RefCell::new(None)),
+        })
+    }
+
+    /// Waits until all changes are written to the database, and returns the
+    /// error of the first write that failed since the last call. This must
+    /// be called before reloading the page.
+    pub async fn flush(&self) -> Result<(), StorageError> {
+        while *self.pending.borrow() > 0 {
+            wait_ms(10).await;
+        }
+        match self.failed.borrow_mut().take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Browsers count two bytes per character.
+    fn size(key: &str, value: &str) -> f64 {
+        ((key.len() + value.len()) * 2) as f64
+    }
+
+    fn write(&self, ops: Vec<StorageOp>) -> Result<(), StorageError> {
+        let mut cache = self.cache.borrow_mut();
+        let needed: f64 = ops
+            .iter()
+            .map(|op| {
+                let old = cache
+                    .get(op.key())
+                    .map_or(0., |value| IndexedDbStorage::size(op.key(), value));
+                match op {
+                    StorageOp::Save(key, value) => IndexedDbStorage::size(key, value) - old,
+                    StorageOp::Remove(_) => -old,
+                }
+            })
+            .sum();
+        let available = *self.available.borrow();
+        if needed > available {
+            return Err(StorageError::Save(format!(
+                "quota exceeded: need {} bytes, but only {} are available",
+                needed, available
+            )));
+        }
+        *self.available.borrow_mut() -= needed;
+        for op in ops.iter() {
+            match op {
+                StorageOp::Save(key, value) => cache.insert(key.clone(), value.clone()),
+                StorageOp::Remove(key) => cache.remove(key),
+            };
+        }
+
+        *self.pending.borrow_mut() += 1;
+        let db = Rc::clone(&self.db);
+        let available = Rc::clone(&self.available);
+        let pending = Rc::clone(&self.pending);
+        let failed = Rc::clone(&self.failed);
+        spawn_local(async move {
+            if let Err(e) = db.apply(&ops).await {
+                console_warn!("Couldn't write to IndexedDB: {}", e);
+                if let Ok((usage, quota)) = IndexedDb::estimate().await {
+                    *available.borrow_mut() = quota - usage;
+                }
+                failed.borrow_mut().get_or_insert(e);
+            }
+            *pending.borrow_mut() -= 1;
+        });
+        Ok(())
+    }
+}
+
+impl DataStorage for IndexedDbStorage {
+    fn get(&self, key: &str) -> Result<Option<String>, StorageError> {
+        Ok(self.cache.borrow().get(key).cloned())
+    }
+
+    fn save(&self, key: &str, value: &str) -> Result<(), StorageError> {
+        self.write(vec![StorageOp::Save(key.to_string(), value.to_string())])
+    }
+
+    fn remove(&self, key: &str) -> Result<(), StorageError> {
+        self.write(vec![StorageOp::Remove(key.to_string())])
+    }
+
+    fn keys(&self, prefix: &str) -> Result<Vec<String>, StorageError> {
+        Ok(self
+            .cache
+            .borrow()
+            .range(prefix.to_string()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, _)| key.clone())
+            .collect())
+    }
+
+    fn box_clone(&self) -> Box<dyn DataStorage> {
+        Box::new(IndexedDbStorage {
+            db: Rc::clone(&self.db),
+            cache: Rc::clone(&self.cache),
+            available: Rc::clone(&self.available),
+            pending: Rc::clone(&self.pending),
+            failed: Rc::clone(&self.failed),
+        })
+    }
+
+    /// The operations are written in one transaction of the database.
+    fn apply(&self, ops: Vec<StorageOp>) -> Result<(), StorageError> {
+        self.write(ops)
+    }
+}
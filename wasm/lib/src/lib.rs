@@ -1,5 +1,6 @@
 #[macro_use]
 pub mod logs;
+pub mod indexed_db;
 pub mod logger;
 pub mod storage_logs;
 pub mod web_rtc_connection;
@@ -3,7 +3,7 @@ use std::sync::Mutex;use std::sync::mpsc;
 use std::sync::mpsc::Receiver;
 use std::sync::mpsc::SyncSender;
 
-use common::node::ext_interface::Logger;
+use common::node::{ext_interface::Logger, logging::Record};
 
 use crate::logs::wait_ms;
 
@@ -50,16 +50,8 @@ impl NodeLogger {
 }
 
 impl Logger for NodeLogger {
-    fn info(&self, s: &str) {
-        self.print(format!("info: {:?}", s));
-    }
-
-    fn warn(&self, s: &str) {
-        self.print(format!("warn: {:?}", s));
-    }
-
-    fn error(&self, s: &str) {
-        self.print(format!("err : {:?}", s));
+    fn write(&self, record: &Record) {
+        self.print(format!("{:5}: {}", record.level, record));
     }
 
     fn clone(&self) -> Box<dyn Logger>{
@@ -1,45 +1,67 @@
-use common::node::ext_interface::{DataStorage,Logger};
+use common::node::{
+    ext_interface::{DataStorage, Logger, StorageError},
+    logging::{Level, Record},
+};
 
-use web_sys::window;
+use web_sys::{window, Storage};
 
 pub struct LocalStorage {}
 
-impl DataStorage for LocalStorage {
-    fn load(&self, key: &str) -> Result<String, String> {
+impl LocalStorage {
+    fn storage() -> Result<Storage, StorageError> {
         window()
-            .unwrap()
+            .ok_or_else(|| StorageError::Unavailable("no window".to_string()))?
             .local_storage()
-            .map_err(|e| e.as_string().unwrap())?
-            .unwrap()
+            .map_err(|e| StorageError::Unavailable(format!("{:?}", e)))?
+            .ok_or_else(|| StorageError::Unavailable("no localStorage".to_string()))
+    }
+}
+
+impl DataStorage for LocalStorage {
+    fn get(&self, key: &str) -> Result<Option<String>, StorageError> {
+        LocalStorage::storage()?
             .get(key)
-            .map(|s| s.unwrap_or("".to_string()))
-            .map_err(|e| e.as_string().unwrap())
+            .map_err(|e| StorageError::Load(format!("{:?}", e)))
     }
 
-    fn save(&self, key: &str, value: &str) -> Result<(), String> {
-        window()
-            .unwrap()
-            .local_storage()
-            .map_err(|e| e.as_string().unwrap())?
-            .unwrap()
+    fn save(&self, key: &str, value: &str) -> Result<(), StorageError> {
+        LocalStorage::storage()?
             .set(key, value)
-            .map_err(|e| e.as_string().unwrap())
+            .map_err(|e| StorageError::Save(format!("{:?}", e)))
     }
-}
 
-pub struct ConsoleLogger {}
+    fn remove(&self, key: &str) -> Result<(), StorageError> {
+        LocalStorage::storage()?
+            .remove_item(key)
+            .map_err(|e| StorageError::Remove(format!("{:?}", e)))
+    }
 
-impl Logger for ConsoleLogger {
-    fn info(&self, s: &str) {
-        console_log!("info: {}", s);
+    fn keys(&self, prefix: &str) -> Result<Vec<String>, StorageError> {
+        let storage = LocalStorage::storage()?;
+        let len = storage
+            .length()
+            .map_err(|e| StorageError::List(format!("{:?}", e)))?;
+        let mut keys: Vec<String> = (0..len)
+            .filter_map(|i| storage.key(i).ok().flatten())
+            .filter(|key| key.starts_with(prefix))
+            .collect();
+        keys.sort();
+        Ok(keys)
     }
 
-    fn warn(&self, s: &str) {
-        console_warn!("warn: {}", s);
+    fn box_clone(&self) -> Box<dyn DataStorage> {
+        Box::new(LocalStorage {})
     }
+}
 
-    fn error(&self, s: &str) {
-        console_warn!(" err: {}", s);
+pub struct ConsoleLogger {}
+
+impl Logger for ConsoleLogger {
+    fn write(&self, record: &Record) {
+        match record.level {
+            Level::Error | Level::Warn => console_warn!("{:5}: {}", record.level, record),
+            _ => console_log!("{:5}: {}", record.level, record),
+        }
     }
 
     fn clone(&self) -> Box<dyn Logger> {
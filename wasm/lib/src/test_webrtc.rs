@@ -1,10 +1,11 @@
+use std::cell::RefCell;
+use std::error::Error;
 use std::rc::Rc;
 use std::sync::{mpsc::channel, Arc, Mutex};
-use std::cell::RefCell;
 
 use common::{
     node::{
-        ext_interface::{DataStorage, Logger},
+        ext_interface::{DataStorage, Logger, StorageError},
         types::U256,
         Node,
     },
@@ -13,7 +14,7 @@ use common::{
             WSSignalMessage, WebRTCConnection, WebRTCConnectionSetup, WebRTCConnectionState,
             WebRTCSetupCBMessage, WebSocketMessage,
         },
-        websocket::{MessageCallback, WSMessage, WebSocketConnection},
+        websocket::{MessageCallback, WSError, WSMessage, WebSocketConnection},
     },
 };
 
@@ -66,13 +67,15 @@ impl WebSocketDummy {
     fn run_queue(&mut self) -> Result<usize, String> {
         let msgs: Vec<Message> = self.msg_queue.borrow_mut().drain(..).collect();
         let msgs_count = msgs.len();
-        // self.logger
-        //     .info(&format!("dbg: WebSocketDummy has {} messages", msgs_count));
-        msgs.iter().for_each(|msg| {
-            match WebSocketMessage::from_str(&msg.str) {
+        self.logger
+            .debug(&format!("WebSocketDummy has {} messages", msgs_count));
+        msgs.iter()
+            .for_each(|msg| match WebSocketMessage::from_str(&msg.str) {
                 Ok(wsm) => {
-                    // self.logger
-                    // .info(&format!("dbg: WebSocketDummy got msg {:?} from {}", wsm.msg, msg.id));
+                    self.logger.trace(&format!(
+                        "WebSocketDummy got msg {:?} from {}",
+                        wsm.msg, msg.id
+                    ));
                     match wsm.msg {
                         WSSignalMessage::PeerSetup(_) => {
                             if self.callbacks.len() == 2 {
@@ -89,8 +92,7 @@ impl WebSocketDummy {
                 Err(e) => self
                     .logger
                     .info(&format!("Error while getting message: {}", e)),
-            }
-        });
+            });
         Ok(msgs_count)
     }
 
@@ -129,8 +131,8 @@ impl WebSocketConnection for WebSocketConnectionDummy {
         self.cb.borrow_mut().replace(cb);
     }
 
-    fn send(&mut self, msg: String) -> Result<(), String> {
-        // ConsoleLogger{}.info(&format!("dbg: WebSocketConnection::send({})", msg));
+    fn send(&mut self, msg: String) -> Result<(), WSError> {
+        ConsoleLogger {}.trace(&format!("WebSocketConnection::send({})", msg));
         let queue = Rc::clone(&self.msg_queue);
         queue.borrow_mut().push(Message {
             id: self.id,
@@ -139,7 +141,7 @@ impl WebSocketConnection for WebSocketConnectionDummy {
         Ok(())
     }
 
-    fn reconnect(&mut self) -> Result<(), String> {
+    fn reconnect(&mut self) -> Result<(), WSError> {
         todo!()
     }
 }
@@ -147,13 +149,25 @@ impl WebSocketConnection for WebSocketConnectionDummy {
 pub struct DataStorageDummy {}
 
 impl DataStorage for DataStorageDummy {
-    fn load(&self, _key: &str) -> Result<String, String> {
-        Ok("".to_string())
+    fn get(&self, _key: &str) -> Result<Option<String>, StorageError> {
+        Ok(None)
+    }
+
+    fn save(&self, _key: &str, _value: &str) -> Result<(), StorageError> {
+        Ok(())
     }
 
-    fn save(&self, _key: &str, _value: &str) -> Result<(), String> {
+    fn remove(&self, _key: &str) -> Result<(), StorageError> {
         Ok(())
     }
+
+    fn keys(&self, _prefix: &str) -> Result<Vec<String>, StorageError> {
+        Ok(vec![])
+    }
+
+    fn box_clone(&self) -> Box<dyn DataStorage> {
+        Box::new(DataStorageDummy {})
+    }
 }
 
 fn put_msg(
@@ -166,6 +180,7 @@ fn put_msg(
         WebRTCSetupCBMessage::Connection(c) => {
             conn.lock().unwrap().replace(c);
         }
+        WebRTCSetupCBMessage::Failed(e) => panic!("setup failed: {}", e),
     }
 }
 
@@ -180,26 +195,26 @@ async fn set_callback(
     let connc = Arc::clone(&conn);
     webrtc
         .set_callback(Box::new(move |msg| {
-            logc.info(&format!("dbg: Got message: {:?}", &msg));
+            logc.debug(&format!("Got message: {:?}", &msg));
             put_msg(&icec, &connc, msg);
         }))
         .await;
 }
 
-async fn connect_test_base() -> Result<(), String> {
+async fn connect_test_base() -> Result<(), Box<dyn Error>> {
     let log = Box::new(ConsoleLogger {});
 
     log.info("Setting up nodes");
     // First node
     let ice1 = Arc::new(Mutex::new(vec![]));
     let conn1 = Arc::new(Mutex::new(None));
-    let mut webrtc1 = WebRTCConnectionSetupWasm::new(WebRTCConnectionState::Initializer)?;
+    let mut webrtc1 = WebRTCConnectionSetupWasm::new(WebRTCConnectionState::Initializer, &[])?;
     set_callback(&log, &mut webrtc1, &ice1, &conn1).await;
 
     // Second node
     let ice2 = Arc::new(Mutex::new(vec![]));
     let conn2 = Arc::new(Mutex::new(None));
-    let mut webrtc2 = WebRTCConnectionSetupWasm::new(WebRTCConnectionState::Follower)?;
+    let mut webrtc2 = WebRTCConnectionSetupWasm::new(WebRTCConnectionState::Follower, &[])?;
     set_callback(&log, &mut webrtc2, &ice2, &conn2).await;
 
     // Exchange messages
@@ -260,21 +275,21 @@ async fn connect_test_base() -> Result<(), String> {
     Ok(())
 }
 
-async fn connect_test_simple() -> Result<(), String> {
+async fn connect_test_simple() -> Result<(), Box<dyn Error>> {
     let log = Box::new(ConsoleLogger {});
     let mut ws_conn = WebSocketDummy::new(log.clone());
 
     // First node
-    let rtc_spawner = Box::new(|cs| WebRTCConnectionSetupWasm::new(cs));
+    let rtc_spawner = Box::new(WebRTCConnectionSetupWasm::new);
     let my_storage = Box::new(DataStorageDummy {});
     let ws = Box::new(ws_conn.get_connection()?);
-    let mut node1 = Node::new(my_storage, log.clone(), ws, rtc_spawner)?;
+    let mut node1 = Node::new(my_storage, None, log.clone(), ws, rtc_spawner)?;
 
     // Second node
-    let rtc_spawner = Box::new(|cs| WebRTCConnectionSetupWasm::new(cs));
+    let rtc_spawner = Box::new(WebRTCConnectionSetupWasm::new);
     let my_storage = Box::new(DataStorageDummy {});
     let ws = Box::new(ws_conn.get_connection()?);
-    let mut node2 = Node::new(my_storage, log.clone(), ws, rtc_spawner)?;
+    let mut node2 = Node::new(my_storage, None, log.clone(), ws, rtc_spawner)?;
 
     // Pass messages
     ws_conn.run_queue()?;
@@ -308,16 +323,16 @@ async fn connect_test_simple() -> Result<(), String> {
     Ok(())
 }
 
-async fn test_channel() -> Result<(), String> {
+async fn test_channel() -> Result<(), Box<dyn Error>> {
     let log = ConsoleLogger {};
     let (tx, rx) = channel::<&str>();
-    tx.send("one").map_err(|e| e.to_string())?;
-    tx.send("two").map_err(|e| e.to_string())?;
+    tx.send("one")?;
+    tx.send("two")?;
     log.info(&format!(
         "rx is: {:?}",
         rx.try_iter().collect::<Vec<&str>>()
     ));
-    tx.send("three").map_err(|e| e.to_string())?;
+    tx.send("three")?;
     log.info(&format!(
         "rx is: {:?}",
         rx.try_iter().collect::<Vec<&str>>()
@@ -334,7 +349,9 @@ async fn connect_test() {
         connect_test_base().await?;
         test_channel().await?;
         connect_test_simple().await
-    }.await {
+    }
+    .await
+    {
         Ok(_) => log.info("All OK"),
         Err(e) => log.info(&format!("Something went wrong: {}", e)),
     };
@@ -1,19 +1,37 @@
 use async_trait::async_trait;
+use js_sys::Reflect;
+use std::{any::Any, cell::RefCell, collections::HashMap, rc::Rc};
 
-use wasm_bindgen::{prelude::Closure, JsCast};
-use web_sys::{MessageEvent, RtcDataChannel, RtcPeerConnection};
-// use web_sys::console::log_1;
+use wasm_bindgen::{prelude::Closure, JsCast, JsValue};
+use web_sys::{
+    Event, MessageEvent, RtcDataChannel, RtcIceConnectionState, RtcPeerConnection,
+};
 
-use common::signal::web_rtc::{ConnectionStateMap, WebRTCConnection, WebRTCMessageCB, ConnType};
+use common::signal::web_rtc::{
+    ConnType, ConnectionError, ConnectionStateMap, WebRTCConnection, WebRTCConnectionEvent, WebRTCMessageCB,
+    WebRTCStateCB,
+};
 
 pub struct WebRTCConnectionWasm {
     dc: RtcDataChannel,
     conn: RtcPeerConnection,
+    // The callbacks are kept here, so they are freed with the connection.
+    callbacks: RefCell<Vec<Box<dyn Any>>>,
 }
 
 impl WebRTCConnectionWasm {
     pub fn new(dc: RtcDataChannel, conn: RtcPeerConnection) -> Box<dyn WebRTCConnection> {
-        Box::new(WebRTCConnectionWasm { dc, conn })
+        Box::new(WebRTCConnectionWasm {
+            dc,
+            conn,
+            callbacks: RefCell::new(vec![]),
+        })
+    }
+}
+
+impl Drop for WebRTCConnectionWasm {
+    fn drop(&mut self) {
+        self.close();
     }
 }
 
@@ -21,8 +39,10 @@ impl WebRTCConnectionWasm {
 impl WebRTCConnection for WebRTCConnectionWasm {
     /// Send a message to the other node. This call blocks until the message
     /// is queued.
-    fn send(&self, s: String) -> Result<(), String> {
-        self.dc.send_with_str(&s).map_err(|e| format!("{:?}", e))
+    fn send(&self, s: String) -> Result<(), ConnectionError> {
+        self.dc
+            .send_with_str(&s)
+            .map_err(|e| ConnectionError::Send(format!("{:?}", e)))
     }
 
     /// Sets the callback for incoming messages.
@@ -38,35 +58,136 @@ impl WebRTCConnection for WebRTCConnectionWasm {
             );
         self.dc
             .set_onmessage(Some(onmessage_callback.as_ref().unchecked_ref()));
-        onmessage_callback.forget();
+        self.callbacks.borrow_mut().push(Box::new(onmessage_callback));
     }
 
-    async fn get_state(&self) -> Result<ConnectionStateMap, String> {
+    /// Sets the callback for closed or failed connections.
+    fn set_cb_state(&self, cb: WebRTCStateCB) {
+        let cb = Rc::new(RefCell::new(cb));
+
+        let cb_clone = Rc::clone(&cb);
+        let onclose_callback = Closure::wrap(Box::new(move |_ev: Event| {
+            (cb_clone.borrow_mut())(WebRTCConnectionEvent::Closed);
+        }) as Box<dyn FnMut(Event)>);
+        self.dc
+            .set_onclose(Some(onclose_callback.as_ref().unchecked_ref()));
+
+        let cb_clone = Rc::clone(&cb);
+        let onerror_callback = Closure::wrap(Box::new(move |ev: Event| {
+            (cb_clone.borrow_mut())(WebRTCConnectionEvent::Error(format!("{:?}", ev)));
+        }) as Box<dyn FnMut(Event)>);
+        self.dc
+            .set_onerror(Some(onerror_callback.as_ref().unchecked_ref()));
+
+        let conn = self.conn.clone();
+        let onicestate_callback = Closure::wrap(Box::new(move |_ev: Event| {
+            let ev = match conn.ice_connection_state() {
+                RtcIceConnectionState::Disconnected => WebRTCConnectionEvent::Disconnected,
+                RtcIceConnectionState::Failed => WebRTCConnectionEvent::Failed,
+                RtcIceConnectionState::Closed => WebRTCConnectionEvent::Closed,
+                _ => return,
+            };
+            (cb.borrow_mut())(ev);
+        }) as Box<dyn FnMut(Event)>);
+        self.conn
+            .set_oniceconnectionstatechange(Some(onicestate_callback.as_ref().unchecked_ref()));
+        let mut callbacks = self.callbacks.borrow_mut();
+        callbacks.push(Box::new(onclose_callback));
+        callbacks.push(Box::new(onerror_callback));
+        callbacks.push(Box::new(onicestate_callback));
+    }
+
+    /// Returns the statistics of the candidate pair currently used by the
+    /// connection.
+    async fn get_state(&self) -> Result<ConnectionStateMap, ConnectionError> {
         let conn_stats: js_sys::Map = wasm_bindgen_futures::JsFuture::from(self.conn.get_stats())
             .await
-            .unwrap()
+            .map_err(|e| ConnectionError::State(format!("{:?}", e)))?
             .into();
-        // conn_stats.for_each(&mut |v, k| log_1(&format!("- {:?}: {:?}", k, v).into()));
-        let mut type_remote = ConnType::Unknown;
-        conn_stats.for_each(&mut |k, _v| {
-            let s = format!("{:?}", k);
-            // log_1(&s.clone().into());
-            if s.contains("srflx"){
-                type_remote = ConnType::STUNServer;
-            } else if s.contains("prflx"){
-                type_remote = ConnType::STUNPeer;
-            } else if s.contains("relay"){
-                type_remote = ConnType::TURN;
-            // } else if s.contains("host") {
-            //     type_remote = ConnType::Host;
-            }
-        });
-        Ok(ConnectionStateMap {
-            delay_ms: 0,
-            tx_bytes: 0,
-            rx_bytes: 0,
-            type_remote,
-            type_local: type_remote,
+        Ok(parse_stats(&conn_stats))
+    }
+
+    /// Removes the callbacks before closing, so the closing itself is not
+    /// reported, and the callbacks can be freed.
+    fn close(&self) {
+        self.dc.set_onmessage(None);
+        self.dc.set_onclose(None);
+        self.dc.set_onerror(None);
+        self.conn.set_oniceconnectionstatechange(None);
+        self.dc.close();
+        self.conn.close();
+        self.callbacks.borrow_mut().clear();
+    }
+}
+
+/// Parses an RTCStatsReport. The candidate pair in use is found either through
+/// the `selectedCandidatePairId` of the transport (Chrome, Safari), or by its
+/// `selected` flag (Firefox). If neither is available, the nominated and
+/// succeeded pair is used.
+fn parse_stats(report: &js_sys::Map) -> ConnectionStateMap {
+    let mut entries: HashMap<String, JsValue> = HashMap::new();
+    report.for_each(&mut |v, k| {
+        if let Some(id) = k.as_string() {
+            entries.insert(id, v);
+        }
+    });
+    let of_type = |typ: &'static str| {
+        entries
+            .values()
+            .filter(move |v| get_str(v, "type").as_deref() == Some(typ))
+    };
+
+    let pair = of_type("transport")
+        .filter_map(|t| get_str(t, "selectedCandidatePairId"))
+        .find_map(|id| entries.get(&id))
+        .or_else(|| {
+            of_type("candidate-pair").find(|p| get_bool(p, "selected") == Some(true))
         })
+        .or_else(|| {
+            of_type("candidate-pair").find(|p| {
+                get_bool(p, "nominated") == Some(true)
+                    && get_str(p, "state").as_deref() == Some("succeeded")
+            })
+        });
+
+    let mut state = ConnectionStateMap {
+        type_local: ConnType::Unknown,
+        type_remote: ConnType::Unknown,
+        rx_bytes: 0,
+        tx_bytes: 0,
+        delay_ms: 0,
+    };
+    if let Some(pair) = pair {
+        let candidate_type = |key: &str| {
+            get_str(pair, key)
+                .and_then(|id| entries.get(&id))
+                .and_then(|c| get_str(c, "candidateType"))
+                .map(|t| ConnType::from_candidate_type(&t))
+                .unwrap_or(ConnType::Unknown)
+        };
+        state.type_local = candidate_type("localCandidateId");
+        state.type_remote = candidate_type("remoteCandidateId");
+        state.rx_bytes = get_f64(pair, "bytesReceived").unwrap_or_default() as u64;
+        state.tx_bytes = get_f64(pair, "bytesSent").unwrap_or_default() as u64;
+        // The round trip time is given in seconds.
+        state.delay_ms = (get_f64(pair, "currentRoundTripTime").unwrap_or_default() * 1000.)
+            .round() as u32;
     }
+    state
+}
+
+fn get_field(obj: &JsValue, key: &str) -> Option<JsValue> {
+    Reflect::get(obj, &JsValue::from_str(key)).ok()
+}
+
+fn get_str(obj: &JsValue, key: &str) -> Option<String> {
+    get_field(obj, key)?.as_string()
+}
+
+fn get_f64(obj: &JsValue, key: &str) -> Option<f64> {
+    get_field(obj, key)?.as_f64()
+}
+
+fn get_bool(obj: &JsValue, key: &str) -> Option<bool> {
+    get_field(obj, key)?.as_bool()
 }
@@ -1,6 +1,11 @@
 use async_trait::async_trait;
 
-use std::sync::{Arc, Mutex};
+use std::{
+    any::Any,
+    cell::RefCell,
+    rc::{Rc, Weak},
+    sync::{Arc, Mutex},
+};
 
 use js_sys::Reflect;
 use wasm_bindgen::prelude::*;
@@ -8,13 +13,14 @@ use wasm_bindgen::JsCast;
 use wasm_bindgen_futures::JsFuture;
 
 use common::signal::web_rtc::{
-    WebRTCConnectionSetup, WebRTCConnectionState, WebRTCSetupCB, WebRTCSetupCBMessage,
+    IceServer, SetupError, WebRTCConnectionSetup, WebRTCConnectionState, WebRTCSetupCB,
+    WebRTCSetupCBMessage,
 };
 
 use web_sys::{
-    console::log_1, Event, RtcDataChannel, RtcDataChannelEvent, RtcIceCandidate,
-    RtcIceCandidateInit, RtcIceGatheringState, RtcPeerConnection, RtcPeerConnectionIceEvent,
-    RtcSdpType, RtcSessionDescriptionInit,
+    console::log_1, Event, RtcConfiguration, RtcDataChannel, RtcDataChannelEvent, RtcIceCandidate,
+    RtcIceCandidateInit, RtcIceConnectionState, RtcIceGatheringState, RtcPeerConnection,
+    RtcPeerConnectionIceEvent, RtcSdpType, RtcSessionDescriptionInit,
 };
 
 use crate::{logs::wait_ms, web_rtc_connection::WebRTCConnectionWasm};
@@ -23,11 +29,16 @@ fn log(s: &str) {
     log_1(&JsValue::from_str(s));
 }
 
+/// The JS callbacks of a connection. They are kept until the connection is
+/// closed, so they can be freed.
+type Callbacks = Rc<RefCell<Vec<Box<dyn Any>>>>;
+
 /// Structure for easy WebRTC handling without all the hassle of JS-internals.
 pub struct WebRTCConnectionSetupWasm {
     nt: WebRTCConnectionState,
     rp_conn: RtcPeerConnection,
     callback: Arc<Mutex<Option<WebRTCSetupCB>>>,
+    callbacks: Callbacks,
 }
 
 impl WebRTCConnectionSetupWasm {
@@ -38,56 +49,71 @@ impl WebRTCConnectionSetupWasm {
     /// # Arguments
     ///
     /// * `init` - Initializer or Follower
+    /// * `ice_servers` - STUN and TURN servers to find a path to the other node
     ///
     /// # Actions
     ///
     /// Once two nodes are set up, they need to exchang the offer and the answer string.
     /// Followed by that they need to exchange the ice strings, in either order.
     /// Only after exchanging this information can the msg_send and msg_receive methods be used.
-    pub fn new(nt: WebRTCConnectionState) -> Result<Box<dyn WebRTCConnectionSetup>, String> {
-        let rp_conn =
-            RtcPeerConnection::new().map_err(|e| format!("PeerConnection error: {:?}", e))?;
+    pub fn new(
+        nt: WebRTCConnectionState,
+        ice_servers: &[IceServer],
+    ) -> Result<Box<dyn WebRTCConnectionSetup>, SetupError> {
+        let rp_conn = RtcPeerConnection::new_with_configuration(&rtc_config(ice_servers)?)
+            .map_err(|e| SetupError::Spawn(format!("PeerConnection error: {:?}", e)))?;
         let rn = WebRTCConnectionSetupWasm {
             nt,
             rp_conn: rp_conn.clone(),
             callback: Arc::new(Mutex::new(None)),
+            callbacks: Rc::new(RefCell::new(vec![])),
         };
-        ice_start(&rp_conn, Arc::clone(&rn.callback));
+        ice_start(&rp_conn, Arc::clone(&rn.callback), &rn.callbacks);
         let cb_clone = Arc::clone(&rn.callback);
         match nt {
-            WebRTCConnectionState::Initializer => dc_create_init(rp_conn.clone(), cb_clone),
-            WebRTCConnectionState::Follower => dc_create_follow(rp_conn.clone(), cb_clone),
+            WebRTCConnectionState::Initializer => {
+                dc_create_init(rp_conn.clone(), cb_clone, &rn.callbacks)
+            }
+            WebRTCConnectionState::Follower => {
+                dc_create_follow(rp_conn.clone(), cb_clone, &rn.callbacks)
+            }
         };
         Ok(Box::new(rn))
     }
 
     // Making sure the struct is in correct state
 
-    fn is_initializer(&self) -> Result<(), String> {
+    fn is_initializer(&self) -> Result<(), SetupError> {
         if self.nt != WebRTCConnectionState::Initializer {
-            return Err("This method is only available to the Initializer".to_string());
+            return Err(SetupError::WrongState(self.nt));
         }
         Ok(())
     }
 
-    fn is_follower(&self) -> Result<(), String> {
+    fn is_follower(&self) -> Result<(), SetupError> {
         if self.nt != WebRTCConnectionState::Follower {
-            return Err("This method is only available to the Follower".to_string());
+            return Err(SetupError::WrongState(self.nt));
         }
         Ok(())
     }
 }
 
+impl Drop for WebRTCConnectionSetupWasm {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
 #[async_trait(?Send)]
 impl WebRTCConnectionSetup for WebRTCConnectionSetupWasm {
     // Returns the offer string that needs to be sent to the `Follower` node.
-    async fn make_offer(&mut self) -> Result<String, String> {
+    async fn make_offer(&mut self) -> Result<String, SetupError> {
         self.is_initializer()?;
         let offer = JsFuture::from(self.rp_conn.create_offer())
             .await
-            .map_err(|e| e.as_string().unwrap())?;
+            .map_err(|e| SetupError::Offer(format!("{:?}", e)))?;
         let offer_sdp = Reflect::get(&offer, &JsValue::from_str("sdp"))
-            .map_err(|e| e.as_string().unwrap())?
+            .map_err(|e| SetupError::Offer(format!("{:?}", e)))?
             .as_string()
             .unwrap();
 
@@ -96,29 +122,29 @@ impl WebRTCConnectionSetup for WebRTCConnectionSetupWasm {
         let sld_promise = self.rp_conn.set_local_description(&offer_obj);
         JsFuture::from(sld_promise)
             .await
-            .map_err(|e| e.as_string().unwrap())?;
+            .map_err(|e| SetupError::Offer(format!("{:?}", e)))?;
         Ok(offer_sdp)
     }
 
     // Takes the offer string
-    async fn make_answer(&mut self, offer: String) -> Result<String, String> {
+    async fn make_answer(&mut self, offer: String) -> Result<String, SetupError> {
         self.is_follower()?;
         let mut offer_obj = RtcSessionDescriptionInit::new(RtcSdpType::Offer);
         offer_obj.sdp(&offer);
         let srd_promise = self.rp_conn.set_remote_description(&offer_obj);
         JsFuture::from(srd_promise)
             .await
-            .map_err(|e| e.as_string().unwrap())?;
+            .map_err(|e| SetupError::Answer(format!("{:?}", e)))?;
 
         let answer = match JsFuture::from(self.rp_conn.create_answer()).await {
             Ok(f) => f,
             Err(e) => {
                 log(&format!("Error answer: {:?}", e));
-                return Err(e.as_string().unwrap());
+                return Err(SetupError::Answer(format!("{:?}", e)));
             }
         };
         let answer_sdp = Reflect::get(&answer, &JsValue::from_str("sdp"))
-            .map_err(|e| e.as_string().unwrap())?
+            .map_err(|e| SetupError::Answer(format!("{:?}", e)))?
             .as_string()
             .unwrap();
 
@@ -127,24 +153,24 @@ impl WebRTCConnectionSetup for WebRTCConnectionSetupWasm {
         let sld_promise = self.rp_conn.set_local_description(&answer_obj);
         JsFuture::from(sld_promise)
             .await
-            .map_err(|e| e.as_string().unwrap())?;
+            .map_err(|e| SetupError::Answer(format!("{:?}", e)))?;
         Ok(answer_sdp)
     }
 
     // Takes the answer string and finalizes the first part of the connection.
-    async fn use_answer(&mut self, answer: String) -> Result<(), String> {
+    async fn use_answer(&mut self, answer: String) -> Result<(), SetupError> {
         self.is_initializer()?;
         let mut answer_obj = RtcSessionDescriptionInit::new(RtcSdpType::Answer);
         answer_obj.sdp(&answer);
         let srd_promise = self.rp_conn.set_remote_description(&answer_obj);
         JsFuture::from(srd_promise)
             .await
-            .map_err(|e| e.as_string().unwrap())?;
+            .map_err(|e| SetupError::UseAnswer(format!("{:?}", e)))?;
         Ok(())
     }
 
     // Waits for the ICE to move on from the 'New' state
-    async fn wait_gathering(&mut self) -> Result<(), String> {
+    async fn wait_gathering(&mut self) -> Result<(), SetupError> {
         // self.is_not_setup()?;
         for _ in 0u8..10 {
             match self.rp_conn.ice_gathering_state() {
@@ -152,7 +178,7 @@ impl WebRTCConnectionSetup for WebRTCConnectionSetupWasm {
                 _ => return Ok(()),
             }
         }
-        Err("Didn't reach IceGatheringState".to_string())
+        Err(SetupError::Gathering)
     }
 
     // Waits for the ICE string to be avaialble.
@@ -161,12 +187,15 @@ impl WebRTCConnectionSetup for WebRTCConnectionSetupWasm {
     }
 
     // Sends the ICE string to the WebRTC.
-    async fn ice_put(&mut self, ice: String) -> Result<(), String> {
+    async fn ice_put(&mut self, ice: String) -> Result<(), SetupError> {
         // self.is_not_setup()?;
         let rp_clone = self.rp_conn.clone();
         let els: Vec<&str> = ice.split(":-:").collect();
         if els.len() != 3 {
-            return Err(format!("wrong ice candidate string: {}", ice));
+            return Err(SetupError::Ice(format!(
+                "wrong ice candidate string: {}",
+                ice
+            )));
         }
         let mut ric_init = RtcIceCandidateInit::new(els[0]);
         ric_init.sdp_mid(Some(els[1]));
@@ -176,9 +205,8 @@ impl WebRTCConnectionSetup for WebRTCConnectionSetupWasm {
                 let _ = rp_clone.add_ice_candidate_with_opt_rtc_ice_candidate(Some(&e));
                 Ok(())
             }
-            Err(e) => Err(format!("Couldn't consume ice: {:?}", e)),
+            Err(e) => Err(SetupError::Ice(format!("Couldn't consume ice: {:?}", e))),
         }
-        .map_err(|js| js.to_string())
     }
 
     async fn print_states(&mut self) {
@@ -190,49 +218,106 @@ impl WebRTCConnectionSetup for WebRTCConnectionSetupWasm {
             self.rp_conn.ice_connection_state()
         ));
     }
+
+    /// Removes the callbacks before closing, so they can be freed.
+    fn close(&mut self) {
+        self.rp_conn.set_onicecandidate(None);
+        self.rp_conn.set_oniceconnectionstatechange(None);
+        self.rp_conn.set_ondatachannel(None);
+        self.rp_conn.close();
+        self.callbacks.borrow_mut().clear();
+    }
 }
 
-fn ice_start(rp_conn: &RtcPeerConnection, callback: Arc<Mutex<Option<WebRTCSetupCB>>>) {
-    let onicecandidate_callback1 = Closure::wrap(Box::new(move |ev: RtcPeerConnectionIceEvent| {
-        match ev.candidate() {
-            Some(candidate) => {
-                let cand = format!(
-                    "{}:-:{}:-:{}",
-                    candidate.candidate(),
-                    candidate.sdp_mid().unwrap(),
-                    candidate.sdp_m_line_index().unwrap()
-                );
-                if let Some(cb) = callback.lock().unwrap().as_ref() {
-                    cb(WebRTCSetupCBMessage::Ice(cand.clone()));
+/// The fields of IceServer have the same names as the RTCIceServer dictionary,
+/// so the JSON representation can be used directly.
+fn rtc_config(ice_servers: &[IceServer]) -> Result<RtcConfiguration, SetupError> {
+    let servers = serde_json::to_string(ice_servers)
+        .map_err(|e| SetupError::Spawn(format!("ICE servers: {}", e)))?;
+    let config = RtcConfiguration::new();
+    js_sys::JSON::parse(&servers)
+        .and_then(|servers| Reflect::set(&config, &JsValue::from_str("iceServers"), &servers))
+        .map_err(|e| SetupError::Spawn(format!("ICE servers: {:?}", e)))?;
+    Ok(config)
+}
+
+fn ice_start(
+    rp_conn: &RtcPeerConnection,
+    callback: Arc<Mutex<Option<WebRTCSetupCB>>>,
+    callbacks: &Callbacks,
+) {
+    let callback_state = Arc::clone(&callback);
+    let onicecandidate_callback1 =
+        Closure::wrap(
+            Box::new(move |ev: RtcPeerConnectionIceEvent| match ev.candidate() {
+                Some(candidate) => {
+                    let cand = format!(
+                        "{}:-:{}:-:{}",
+                        candidate.candidate(),
+                        candidate.sdp_mid().unwrap(),
+                        candidate.sdp_m_line_index().unwrap()
+                    );
+                    if let Some(cb) = callback.lock().unwrap().as_ref() {
+                        cb(WebRTCSetupCBMessage::Ice(cand.clone()));
+                    }
                 }
+                None => {}
+            }) as Box<dyn FnMut(RtcPeerConnectionIceEvent)>,
+        );
+    rp_conn.set_onicecandidate(Some(onicecandidate_callback1.as_ref().unchecked_ref()));
+    callbacks
+        .borrow_mut()
+        .push(Box::new(onicecandidate_callback1));
+
+    // Once connected, WebRTCConnectionWasm replaces this callback.
+    let conn = rp_conn.clone();
+    let onicestate_callback = Closure::wrap(Box::new(move |_ev: Event| {
+        if conn.ice_connection_state() == RtcIceConnectionState::Failed {
+            if let Some(cb) = callback_state.lock().unwrap().as_ref() {
+                cb(WebRTCSetupCBMessage::Failed(
+                    "ICE connection failed".to_string(),
+                ));
             }
-            None => {}
         }
-    })
-        as Box<dyn FnMut(RtcPeerConnectionIceEvent)>);
-    rp_conn.set_onicecandidate(Some(onicecandidate_callback1.as_ref().unchecked_ref()));
-    onicecandidate_callback1.forget();
+    }) as Box<dyn FnMut(Event)>);
+    rp_conn.set_oniceconnectionstatechange(Some(onicestate_callback.as_ref().unchecked_ref()));
+    callbacks.borrow_mut().push(Box::new(onicestate_callback));
 }
 
-fn dc_create_init(rp_conn: RtcPeerConnection, cb: Arc<Mutex<Option<WebRTCSetupCB>>>) {
+fn dc_create_init(
+    rp_conn: RtcPeerConnection,
+    cb: Arc<Mutex<Option<WebRTCSetupCB>>>,
+    callbacks: &Callbacks,
+) {
     let dc = rp_conn.create_data_channel("data-channel");
-    dc_set_onopen(&mut Some(dc), &mut Some(rp_conn), cb);
+    dc_set_onopen(&mut Some(dc), &mut Some(rp_conn), cb, callbacks);
 }
 
-fn dc_create_follow(rp_conn: RtcPeerConnection, cb: Arc<Mutex<Option<WebRTCSetupCB>>>) {
+fn dc_create_follow(
+    rp_conn: RtcPeerConnection,
+    cb: Arc<Mutex<Option<WebRTCSetupCB>>>,
+    callbacks: &Callbacks,
+) {
     let mut rpc = Some(rp_conn.clone());
+    // A strong reference would keep the callbacks alive forever.
+    let callbacks_weak: Weak<RefCell<Vec<Box<dyn Any>>>> = Rc::downgrade(callbacks);
     let ondatachannel_callback = Closure::wrap(Box::new(move |ev: RtcDataChannelEvent| {
-        let dc = ev.channel();
-        dc_set_onopen(&mut Some(dc), &mut rpc, Arc::clone(&cb));
+        if let Some(callbacks) = callbacks_weak.upgrade() {
+            let dc = ev.channel();
+            dc_set_onopen(&mut Some(dc), &mut rpc, Arc::clone(&cb), &callbacks);
+        }
     }) as Box<dyn FnMut(RtcDataChannelEvent)>);
     rp_conn.set_ondatachannel(Some(ondatachannel_callback.as_ref().unchecked_ref()));
-    ondatachannel_callback.forget();
+    callbacks
+        .borrow_mut()
+        .push(Box::new(ondatachannel_callback));
 }
 
 fn dc_set_onopen(
     dc: &mut Option<RtcDataChannel>,
     rp_conn: &mut Option<RtcPeerConnection>,
     cb: Arc<Mutex<Option<WebRTCSetupCB>>>,
+    callbacks: &Callbacks,
 ) {
     let dcc = dc.take().unwrap();
     let mut dccc = Some(dcc.clone());
@@ -242,5 +327,5 @@ fn dc_set_onopen(
         cb.lock().unwrap().as_ref().unwrap()(WebRTCSetupCBMessage::Connection(conn));
     }) as Box<dyn FnMut(Event)>);
     dcc.set_onopen(Some(ondatachannel_open.as_ref().unchecked_ref()));
-    ondatachannel_open.forget();
+    callbacks.borrow_mut().push(Box::new(ondatachannel_open));
 }
@@ -9,25 +9,63 @@ use web_sys::ErrorEvent;
 use web_sys::MessageEvent;
 use web_sys::WebSocket;
 
-use common::signal::websocket::{MessageCallback, WSMessage, WebSocketConnection};
+use common::signal::websocket::{MessageCallback, WSError, WSMessage, WebSocketConnection};
 
 pub struct WebSocketWasm {
     cb: Rc<RefCell<Option<MessageCallback>>>,
     ws: WebSocket,
-    addr: String,
+    // the signal servers, starting with the one in use
+    urls: Vec<String>,
+    // the signal server in use accepted the connection
+    opened: Rc<RefCell<bool>>,
 }
 
 impl WebSocketWasm {
-    pub fn new(addr: &str) -> Result<WebSocketWasm, JsValue> {
-        console_log!("connecting to: {}", addr);
-        let ws = WebSocket::new(addr)?;
-        let mut wsw = WebSocketWasm {
-            cb: Rc::new(RefCell::new(None)),
-            ws: ws.clone(),
-            addr: addr.to_string(),
-        };
-        wsw.attach_callbacks();
-        Ok(wsw)
+    pub fn new(addr: &str) -> Result<WebSocketWasm, WSError> {
+        WebSocketWasm::with_urls(vec![addr.to_string()])
+    }
+
+    /// Connects to the first of the signal servers. If a server doesn't
+    /// accept the connection, the next one is tried when the node sends
+    /// its next message.
+    pub fn with_urls(urls: Vec<String>) -> Result<WebSocketWasm, WSError> {
+        let mut urls = urls;
+        for _ in 0..urls.len() {
+            console_log!("connecting to: {}", urls[0]);
+            match WebSocket::new(&urls[0]) {
+                Ok(ws) => {
+                    let mut wsw = WebSocketWasm {
+                        cb: Rc::new(RefCell::new(None)),
+                        ws,
+                        urls,
+                        opened: Rc::new(RefCell::new(false)),
+                    };
+                    wsw.attach_callbacks();
+                    return Ok(wsw);
+                }
+                Err(e) => {
+                    console_warn!("invalid signal server {}: {:?}", urls[0], e);
+                    urls.rotate_left(1);
+                }
+            }
+        }
+        Err(WSError::Unreachable("no valid signal server".to_string()))
+    }
+
+    /// Returns the comma separated URLs of `list`, or `default` if the
+    /// list is empty. This allows to override the signal servers of the
+    /// config from the environment or the URL of the page.
+    pub fn urls(list: &str, default: Vec<String>) -> Vec<String> {
+        let urls: Vec<String> = list
+            .split(',')
+            .map(|url| url.trim().to_string())
+            .filter(|url| !url.is_empty())
+            .collect();
+        if urls.is_empty() {
+            default
+        } else {
+            urls
+        }
     }
 
     fn attach_callbacks(&mut self){
@@ -65,8 +103,10 @@ impl WebSocketWasm {
 
         console_log!("creating onopen callback");
         let cb_clone = self.cb.clone();
+        let opened = self.opened.clone();
         let onopen_callback = Closure::wrap(Box::new(move |_| {
             console_log!("socket opened");
+            *opened.borrow_mut() = true;
             if let Some(cb) = cb_clone.borrow_mut().as_deref_mut() {
                 cb(WSMessage::Opened("".to_string()));
             }
@@ -80,12 +120,14 @@ impl WebSocketWasm {
 
 #[async_trait(?Send)]
 impl WebSocketConnection for WebSocketWasm {
-    fn send(&mut self, msg: String) -> Result<(), String> {
+    fn send(&mut self, msg: String) -> Result<(), WSError> {
         if self.ws.ready_state() != WebSocket::OPEN {
             console_log!("Websocket is not open - trying to reconnect");
             self.reconnect()?;
         }
-        self.ws.send_with_str(&msg).map_err(|e| format!("Error while sending: {:?}", e))?;
+        self.ws
+            .send_with_str(&msg)
+            .map_err(|e| WSError::Send(format!("{:?}", e)))?;
         Ok(())
     }
 
@@ -93,9 +135,16 @@ impl WebSocketConnection for WebSocketWasm {
         self.cb.borrow_mut().replace(cb);
     }
 
-    fn reconnect(&mut self) -> Result<(), String>{
-        self.ws = WebSocket::new(&self.addr).map_err(|e| e.as_string().unwrap())?;
+    /// Connects again to the signal server, or to the next one if the
+    /// current one never accepted the connection.
+    fn reconnect(&mut self) -> Result<(), WSError> {
+        if !self.opened.replace(false) && self.urls.len() > 1 {
+            self.urls.rotate_left(1);
+            console_warn!("signal server unreachable - trying {}", self.urls[0]);
+        }
+        self.ws = WebSocket::new(&self.urls[0])
+            .map_err(|e| WSError::Unreachable(format!("{:?}", e)))?;
         self.attach_callbacks();
-        Err("Not implemented".to_string())
+        Err(WSError::Unreachable("waiting for reconnection".to_string()))
     }
 }
@@ -1,9 +1,24 @@
 #![recursion_limit = "1024"]
 
-use common::node::{ext_interface::Logger, logic::Stat};
+use common::{
+    node::{
+        events::Wakeup,
+        ext_interface::{DataStorage, Logger},
+        logging::set_filter_str,
+        logic::Stat,
+        network::{timeline::ConnectionTimeline, NetworkError},
+        types::U256,
+        NodeError,
+    },
+    node::config::{secret::SecretError, ConfigError},
+    signal::websocket::WSError,
+};
 use std::sync::Arc;
 use std::sync::Mutex;
-use yew::services::IntervalService;
+use yew::services::{
+    reader::{File, FileData, ReaderTask},
+    IntervalService, ReaderService,
+};
 
 use std::time::Duration;
 
@@ -13,11 +28,13 @@ use yew::prelude::*;
 use js_sys::Date;
 use regex::Regex;
 use wasm_lib::{
+    logs::wait_ms,
+    indexed_db::IndexedDbStorage,
     storage_logs::{ConsoleLogger, LocalStorage},
     web_rtc_setup::WebRTCConnectionSetupWasm,
     web_socket::WebSocketWasm,
 };
-use web_sys::{console, window};
+use web_sys::{console, window, UrlSearchParams};
 
 use common::node::Node;
 
@@ -29,11 +46,7 @@ fn log_2(s: &str, t: String) {
     console::log_2(&JsValue::from(s), &JsValue::from(t));
 }
 
-#[cfg(not(feature = "local"))]
-const URL: &str = "wss://signal.fledg.re";
-
-#[cfg(feature = "local")]
-const URL: &str = "ws://localhost:8765";
+const LOG_FILTER_NAME: &str = "logFilter";
 
 struct Model {
     link: ComponentLink<Self>,
@@ -42,12 +55,26 @@ struct Model {
     counter: u32,
     show_reset: bool,
     no_contact_yet: bool,
+    // the peer whose connection timeline is shown
+    selected: Option<U256>,
+    show_words: bool,
+    // the encrypted config, ready to be downloaded
+    export: Option<String>,
+    reader: ReaderService,
+    reader_task: Option<ReaderTask>,
 }
 
 enum Msg {
     UpdateLog,
     Reset,
-    Node(Result<Node, JsValue>),
+    Node(Result<Node, NodeError>),
+    Select(U256),
+    ShowWords,
+    Export,
+    ImportFile(File),
+    FileRead(FileData),
+    ImportWords,
+    SetAlias,
 }
 
 async fn wrap<F: std::future::Future>(f: F, done_cb: yew::Callback<F::Output>) {
@@ -60,10 +87,10 @@ impl Component for Model {
 
     fn create(_: Self::Properties, link: ComponentLink<Self>) -> Self {
         console_error_panic_hook::set_once();
-        Model::set_localstorage();
+        Model::set_log_filter();
 
         let logger = ConsoleLogger {};
-        Model::node_start(logger.clone(), &link);
+        Model::node_start(logger.clone(), &link, None, Model::config_from_url());
         let _ = Box::leak(Box::new(IntervalService::spawn(
             Duration::from_secs(1),
             link.callback(|_| Msg::UpdateLog),
@@ -74,6 +101,11 @@ impl Component for Model {
             counter: 0,
             show_reset: false,
             no_contact_yet: true,
+            selected: None,
+            show_words: false,
+            export: None,
+            reader: ReaderService::new(),
+            reader_task: None,
             logger,
         }
     }
@@ -93,7 +125,6 @@ impl Component for Model {
                         }
                     }
                 }
-                self.process();
                 self.counter += 1;
                 if self.counter % 15 == 0 || self.counter < 3 {
                     self.node_list();
@@ -103,18 +134,104 @@ impl Component for Model {
             Msg::Node(res_node) => match res_node {
                 Ok(node) => {
                     self.logger.info("Got node");
-                    self.node = Some(Arc::new(Mutex::new(node)));
+                    let wakeup = node.wakeup();
+                    let n = Arc::new(Mutex::new(node));
+                    Model::node_run(Arc::clone(&n), wakeup, self.logger.clone());
+                    self.node = Some(n);
                 }
                 Err(e) => {
-                    self.logger
-                        .error(&format!("Couldn't create node: {}", e.as_string().unwrap()));
+                    match e {
+                        NodeError::Network(NetworkError::WebSocket(WSError::Unreachable(_))) => {
+                            self.logger
+                                .error("Signal server is unreachable - please try again later")
+                        }
+                        NodeError::Config(ConfigError::Locked)
+                        | NodeError::Config(ConfigError::Secret(SecretError::Decrypt)) => {
+                            let msg = "The key of this node is protected - please enter the passphrase";
+                            if let Ok(Some(passphrase)) = window().unwrap().prompt_with_message(msg) {
+                                Model::node_start(self.logger.clone(), &self.link, Some(passphrase), None);
+                                return true;
+                            }
+                            self.logger.error("Node is locked");
+                        }
+                        e => self.logger.error(&format!("Couldn't create node: {}", e)),
+                    }
                     self.show_reset = true;
                 }
             },
+            Msg::Select(id) => {
+                self.selected = match self.selected.as_ref() {
+                    Some(sel) if sel == &id => None,
+                    _ => Some(id),
+                };
+            }
+            Msg::ShowWords => self.show_words = !self.show_words,
+            Msg::Export => {
+                self.export = None;
+                let msg = "Passphrase to protect the exported key";
+                if let Ok(Some(passphrase)) = window().unwrap().prompt_with_message(msg) {
+                    if let Some(n) = self.node_copy() {
+                        if let Ok(node) = n.try_lock() {
+                            match node.export(&passphrase) {
+                                Ok(config) => self.export = Some(config),
+                                Err(e) => self.logger.error(&format!("Couldn't export: {}", e)),
+                            }
+                        }
+                    }
+                }
+            }
+            Msg::SetAlias => {
+                let msg = "Alias shown next to the name of the node, empty to remove it";
+                if let Ok(Some(alias)) = window().unwrap().prompt_with_message(msg) {
+                    if let Some(n) = self.node_copy() {
+                        if let Ok(mut node) = n.try_lock() {
+                            let alias = Some(alias.trim()).filter(|a| !a.is_empty());
+                            if let Err(e) = node.set_alias(alias) {
+                                self.logger.error(&format!("Couldn't set alias: {}", e));
+                            }
+                        }
+                    }
+                }
+            }
+            Msg::ImportFile(file) => {
+                let callback = self.link.callback(Msg::FileRead);
+                match self.reader.read_file(file, callback) {
+                    Ok(task) => self.reader_task = Some(task),
+                    Err(e) => self.logger.error(&format!("Couldn't read file: {}", e)),
+                }
+            }
+            Msg::FileRead(data) => {
+                self.reader_task = None;
+                if !Model::confirm_import() {
+                    return true;
+                }
+                let config = String::from_utf8_lossy(&data.content).to_string();
+                let msg = "Passphrase of the imported key";
+                if let Ok(Some(passphrase)) = window().unwrap().prompt_with_message(msg) {
+                    Model::import(self.logger.clone(), move |storage| {
+                        Node::import(storage, &config, &passphrase)
+                    });
+                }
+            }
+            Msg::ImportWords => {
+                if !Model::confirm_import() {
+                    return true;
+                }
+                let win = window().unwrap();
+                let words = win.prompt_with_message("Recovery words of the node");
+                if let Ok(Some(words)) = words {
+                    let msg = "Passphrase to protect the key, or empty to store it in plain text";
+                    if let Ok(Some(passphrase)) = win.prompt_with_message(msg) {
+                        Model::import(self.logger.clone(), move |storage| {
+                            let passphrase = Some(passphrase.as_str()).filter(|p| !p.is_empty());
+                            Node::import_mnemonic(storage, &words, passphrase)
+                        });
+                    }
+                }
+            }
             Msg::Reset => {
-                Model::set_config("");
                 self.show_reset = false;
-                Model::node_start(self.logger.clone(), &self.link);
+                Model::node_start(self.logger.clone(), &self.link, None, Some("".into()));
             }
         }
         true
@@ -155,56 +272,146 @@ impl Component for Model {
                         <li>{"Our node: "}{self.describe()}</li>
                         <li>{"Known Nodes:"}{self.nodes_reachable()}</li>
                     </ul>
+                    {self.peer_details()}
                     <button style={reset_style} onclick=self.link.callback(|_| Msg::Reset)>{ "Reset Config" }</button>
                 </div>
+                {self.identity()}
             </div>
         }
     }
 }
 
 impl Model {
-    fn process(&self) {
-        if let Some(n) = self.node_copy() {
-            let log = self.logger.clone();
-            wasm_bindgen_futures::spawn_local(async move {
-                if let Ok(mut node) = n.try_lock(){
-                    if let Err(e) = node.process().await {
-                        log.info(&format!("Error: {}", e));
+    /// Processes the messages of the node as soon as they arrive.
+    fn node_run(n: Arc<Mutex<Node>>, wakeup: Wakeup, log: Box<dyn Logger>) {
+        wasm_bindgen_futures::spawn_local(async move {
+            loop {
+                wakeup.wait().await;
+                match n.try_lock() {
+                    Ok(mut node) => {
+                        if let Err(e) = node.process().await {
+                            log.info(&format!("Error: {}", e));
+                        }
+                    }
+                    Err(_) => {
+                        // Somebody else uses the node - try again later.
+                        wakeup.notify();
+                        wait_ms(10).await;
                     }
                 }
-            });
-        }
+            }
+        });
     }
-    fn node_start(logger: Box<dyn Logger>, link: &ComponentLink<Model>) {
+
+    /// Starts the node with the data from IndexedDB. If `config` is given,
+    /// it replaces the stored config, which is kept as a backup in case the
+    /// identity is still needed.
+    fn node_start(
+        logger: Box<dyn Logger>,
+        link: &ComponentLink<Model>,
+        passphrase: Option<String>,
+        config: Option<String>,
+    ) {
         wasm_bindgen_futures::spawn_local(wrap(
-            async {
-                let rtc_spawner = Box::new(|cs| WebRTCConnectionSetupWasm::new(cs));
-                let my_storage = Box::new(LocalStorage {});
-                let ws = WebSocketWasm::new(URL)?;
-                let node = Node::new(my_storage, logger, Box::new(ws), rtc_spawner)?;
+            async move {
+                let rtc_spawner = Box::new(WebRTCConnectionSetupWasm::new);
+                let my_storage = Box::new(IndexedDbStorage::open().await?);
+                if let Some(config) = config {
+                    Node::set_config(my_storage.box_clone(), &config)?;
+                }
+                let ledger = Node::ledger(my_storage.box_clone())?;
+                let urls = WebSocketWasm::urls(&Model::signal_from_url(), ledger.signal_urls);
+                log_2("Joining network through", format!("{} {:?}", ledger.name, urls));
+                let ws = WebSocketWasm::with_urls(urls).map_err(NetworkError::from)?;
+                let node = Node::new(
+                    my_storage,
+                    passphrase.as_deref(),
+                    logger,
+                    Box::new(ws),
+                    rtc_spawner,
+                )?;
 
                 Ok(node)
             },
-            link.callback(|n: Result<Node, JsValue>| Msg::Node(n)),
+            link.callback(|n: Result<Node, NodeError>| Msg::Node(n)),
         ));
     }
 
     fn describe(&self) -> String {
         if let Some(n) = self.node_copy() {
             if let Ok(node) = n.try_lock(){
-                return format!("{} => {}", node.info.info, node.info.public);
+                return format!("{} => {}", node.info.label(), node.info.public);
             }
         }
         return "Unknown".into();
     }
 
-    fn set_config(data: &str) {
-        if let Err(err) = Node::set_config(Box::new(LocalStorage {}), &data) {
-            log_2("Got error while saving config:", err);
+    /// Changes the stored config in IndexedDB and restarts the node once
+    /// it is written.
+    fn import<F: FnOnce(Box<dyn DataStorage>) -> Result<(), NodeError> + 'static>(
+        logger: Box<dyn Logger>,
+        f: F,
+    ) {
+        wasm_bindgen_futures::spawn_local(async move {
+            let import = async {
+                let storage = IndexedDbStorage::open().await?;
+                f(storage.box_clone())?;
+                storage.flush().await?;
+                Ok::<(), NodeError>(())
+            };
+            match import.await {
+                Ok(_) => Model::reload(),
+                Err(e) => logger.error(&format!("Couldn't import: {}", e)),
+            }
+        });
+    }
+
+    /// Asks the user before the identity of this node is replaced.
+    fn confirm_import() -> bool {
+        let msg = "This replaces the identity of this node. \
+            The current configuration is kept as a backup. Continue?";
+        window()
+            .and_then(|w| w.confirm_with_message(msg).ok())
+            .unwrap_or(false)
+    }
+
+    /// Reads the log filter from the localStorage, so it can be changed
+    /// in the browser without rebuilding.
+    fn set_log_filter() {
+        if let Ok(filter) = (LocalStorage {}).load(LOG_FILTER_NAME) {
+            if let Err(e) = set_filter_str(&filter) {
+                log_2("Invalid log filter:", e.to_string());
+            }
         }
     }
 
-    fn set_localstorage() {
+    /// Returns the signal servers given with `?signal=` in the URL, which
+    /// replace the ones of the network, e.g. `?signal=ws://localhost:8765`.
+    /// As a link could send the node to any signal server, release builds
+    /// ask the user first, while debug builds used for development take them
+    /// right away.
+    fn signal_from_url() -> String {
+        let signal = window()
+            .and_then(|w| w.location().search().ok())
+            .and_then(|search| UrlSearchParams::new_with_str(&search).ok())
+            .and_then(|params| params.get("signal"))
+            .unwrap_or_default();
+        if signal.is_empty() || cfg!(debug_assertions) {
+            return signal;
+        }
+        let msg = format!(
+            "This page asks to use the signal servers {} instead of the ones \
+            of the network. Only accept if you trust them. Use them?",
+            signal
+        );
+        match window().and_then(|w| w.confirm_with_message(&msg).ok()) {
+            Some(true) => signal,
+            _ => String::new(),
+        }
+    }
+
+    /// Returns the config given after the '#' in the URL, if any.
+    fn config_from_url() -> Option<String> {
         if let Ok(loc) = window().unwrap().location().href() {
             log_2("Location is", loc.clone());
             if loc.contains("#") {
@@ -213,11 +420,12 @@ impl Model {
                 if data_enc != "" {
                     log_1("Setting data");
                     if let Ok(data) = urlencoding::decode(&data_enc) {
-                        Model::set_config(&data);
+                        return Some(data.to_string());
                     }
                 }
             }
         }
+        None
     }
 
     fn nodes_reachable(&self) -> Html {
@@ -230,12 +438,23 @@ impl Model {
                 for stat in stats {
                     if let Some(ni) = stat.node_info.as_ref() {
                         if node.info.public != ni.public {
-                            out.push(vec![
-                                format!("{}", ni.info),
+                            out.push((ni.public.clone(), vec![
+                                ni.label(),
                                 format!("{} / {}", stat.ping_rx, stat.ping_tx),
                                 format!("{}s", ((now-stat.last_contact) / 1000.).floor()),
                                 format!("{:?} / {:?}", stat.incoming, stat.outgoing),
-                            ]);
+                                stat.latency.rtt_avg_ms.map_or("N/A".to_string(), |rtt| {
+                                    format!(
+                                        "{:.0}ms / {:.0}ms / {:.0}%",
+                                        rtt,
+                                        stat.latency.jitter_ms,
+                                        stat.latency.loss() * 100.
+                                    )
+                                }),
+                                stat.link.map_or("N/A".to_string(), |l| {
+                                    format!("{}ms / {}B / {}B", l.delay_ms, l.rx_bytes, l.tx_bytes)
+                                }),
+                            ]));
                         }
                     }
                 }
@@ -252,22 +471,133 @@ impl Model {
                     <th>{"Count (rx/tx)"}</th>
                     <th>{"Last seen"}</th>
                     <th>{"Conn Stat (in/out)"}</th>
+                    <th>{"Ping (rtt/jitter/loss)"}</th>
+                    <th>{"Link (rtt/rx/tx)"}</th>
                 </tr>
             </thead>
             <tbody>
-                {out.iter().map(|li|
+                {out.into_iter().map(|(id, li)|
                     html!{
-                        <tr>
+                        <tr onclick=self.link.callback(move |_| Msg::Select(id.clone()))
+                            title={"Click to show the connection setup"}>
                             <td>{li[0].clone()}</td>
                             <td>{li[1].clone()}</td>
                             <td>{li[2].clone()}</td>
                             <td>{li[3].clone()}</td>
+                            <td>{li[4].clone()}</td>
+                            <td>{li[5].clone()}</td>
                         </tr>
                     }).collect::<Html>()}
             </tbody>
         </table>};
     }
 
+    /// Shows the setup events of the connections to the selected peer, and
+    /// a link to download them as JSON.
+    fn peer_details(&self) -> Html {
+        let id = match self.selected.as_ref() {
+            Some(id) => id,
+            None => return html! {},
+        };
+        let timeline = self
+            .node_copy()
+            .and_then(|n| n.try_lock().ok().and_then(|node| node.timeline(id)));
+        let timeline = match timeline {
+            Some(tl) => tl,
+            None => return html! {<div>{format!("No connection to {} yet", id)}</div>},
+        };
+        let json = timeline.to_json().unwrap_or_else(|e| e.to_string());
+        html! {
+            <div class="details">
+                <h3>{format!("Connection setup with {}", id)}</h3>
+                {Model::timeline_table(&timeline)}
+                <a href={format!("data:application/json,{}", urlencoding::encode(&json))}
+                    download={"timeline.json"}>{"Download as JSON"}</a>
+            </div>
+        }
+    }
+
+    /// Lets the user move the identity of the node to another browser, either
+    /// with an encrypted file, or with the recovery words.
+    fn identity(&self) -> Html {
+        let words = match self.show_words {
+            true => self
+                .node_copy()
+                .and_then(|n| n.try_lock().ok().map(|node| node.mnemonic()))
+                .unwrap_or_default(),
+            false => "".to_string(),
+        };
+        let download = match self.export.as_ref() {
+            Some(config) => html! {
+                <a href={format!("data:application/toml,{}", urlencoding::encode(config))}
+                    download={"fledger.toml"}>{"Download encrypted key"}</a>
+            },
+            None => html! {},
+        };
+        html! {
+            <div class="identity">
+                <h3>{"Identity"}</h3>
+                <p>{"Keep a backup of the key of your node, so you can move it to
+                another browser without losing your Mana."}</p>
+                <button onclick=self.link.callback(|_| Msg::SetAlias)>{"Set alias"}</button>
+                <button onclick=self.link.callback(|_| Msg::ShowWords)>{"Show recovery words"}</button>
+                <button onclick=self.link.callback(|_| Msg::Export)>{"Export"}</button>
+                {download}
+                <p><code>{words}</code></p>
+                <label>{"Import file: "}
+                    <input type="file" onchange=self.link.batch_callback(|cd| match cd {
+                        ChangeData::Files(files) => files.get(0).map(Msg::ImportFile).into_iter().collect(),
+                        _ => vec![],
+                    })/>
+                </label>
+                <button onclick=self.link.callback(|_| Msg::ImportWords)>{"Import recovery words"}</button>
+            </div>
+        }
+    }
+
+    /// Restarts the node with the new config.
+    fn reload() {
+        if let Err(e) = window().unwrap().location().reload() {
+            log_2("Couldn't reload:", format!("{:?}", e));
+        }
+    }
+
+    fn timeline_table(timeline: &ConnectionTimeline) -> Html {
+        let mut events: Vec<(&str, f64, String)> = timeline
+            .outgoing
+            .iter()
+            .map(|e| ("out", e.time, format!("{:?}", e.event)))
+            .chain(
+                timeline
+                    .incoming
+                    .iter()
+                    .map(|e| ("in", e.time, format!("{:?}", e.event))),
+            )
+            .collect();
+        events.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+        let start = events.first().map(|e| e.1).unwrap_or_default();
+        html! {
+        <table class={"styled-table"}>
+            <thead>
+                <tr>
+                    <th>{"Time (ms)"}</th>
+                    <th>{"Direction"}</th>
+                    <th>{"Event"}</th>
+                </tr>
+            </thead>
+            <tbody>
+                {events.iter().map(|(dir, time, ev)|
+                    html!{
+                        <tr>
+                            <td>{format!("{:.0}", time - start)}</td>
+                            <td>{dir}</td>
+                            <td>{ev}</td>
+                        </tr>
+                    }).collect::<Html>()}
+            </tbody>
+        </table>}
+    }
+
     fn node_copy<'a>(&self) -> Option<Arc<Mutex<Node>>> {
         if let Some(n) = &self.node {
             Some(Arc::clone(&n))
@@ -301,6 +631,38 @@ impl Model {
     }
 }
 
+/// Sets a new log filter, e.g. "info,network::connection=trace". It is stored
+/// in the localStorage and used after a reload, too.
+#[wasm_bindgen]
+pub fn set_log_filter(filter: &str) {
+    match set_filter_str(filter) {
+        Ok(_) => {
+            if let Err(e) = (LocalStorage {}).save(LOG_FILTER_NAME, filter) {
+                log_2("Couldn't store log filter:", e.to_string());
+            }
+        }
+        Err(e) => log_2("Invalid log filter:", e.to_string()),
+    }
+}
+
+/// Protects the key of the node with a new passphrase, which is asked for
+/// after every reload. An empty passphrase stores the key in plain text again.
+#[wasm_bindgen]
+pub async fn set_passphrase(old: String, new: String) {
+    let old = Some(old.as_str()).filter(|p| !p.is_empty());
+    let new = Some(new.as_str()).filter(|p| !p.is_empty());
+    let change = async {
+        let storage = IndexedDbStorage::open().await?;
+        Node::change_passphrase(storage.box_clone(), old, new)?;
+        storage.flush().await?;
+        Ok::<(), NodeError>(())
+    };
+    match change.await {
+        Ok(_) => log_1("Passphrase changed"),
+        Err(e) => log_2("Couldn't change passphrase:", e.to_string()),
+    }
+}
+
 #[wasm_bindgen(start)]
 pub async fn run_app() {
     console_error_panic_hook::set_once();